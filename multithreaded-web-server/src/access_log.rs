@@ -0,0 +1,296 @@
+// ===== Access log
+// One line per request: who asked for what, what we answered, and how long it took.
+// Two formats are supported:
+// - the Common Log Format that web servers have written since NCSA httpd, which most log tools read:
+//   127.0.0.1 - - [18/Oct/2026:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 2326 412
+//   with the duration in microseconds added at the end, like Apache's `%D`
+// - JSON lines, one object per line, for log pipelines that would rather not parse the above
+// Both record the same things: the client's IP address, the request line as it was sent (the target with its
+// query string, not decoded), the status, the size of the body and the duration.
+//
+// Logs go to stdout or to a file. Tools like logrotate rename the file and send SIGHUP, we then open
+// the file again under its original name so new lines go to the new file.
+
+use std::{
+    fmt::{self, Write as _},
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::{http_date::DateTime, Request};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Common,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "common" => Ok(LogFormat::Common),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format `{value}`, expected `common` or `json`")),
+        }
+    }
+}
+
+/// Writes the access log, cloning gives another handle to the same log.
+#[derive(Clone)]
+pub struct AccessLog {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    format: LogFormat,
+    // `None` for stdout
+    path: Option<PathBuf>,
+    file: Mutex<Option<File>>,
+}
+
+/// What the access log knows about a request. For a request that couldn't be parsed, `request` is `None`.
+pub struct Entry<'a> {
+    pub client: Option<SocketAddr>,
+    pub request: Option<&'a Request>,
+    pub status: u16,
+    /// The size of the response body that was sent.
+    pub bytes: usize,
+    pub duration: Duration,
+}
+
+impl AccessLog {
+    pub fn stdout(format: LogFormat) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                format,
+                path: None,
+                file: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn file(path: impl AsRef<Path>, format: LogFormat) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = open(&path)?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                format,
+                path: Some(path),
+                file: Mutex::new(Some(file)),
+            }),
+        })
+    }
+
+    pub fn format(&self) -> LogFormat {
+        self.inner.format
+    }
+
+    /// Opens the log file again, for when it was renamed or deleted. Does nothing when logging to stdout.
+    ///
+    /// If the file can't be opened the log keeps going to the old one.
+    pub fn reopen(&self) -> io::Result<()> {
+        if let Some(path) = &self.inner.path {
+            let file = open(path)?;
+            *self.inner.file.lock().unwrap() = Some(file);
+        }
+        Ok(())
+    }
+
+    /// Reopens the log file when the process receives SIGHUP, which is what logrotate sends after rotating it.
+    #[cfg(unix)]
+    pub fn reopen_on_sighup(&self) -> io::Result<()> {
+        use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+        let mut signals = Signals::new([SIGHUP])?;
+        let log = self.clone();
+
+        std::thread::Builder::new()
+            .name(String::from("access-log"))
+            .spawn(move || {
+                for _ in signals.forever() {
+                    if let Err(err) = log.reopen() {
+                        eprintln!("Failed to reopen the access log: {err}");
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    pub fn log(&self, entry: &Entry<'_>) {
+        let line = match self.inner.format {
+            LogFormat::Common => common(entry, SystemTime::now()),
+            LogFormat::Json => json(entry, SystemTime::now()),
+        };
+
+        // a single write per line, so lines from different workers don't interleave.
+        // There is nobody to tell about a failed write, and the request was answered anyway.
+        let mut file = self.inner.file.lock().unwrap();
+        let _ = match file.as_mut() {
+            Some(file) => file.write_all(line.as_bytes()),
+            None => io::stdout().lock().write_all(line.as_bytes()),
+        };
+    }
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.inner.format)
+            .field("path", &self.inner.path)
+            .finish()
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn common(entry: &Entry<'_>, now: SystemTime) -> String {
+    let time = DateTime::from_system_time(now);
+    let client = entry.client.map_or_else(|| String::from("-"), |client| client.ip().to_string());
+    let request = match entry.request {
+        Some(request) => format!("{} {} {}", request.method, request.target, request.version.as_str()),
+        None => String::from("-"),
+    };
+    let bytes = match entry.bytes {
+        0 => String::from("-"),
+        bytes => bytes.to_string(),
+    };
+
+    format!(
+        "{client} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{}\" {} {bytes} {}\n",
+        time.day,
+        time.month_name(),
+        time.year,
+        time.hour,
+        time.minute,
+        time.second,
+        // the request line comes from the client, a quote in it would end the field early
+        request.replace('"', "\\\""),
+        entry.status,
+        entry.duration.as_micros(),
+    )
+}
+
+fn json(entry: &Entry<'_>, now: SystemTime) -> String {
+    let time = DateTime::from_system_time(now);
+    let mut line = format!(
+        "{{\"time\":\"{}-{:02}-{:02}T{:02}:{:02}:{:02}Z\"",
+        time.year, time.month, time.day, time.hour, time.minute, time.second
+    );
+    if let Some(client) = entry.client {
+        let _ = write!(line, ",\"client\":\"{}\"", client.ip());
+    }
+    if let Some(request) = entry.request {
+        let _ = write!(line, ",\"method\":\"{}\",\"target\":", request.method);
+        push_json_string(&mut line, &request.target);
+        let _ = write!(line, ",\"version\":\"{}\"", request.version.as_str());
+    }
+    let _ = writeln!(
+        line,
+        ",\"status\":{},\"bytes\":{},\"duration_ms\":{}}}",
+        entry.status,
+        entry.bytes,
+        entry.duration.as_secs_f64() * 1000.0
+    );
+    line
+}
+
+// https://www.rfc-editor.org/rfc/rfc8259#section-7
+fn push_json_string(output: &mut String, value: &str) {
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(output, "\\u{:04x}", c as u32);
+            }
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, time::UNIX_EPOCH};
+
+    use super::*;
+
+    fn request(raw: &str) -> Request {
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn entry(request: Option<&Request>) -> Entry<'_> {
+        Entry {
+            client: Some("127.0.0.1:51234".parse().unwrap()),
+            request,
+            status: 200,
+            bytes: 2326,
+            duration: Duration::from_micros(1500),
+        }
+    }
+
+    // Sun, 06 Nov 1994 08:49:37 GMT
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(784111777)
+    }
+
+    #[test]
+    fn formats_common_log_lines() {
+        let request = request("GET /index.html?lang=en HTTP/1.1\r\n\r\n");
+        assert_eq!(
+            common(&entry(Some(&request)), now()),
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /index.html?lang=en HTTP/1.1\" 200 2326 1500\n"
+        );
+
+        let unparsed = Entry {
+            status: 400,
+            bytes: 0,
+            ..entry(None)
+        };
+        assert_eq!(common(&unparsed, now()), "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"-\" 400 - 1500\n");
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        let request = request("GET /say?what=\"hi\" HTTP/1.1\r\n\r\n");
+        assert_eq!(
+            json(&entry(Some(&request)), now()),
+            "{\"time\":\"1994-11-06T08:49:37Z\",\"client\":\"127.0.0.1\",\"method\":\"GET\",\
+             \"target\":\"/say?what=\\\"hi\\\"\",\"version\":\"HTTP/1.1\",\
+             \"status\":200,\"bytes\":2326,\"duration_ms\":1.5}\n"
+        );
+    }
+
+    #[test]
+    fn reopens_a_rotated_file() {
+        let path = env::temp_dir().join(format!("access-log-{}.log", std::process::id()));
+        let rotated = path.with_extension("log.1");
+        let log = AccessLog::file(&path, LogFormat::Common).unwrap();
+
+        log.log(&entry(None));
+        fs::rename(&path, &rotated).unwrap();
+        log.log(&entry(None));
+        log.reopen().unwrap();
+        log.log(&entry(None));
+
+        assert_eq!(fs::read_to_string(&rotated).unwrap().lines().count(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&rotated);
+    }
+}
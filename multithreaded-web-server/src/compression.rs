@@ -0,0 +1,267 @@
+// ===== Response compression
+// Text compresses well: HTML, CSS and JavaScript usually shrink to a quarter of their size with gzip.
+// The client lists the encodings it can decode, with an optional preference ("quality") for each:
+//   Accept-Encoding: gzip, deflate;q=0.5, br
+// and we answer with the body compressed in one of them and `Content-Encoding: gzip`.
+// https://www.rfc-editor.org/rfc/rfc9110#name-accept-encoding
+//
+// Two things are easy to get wrong:
+// - caches must know that the response depends on `Accept-Encoding`, or they would give the gzip
+//   version to a client that can't read it. That's what `Vary: Accept-Encoding` says.
+// - the compressed body is a different representation, so its ETag can't be the same strong ETag.
+//   We make it weak (`W/"..."`), `If-None-Match` uses the weak comparison and keeps working.
+//
+// "deflate" in HTTP is the zlib format (RFC 1950), not the raw deflate stream, which is what a few old
+// servers sent and why some clients still guess. gzip is preferred when a client accepts both.
+
+use std::io::{self, Write};
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression as Level,
+};
+
+use crate::{Request, Response};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Compresses the responses of a connection, see `ConnectionConfig::compression`.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    /// Bodies smaller than this are sent as they are: the few bytes saved aren't worth the time,
+    /// and a tiny body can even grow.
+    pub min_size: usize,
+    /// From 0 (fastest) to 9 (smallest).
+    pub level: u32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            min_size: 1024,
+            level: 6,
+        }
+    }
+}
+
+impl Compression {
+    /// Compresses the body of `response` if its content type is text and the client accepts gzip or deflate.
+    pub fn apply(&self, request: &Request, mut response: Response) -> Response {
+        // already compressed, e.g. a precompressed file, or a range of the uncompressed body
+        if response.headers.contains("Content-Encoding") || response.headers.contains("Content-Range") {
+            return response;
+        }
+        // a file too big to read in memory, see `StaticFiles::max_buffered`. Its `.gz` copy is the way to go.
+        if response.file.is_some() {
+            return response;
+        }
+        // a 304 has no Content-Type to go by, but it stands for the 200 the cache has, which may be compressed.
+        // It must vary like that 200 did: https://www.rfc-editor.org/rfc/rfc9110#name-304-not-modified
+        if response.status == 304 {
+            add_vary(&mut response);
+            return response;
+        }
+        match response.headers.get("Content-Type") {
+            Some(content_type) if is_compressible(content_type) => {}
+            _ => return response,
+        }
+
+        // from here on the answer depends on the client's Accept-Encoding, even if it's the uncompressed body:
+        // the same resource may be big enough to compress the next time
+        add_vary(&mut response);
+        if response.sent_body_len() < self.min_size.max(1) {
+            return response;
+        }
+        let encoding = match negotiate(request.headers.get("Accept-Encoding")) {
+            Some(encoding) => encoding,
+            None => return response,
+        };
+        let compressed = match compress(&response.body, encoding, Level::new(self.level.min(9))) {
+            Ok(compressed) if compressed.len() < response.body.len() => compressed,
+            _ => return response,
+        };
+
+        mark_encoded(&mut response, encoding);
+        response.with_body(compressed)
+    }
+}
+
+/// The encoding to use for a client that sent `accept_encoding`, `None` for the uncompressed body.
+pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+    let accept_encoding = accept_encoding?;
+    let quality = |encoding| quality(accept_encoding, encoding);
+
+    let (gzip, deflate) = (quality(Encoding::Gzip), quality(Encoding::Deflate));
+    if gzip == 0.0 && deflate == 0.0 {
+        return None;
+    }
+    Some(if gzip >= deflate { Encoding::Gzip } else { Encoding::Deflate })
+}
+
+/// Returns true if a client that sent `accept_encoding` can decode `encoding`.
+pub fn accepts(accept_encoding: Option<&str>, encoding: Encoding) -> bool {
+    accept_encoding.is_some_and(|accept_encoding| quality(accept_encoding, encoding) > 0.0)
+}
+
+/// Text formats compress well, images, videos and archives are already compressed.
+pub fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    media_type.starts_with("text/")
+        || media_type.ends_with("+xml")
+        || media_type.ends_with("+json")
+        || matches!(
+            media_type.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "application/wasm"
+        )
+}
+
+/// Sets the headers of a body encoded with `encoding`, for a response whose body is compressed or
+/// comes from a precompressed file.
+pub fn mark_encoded(response: &mut Response, encoding: Encoding) {
+    response.headers.set("Content-Encoding", encoding.as_str());
+    add_vary(response);
+    if let Some(etag) = response.headers.get("ETag") {
+        if !etag.starts_with("W/") {
+            let weak = format!("W/{etag}");
+            response.headers.set("ETag", weak);
+        }
+    }
+}
+
+/// Adds `Accept-Encoding` to the `Vary` header of `response`.
+pub fn add_vary(response: &mut Response) {
+    if !response.headers.has_token("Vary", "Accept-Encoding") && !response.headers.has_token("Vary", "*") {
+        response.headers.append("Vary", "Accept-Encoding");
+    }
+}
+
+// The quality the client gave `encoding`: 0 if it refuses it or didn't list it, 1 if it didn't say.
+// A coding that isn't listed gets the quality of `*`, if there is one.
+fn quality(accept_encoding: &str, encoding: Encoding) -> f32 {
+    let mut wildcard = None;
+    for coding in accept_encoding.split(',') {
+        let mut parts = coding.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let quality = parts
+            .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+            .find_map(|quality| quality.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        // `x-gzip` is what HTTP/1.0 called it
+        let matches = name.eq_ignore_ascii_case(encoding.as_str())
+            || (encoding == Encoding::Gzip && name.eq_ignore_ascii_case("x-gzip"));
+        if matches {
+            return quality;
+        }
+        if name == "*" {
+            wildcard = Some(quality);
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
+
+fn compress(body: &[u8], encoding: Encoding, level: Level) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), level);
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), level);
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    use super::*;
+
+    fn request(accept_encoding: &str) -> Request {
+        let raw = format!("GET / HTTP/1.1\r\nAccept-Encoding: {accept_encoding}\r\n\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn page() -> Response {
+        Response::html(200, "<p>hello</p>".repeat(200)).with_header("ETag", "\"abc\"")
+    }
+
+    #[test]
+    fn negotiates_the_encoding() {
+        assert_eq!(negotiate(None), None);
+        assert_eq!(negotiate(Some("gzip, deflate, br")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(Some("gzip;q=0.5, deflate")), Some(Encoding::Deflate));
+        assert_eq!(negotiate(Some("deflate;q=0, gzip;q=0")), None);
+        assert_eq!(negotiate(Some("identity")), None);
+        assert_eq!(negotiate(Some("*")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(Some("*;q=0.1, gzip;q=0")), Some(Encoding::Deflate));
+        assert_eq!(negotiate(Some("X-GZIP")), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn compresses_text_bodies() {
+        let compression = Compression::default();
+
+        let response = compression.apply(&request("gzip"), page());
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.headers.get("ETag"), Some("W/\"abc\""));
+        let mut body = String::new();
+        GzDecoder::new(response.body.as_slice()).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "<p>hello</p>".repeat(200));
+
+        let response = compression.apply(&request("deflate"), page());
+        assert_eq!(response.headers.get("Content-Encoding"), Some("deflate"));
+        let mut body = String::new();
+        ZlibDecoder::new(response.body.as_slice()).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "<p>hello</p>".repeat(200));
+
+        // the client can't decode it, but a cache still has to know the response depends on it
+        let response = compression.apply(&request("br"), page());
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.headers.get("ETag"), Some("\"abc\""));
+    }
+
+    #[test]
+    fn leaves_small_and_binary_bodies_alone() {
+        let compression = Compression::default();
+
+        // not worth compressing, but a bigger body of the same resource would be
+        let small = compression.apply(&request("gzip"), Response::html(200, "<p>hello</p>"));
+        assert_eq!(small, Response::html(200, "<p>hello</p>").with_header("Vary", "Accept-Encoding"));
+
+        let image = Response::new(200).with_header("Content-Type", "image/png").with_body(vec![0; 4096]);
+        assert_eq!(compression.apply(&request("gzip"), image.clone()), image);
+
+        let partial = page().with_header("Content-Range", "bytes 0-2399/2400");
+        assert_eq!(compression.apply(&request("gzip"), partial.clone()), partial);
+    }
+
+    #[test]
+    fn not_modified_varies_like_the_full_response() {
+        let compression = Compression::default();
+        let not_modified = compression.apply(&request("gzip"), Response::new(304).with_header("ETag", "\"abc\""));
+        assert_eq!(not_modified.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(not_modified.headers.get("Content-Encoding"), None);
+    }
+}
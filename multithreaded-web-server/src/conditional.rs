@@ -0,0 +1,151 @@
+// ===== Conditional requests
+// When a browser already has a copy of a file it sends back the validators we gave it the first time:
+// - `If-None-Match: "<etag>"`            we answer 304 if the file's ETag is still the same
+// - `If-Modified-Since: <http date>`     we answer 304 if the file wasn't modified after that date
+// A 304 Not Modified has no body, so the client reuses its copy and we save the bandwidth.
+// https://www.rfc-editor.org/rfc/rfc9110#name-conditional-requests
+
+use std::{
+    fs::Metadata,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{http_date, request::Method, Request, Response};
+
+/// The `ETag` and `Last-Modified` of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: SystemTime,
+}
+
+impl Validators {
+    /// The ETag is built from the size and modification time of the file, like nginx and Apache do,
+    /// so we don't have to hash the contents on every request.
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        Self {
+            etag: format!("\"{:x}-{:x}{:08x}\"", metadata.len(), since_epoch.as_secs(), since_epoch.subsec_nanos()),
+            last_modified: modified,
+        }
+    }
+
+    /// Returns true if the client's cached copy is still fresh and we should answer 304.
+    pub fn is_not_modified(&self, request: &Request) -> bool {
+        // conditions only apply to requests that would return the file
+        if !matches!(request.method, Method::Get | Method::Head) {
+            return false;
+        }
+
+        // If-None-Match is more precise, when it's present If-Modified-Since must be ignored
+        if let Some(if_none_match) = request.headers.get("If-None-Match") {
+            return etag_list_contains(if_none_match, &self.etag);
+        }
+
+        match request.headers.get("If-Modified-Since").and_then(http_date::parse) {
+            // HTTP dates only have a one second precision
+            Some(since) => seconds(self.last_modified) <= seconds(since),
+            None => false,
+        }
+    }
+
+    /// Returns true if a `Range` request should be honored.
+    ///
+    /// `If-Range` makes the range conditional: "send me the missing part if the file is still the one I have,
+    /// otherwise send me the whole thing". Unlike `If-None-Match` it needs an exact (strong) match.
+    pub fn matches_if_range(&self, request: &Request) -> bool {
+        let if_range = match request.headers.get("If-Range") {
+            Some(if_range) => if_range.trim(),
+            None => return true,
+        };
+
+        if if_range.starts_with('"') {
+            return if_range == self.etag;
+        }
+        match http_date::parse(if_range) {
+            Some(date) => seconds(self.last_modified) == seconds(date),
+            None => false,
+        }
+    }
+
+    /// Adds the `ETag` and `Last-Modified` headers to `response`.
+    pub fn apply(&self, response: Response) -> Response {
+        response
+            .with_header("ETag", self.etag.clone())
+            .with_header("Last-Modified", http_date::format(self.last_modified))
+    }
+}
+
+/// Checks an `If-None-Match` list (`"a", W/"b"` or `*`) using the weak comparison:
+/// `W/"x"` and `"x"` are considered the same.
+fn etag_list_contains(list: &str, etag: &str) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+
+    let etag = etag.trim_start_matches("W/");
+    list.split(',')
+        .map(|candidate| candidate.trim().trim_start_matches("W/"))
+        .any(|candidate| candidate == etag)
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn validators() -> Validators {
+        Validators {
+            etag: String::from("\"2a-5f5e100\""),
+            last_modified: UNIX_EPOCH + Duration::from_millis(1_600_000_000_500),
+        }
+    }
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET /index.html HTTP/1.1\r\n{headers}\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn matching_etag_is_not_modified() {
+        let validators = validators();
+
+        assert!(validators.is_not_modified(&request("If-None-Match: \"2a-5f5e100\"\r\n")));
+        assert!(validators.is_not_modified(&request("If-None-Match: \"old\", W/\"2a-5f5e100\"\r\n")));
+        assert!(validators.is_not_modified(&request("If-None-Match: *\r\n")));
+        assert!(!validators.is_not_modified(&request("If-None-Match: \"old\"\r\n")));
+    }
+
+    #[test]
+    fn compares_dates_to_the_second() {
+        let validators = validators();
+
+        assert!(validators.is_not_modified(&request("If-Modified-Since: Sun, 13 Sep 2020 12:26:40 GMT\r\n")));
+        assert!(!validators.is_not_modified(&request("If-Modified-Since: Sun, 13 Sep 2020 12:26:39 GMT\r\n")));
+        assert!(!validators.is_not_modified(&request("If-Modified-Since: not a date\r\n")));
+    }
+
+    #[test]
+    fn if_range_needs_an_exact_match() {
+        let validators = validators();
+
+        assert!(validators.matches_if_range(&request("")));
+        assert!(validators.matches_if_range(&request("If-Range: \"2a-5f5e100\"\r\n")));
+        assert!(validators.matches_if_range(&request("If-Range: Sun, 13 Sep 2020 12:26:40 GMT\r\n")));
+        assert!(!validators.matches_if_range(&request("If-Range: W/\"2a-5f5e100\"\r\n")));
+        assert!(!validators.matches_if_range(&request("If-Range: Sun, 13 Sep 2020 12:00:00 GMT\r\n")));
+    }
+
+    #[test]
+    fn if_none_match_wins_over_if_modified_since() {
+        let request = request("If-None-Match: \"old\"\r\nIf-Modified-Since: Sun, 13 Sep 2020 12:26:40 GMT\r\n");
+        assert!(!validators().is_not_modified(&request));
+    }
+}
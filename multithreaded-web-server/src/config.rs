@@ -0,0 +1,941 @@
+// ===== Configuration of the server binary
+// Settings come from three places, each one overriding the one before:
+// - the defaults below
+// - a TOML file, if `--config <path>` is given (see `server.toml` for every setting)
+// - command line flags, for a quick change without editing the file
+//
+// Everything is checked once, at startup: a typo in the file or an impossible combination of values
+// stops the server with a message saying what's wrong, instead of showing up as odd behavior later.
+
+use std::{
+    error::Error,
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{Balance, IoMode, Limits, LogFormat, Rate};
+
+// event loops in the epoll mode when the config doesn't say, a couple are plenty for most servers
+const DEFAULT_IO_THREADS: usize = 2;
+
+pub const USAGE: &str = "\
+Usage: multithreaded-web-server [OPTIONS] [DOCUMENT_ROOT]
+
+Options:
+  --config <PATH>              read the settings from a TOML file, the flags below override it
+  --listen <ADDR>              address to listen on, repeat it to listen on several [default: 127.0.0.1:7878]
+  --tls-listen <ADDR>          address to listen on for HTTPS, repeat it to listen on several
+  --tls-cert <PATH>            PEM file with the certificate chain for HTTPS
+  --tls-key <PATH>             PEM file with the private key of the certificate
+  --redirect-http              redirect every plain HTTP request to HTTPS
+  --root <DIR>                 directory the files are served from [default: public]
+  --index <NAME>               file served for a directory [default: index.html]
+  --not-found-page <PATH>      page served with a 404, relative to the root [default: 404.html]
+  --workers <N>                worker threads the pool starts with [default: 4]
+  --max-workers <N>            worker threads the pool grows to under load [default: 32]
+  --queue-capacity <N>         connections waiting for a worker before we answer 503 [default: 64]
+  --io <MODE>                  `blocking` (a worker per connection) or `epoll` (Linux only) [default: blocking]
+  --io-threads <N>             event loop threads in the epoll mode [default: 2]
+  --worker-idle-timeout <DUR>  how long an extra worker waits for a job before it exits [default: 60s]
+  --idle-timeout <DUR>         how long a keep-alive connection waits for its next request [default: 5s]
+  --read-timeout <DUR>         how long a client may stay silent in the middle of a request [default: 5s]
+  --write-timeout <DUR>        how long a client may take to read a response [default: 10s]
+  --request-timeout <DUR>      how long a client has to send a whole request [default: 30s]
+  --shutdown-timeout <DUR>     how long requests get to finish once a shutdown starts [default: 30s]
+  --retry-after <DUR>          what a 503 tells clients about when to come back [default: 1s]
+  --max-header-bytes <N>       longest request line and headers, in bytes [default: 16384]
+  --max-headers <N>            most header lines in a request [default: 100]
+  --max-body-size <N>          longest request body, in bytes [default: 10485760]
+  --rate-limit <N/DUR>         answer 429 to clients sending more than N requests per DUR, e.g. 300/1m
+  --rate-limit-header <NAME>   tell clients apart by this header set by a proxy instead of their IP address
+  --no-cache                   read the files from the disk on every request
+  --cache-capacity <N>         bytes of files kept in memory [default: 67108864]
+  --no-compression             send every response uncompressed
+  --compression-min-size <N>   smallest body in bytes worth compressing [default: 1024]
+  --log-format <FORMAT>        access log format, `common` or `json` [default: common]
+  --log-file <PATH>            write the access log to a file instead of stdout
+  --no-access-log              don't write an access log
+  -h, --help                   print this help
+
+Durations are a number followed by a unit: 250ms, 5s, 2m or 1h.
+";
+
+/// The validated settings of the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub document_root: PathBuf,
+    pub index: String,
+    pub not_found_page: Option<PathBuf>,
+    pub workers: Workers,
+    pub io: IoMode,
+    pub timeouts: Timeouts,
+    pub limits: Limits,
+    pub rate_limit: RateLimit,
+    pub cache: Cache,
+    pub compression: Compression,
+    pub tls: Tls,
+    pub log: Log,
+    /// Path prefixes forwarded to other servers, the first matching one applies.
+    pub proxy: Vec<ProxyRoute>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workers {
+    pub min: usize,
+    pub max: usize,
+    pub queue_capacity: usize,
+    pub idle_timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeouts {
+    pub idle: Duration,
+    pub read: Duration,
+    pub write: Duration,
+    pub request: Duration,
+    pub shutdown: Duration,
+    pub retry_after: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    /// False to let clients send as many requests as they like.
+    pub enabled: bool,
+    /// The rate of every client on the paths without a route of their own.
+    pub rate: Rate,
+    /// The header a reverse proxy sets with the client's address, `None` for the peer's IP address.
+    pub key_header: Option<String>,
+    /// Path prefixes with their own rate, e.g. a stricter one for `/login`.
+    pub routes: Vec<(String, Rate)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cache {
+    /// False to read the files on every request.
+    pub enabled: bool,
+    /// Bytes of responses kept in memory, the least recently used files go first.
+    pub capacity: usize,
+    /// Bigger files aren't cached.
+    pub max_file_size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compression {
+    /// False to never compress responses.
+    pub enabled: bool,
+    pub min_size: usize,
+    pub level: u32,
+    /// Serve the `.gz` copies of static files that have one, see `StaticFiles::precompressed`.
+    pub precompressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Tls {
+    /// Where to listen for HTTPS, next to the plain `listen` addresses.
+    pub listen: Vec<SocketAddr>,
+    pub certificate: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+    /// Answer every plain HTTP request with a redirect to HTTPS.
+    pub redirect_http: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyRoute {
+    /// The requests for this path and the paths under it go to the upstreams, e.g. `/api` takes `/api/items`.
+    pub path: String,
+    pub upstreams: Vec<SocketAddr>,
+    pub balance: Balance,
+    pub timeout: Duration,
+    /// The path asked for to check the upstreams, `None` to not check them.
+    pub health_check: Option<String>,
+    pub health_interval: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Log {
+    /// False to not write an access log at all.
+    pub access: bool,
+    pub format: LogFormat,
+    /// `None` for stdout.
+    pub file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 7878))],
+            document_root: PathBuf::from("public"),
+            index: String::from("index.html"),
+            not_found_page: Some(PathBuf::from("404.html")),
+            workers: Workers {
+                min: 4,
+                max: 32,
+                queue_capacity: 64,
+                idle_timeout: Duration::from_secs(60),
+            },
+            io: IoMode::Blocking,
+            timeouts: Timeouts {
+                idle: Duration::from_secs(5),
+                read: Duration::from_secs(5),
+                write: Duration::from_secs(10),
+                request: Duration::from_secs(30),
+                shutdown: Duration::from_secs(30),
+                retry_after: Duration::from_secs(1),
+            },
+            limits: Limits::default(),
+            rate_limit: RateLimit {
+                enabled: false,
+                rate: Rate::new(300, Duration::from_secs(60)),
+                key_header: None,
+                routes: Vec::new(),
+            },
+            cache: Cache {
+                enabled: true,
+                capacity: 64 * 1024 * 1024,
+                max_file_size: 1024 * 1024,
+            },
+            compression: Compression {
+                enabled: true,
+                min_size: 1024,
+                level: 6,
+                precompressed: true,
+            },
+            tls: Tls::default(),
+            log: Log {
+                access: true,
+                format: LogFormat::Common,
+                file: None,
+            },
+            proxy: Vec::new(),
+        }
+    }
+}
+
+/// What the command line asked for.
+#[derive(Debug, Default)]
+pub struct Args {
+    pub help: bool,
+    config: Option<PathBuf>,
+    overrides: Layer,
+}
+
+/// Why the server can't start with the settings it was given.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A flag that doesn't exist, or without its value.
+    Usage(String),
+    /// The config file couldn't be read.
+    Read { path: PathBuf, source: io::Error },
+    /// The config file isn't valid TOML, or has a setting we don't know or a value of the wrong kind.
+    Parse { path: PathBuf, message: String },
+    /// Every value is fine on its own but they don't work together, or with the system.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Usage(message) => write!(f, "{message}, see --help"),
+            ConfigError::Read { path, source } => write!(f, "failed to read {}: {source}", path.display()),
+            ConfigError::Parse { path, message } => write!(f, "invalid config file {}: {message}", path.display()),
+            ConfigError::Invalid(message) => write!(f, "invalid configuration: {message}"),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// Settings that are set, either by the file or by the flags. The file is deserialized straight into it,
+// so its sections and keys are the names of the fields.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Layer {
+    #[serde(deserialize_with = "addresses")]
+    listen: Option<Vec<SocketAddr>>,
+    document_root: Option<PathBuf>,
+    index: Option<String>,
+    // an empty path turns the 404 page off
+    not_found_page: Option<PathBuf>,
+    workers: WorkersLayer,
+    io: IoLayer,
+    timeouts: TimeoutsLayer,
+    limits: LimitsLayer,
+    rate_limit: RateLimitLayer,
+    cache: CacheLayer,
+    compression: CompressionLayer,
+    tls: TlsLayer,
+    log: LogLayer,
+    proxy: Option<Vec<ProxyLayer>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct WorkersLayer {
+    min: Option<usize>,
+    max: Option<usize>,
+    queue_capacity: Option<usize>,
+    #[serde(deserialize_with = "duration")]
+    idle_timeout: Option<Duration>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct IoLayer {
+    #[serde(deserialize_with = "io_mode")]
+    mode: Option<IoModeName>,
+    threads: Option<usize>,
+}
+
+// `IoMode` without its settings, what `io.mode` and `--io` name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IoModeName {
+    Blocking,
+    Epoll,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TimeoutsLayer {
+    #[serde(deserialize_with = "duration")]
+    idle: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    read: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    write: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    request: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    shutdown: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    retry_after: Option<Duration>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsLayer {
+    max_header_bytes: Option<usize>,
+    max_headers: Option<usize>,
+    max_body_size: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitLayer {
+    enabled: Option<bool>,
+    requests: Option<u32>,
+    #[serde(deserialize_with = "duration")]
+    period: Option<Duration>,
+    key_header: Option<String>,
+    routes: Option<Vec<RouteLayer>>,
+}
+
+// one `[[rate_limit.routes]]`, its period is the one of `rate_limit` when it doesn't have one
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteLayer {
+    path: String,
+    requests: u32,
+    #[serde(default, deserialize_with = "duration")]
+    period: Option<Duration>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CacheLayer {
+    enabled: Option<bool>,
+    capacity: Option<usize>,
+    max_file_size: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CompressionLayer {
+    enabled: Option<bool>,
+    min_size: Option<usize>,
+    level: Option<u32>,
+    precompressed: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsLayer {
+    #[serde(deserialize_with = "addresses")]
+    listen: Option<Vec<SocketAddr>>,
+    certificate: Option<PathBuf>,
+    private_key: Option<PathBuf>,
+    redirect_http: Option<bool>,
+}
+
+// one `[[proxy]]`, only `path` and `upstreams` are required
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProxyLayer {
+    path: String,
+    #[serde(deserialize_with = "addresses")]
+    upstreams: Option<Vec<SocketAddr>>,
+    #[serde(default, deserialize_with = "balance")]
+    balance: Option<Balance>,
+    #[serde(default, deserialize_with = "duration")]
+    timeout: Option<Duration>,
+    health_check: Option<String>,
+    #[serde(default, deserialize_with = "duration")]
+    health_interval: Option<Duration>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogLayer {
+    access: Option<bool>,
+    #[serde(deserialize_with = "log_format")]
+    format: Option<LogFormat>,
+    file: Option<PathBuf>,
+}
+
+impl Args {
+    /// Parses the command line arguments, without the program name.
+    ///
+    /// Flags take their value as the next argument or after a `=`: `--workers 8` or `--workers=8`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut parsed = Args::default();
+        let overrides = &mut parsed.overrides;
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg, None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| ConfigError::Usage(format!("{flag} needs a value")))
+            };
+
+            match flag.as_str() {
+                "-h" | "--help" => parsed.help = true,
+                "--config" => parsed.config = Some(PathBuf::from(value()?)),
+                "--listen" => {
+                    let address = flag_value(&flag, &value()?, parse_address)?;
+                    overrides.listen.get_or_insert_with(Vec::new).push(address);
+                }
+                "--tls-listen" => {
+                    let address = flag_value(&flag, &value()?, parse_address)?;
+                    overrides.tls.listen.get_or_insert_with(Vec::new).push(address);
+                }
+                "--tls-cert" => overrides.tls.certificate = Some(PathBuf::from(value()?)),
+                "--tls-key" => overrides.tls.private_key = Some(PathBuf::from(value()?)),
+                "--redirect-http" => overrides.tls.redirect_http = Some(true),
+                "--root" => overrides.document_root = Some(PathBuf::from(value()?)),
+                "--index" => overrides.index = Some(value()?),
+                "--not-found-page" => overrides.not_found_page = Some(PathBuf::from(value()?)),
+                "--workers" => overrides.workers.min = Some(flag_value(&flag, &value()?, parse_number)?),
+                "--max-workers" => overrides.workers.max = Some(flag_value(&flag, &value()?, parse_number)?),
+                "--queue-capacity" => {
+                    overrides.workers.queue_capacity = Some(flag_value(&flag, &value()?, parse_number)?)
+                }
+                "--io" => overrides.io.mode = Some(flag_value(&flag, &value()?, parse_io_mode)?),
+                "--io-threads" => overrides.io.threads = Some(flag_value(&flag, &value()?, parse_number)?),
+                "--worker-idle-timeout" => {
+                    overrides.workers.idle_timeout = Some(flag_value(&flag, &value()?, parse_duration)?)
+                }
+                "--idle-timeout" => overrides.timeouts.idle = Some(flag_value(&flag, &value()?, parse_duration)?),
+                "--read-timeout" => overrides.timeouts.read = Some(flag_value(&flag, &value()?, parse_duration)?),
+                "--write-timeout" => overrides.timeouts.write = Some(flag_value(&flag, &value()?, parse_duration)?),
+                "--request-timeout" => {
+                    overrides.timeouts.request = Some(flag_value(&flag, &value()?, parse_duration)?)
+                }
+                "--shutdown-timeout" => {
+                    overrides.timeouts.shutdown = Some(flag_value(&flag, &value()?, parse_duration)?)
+                }
+                "--retry-after" => {
+                    overrides.timeouts.retry_after = Some(flag_value(&flag, &value()?, parse_duration)?)
+                }
+                "--max-header-bytes" => {
+                    overrides.limits.max_header_bytes = Some(flag_value(&flag, &value()?, parse_number)?)
+                }
+                "--max-headers" => overrides.limits.max_headers = Some(flag_value(&flag, &value()?, parse_number)?),
+                "--max-body-size" => {
+                    overrides.limits.max_body_size = Some(flag_value(&flag, &value()?, parse_number)?)
+                }
+                "--rate-limit" => {
+                    let (requests, period) = flag_value(&flag, &value()?, parse_rate)?;
+                    overrides.rate_limit.enabled = Some(true);
+                    overrides.rate_limit.requests = Some(requests);
+                    overrides.rate_limit.period = Some(period);
+                }
+                "--rate-limit-header" => overrides.rate_limit.key_header = Some(value()?),
+                "--no-cache" => overrides.cache.enabled = Some(false),
+                "--cache-capacity" => overrides.cache.capacity = Some(flag_value(&flag, &value()?, parse_number)?),
+                "--no-compression" => overrides.compression.enabled = Some(false),
+                "--compression-min-size" => {
+                    overrides.compression.min_size = Some(flag_value(&flag, &value()?, parse_number)?)
+                }
+                "--log-format" => overrides.log.format = Some(flag_value(&flag, &value()?, LogFormat::from_str)?),
+                "--log-file" => overrides.log.file = Some(PathBuf::from(value()?)),
+                "--no-access-log" => overrides.log.access = Some(false),
+                flag if flag.starts_with('-') => return Err(ConfigError::Usage(format!("unknown option {flag}"))),
+                // the document root used to be the only argument: `cargo run -- ./dist`
+                _ if overrides.document_root.is_none() => overrides.document_root = Some(PathBuf::from(&flag)),
+                _ => return Err(ConfigError::Usage(format!("unexpected argument `{flag}`"))),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl Config {
+    /// The defaults, overridden by the config file and then by the flags of `args`, validated.
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let mut config = Config::default();
+        if let Some(path) = &args.config {
+            config.apply(read(path)?);
+        }
+        config.apply(args.overrides);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses a config file's contents, validated.
+    pub fn from_toml(contents: &str) -> Result<Self, ConfigError> {
+        let layer = toml::from_str(contents).map_err(|err| ConfigError::Parse {
+            path: PathBuf::from("<string>"),
+            message: err.to_string(),
+        })?;
+        let mut config = Config::default();
+        config.apply(layer);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply(&mut self, layer: Layer) {
+        fn set<T>(setting: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *setting = value;
+            }
+        }
+
+        set(&mut self.listen, layer.listen);
+        set(&mut self.document_root, layer.document_root);
+        set(&mut self.index, layer.index);
+        if let Some(page) = layer.not_found_page {
+            self.not_found_page = (!page.as_os_str().is_empty()).then_some(page);
+        }
+        set(&mut self.workers.min, layer.workers.min);
+        set(&mut self.workers.max, layer.workers.max);
+        set(&mut self.workers.queue_capacity, layer.workers.queue_capacity);
+        set(&mut self.workers.idle_timeout, layer.workers.idle_timeout);
+        let threads = match self.io {
+            IoMode::Epoll { threads } => threads,
+            IoMode::Blocking => DEFAULT_IO_THREADS,
+        };
+        let mode = match self.io {
+            IoMode::Epoll { .. } => IoModeName::Epoll,
+            IoMode::Blocking => IoModeName::Blocking,
+        };
+        self.io = match layer.io.mode.unwrap_or(mode) {
+            IoModeName::Blocking => IoMode::Blocking,
+            IoModeName::Epoll => IoMode::Epoll {
+                threads: layer.io.threads.unwrap_or(threads),
+            },
+        };
+        set(&mut self.timeouts.idle, layer.timeouts.idle);
+        set(&mut self.timeouts.read, layer.timeouts.read);
+        set(&mut self.timeouts.write, layer.timeouts.write);
+        set(&mut self.timeouts.request, layer.timeouts.request);
+        set(&mut self.timeouts.shutdown, layer.timeouts.shutdown);
+        set(&mut self.limits.max_header_bytes, layer.limits.max_header_bytes);
+        set(&mut self.limits.max_headers, layer.limits.max_headers);
+        set(&mut self.limits.max_body_size, layer.limits.max_body_size);
+        set(&mut self.timeouts.retry_after, layer.timeouts.retry_after);
+        set(&mut self.rate_limit.enabled, layer.rate_limit.enabled);
+        set(&mut self.rate_limit.rate.requests, layer.rate_limit.requests);
+        set(&mut self.rate_limit.rate.period, layer.rate_limit.period);
+        if layer.rate_limit.key_header.is_some() {
+            // an empty name goes back to the IP address
+            self.rate_limit.key_header = layer.rate_limit.key_header.filter(|name| !name.is_empty());
+        }
+        if let Some(routes) = layer.rate_limit.routes {
+            let period = self.rate_limit.rate.period;
+            self.rate_limit.routes = routes
+                .into_iter()
+                .map(|route| (route.path, Rate::new(route.requests, route.period.unwrap_or(period))))
+                .collect();
+        }
+        set(&mut self.cache.enabled, layer.cache.enabled);
+        set(&mut self.cache.capacity, layer.cache.capacity);
+        set(&mut self.cache.max_file_size, layer.cache.max_file_size);
+        set(&mut self.compression.enabled, layer.compression.enabled);
+        set(&mut self.compression.min_size, layer.compression.min_size);
+        set(&mut self.compression.level, layer.compression.level);
+        set(&mut self.compression.precompressed, layer.compression.precompressed);
+        set(&mut self.tls.listen, layer.tls.listen);
+        if layer.tls.certificate.is_some() {
+            self.tls.certificate = layer.tls.certificate;
+        }
+        if layer.tls.private_key.is_some() {
+            self.tls.private_key = layer.tls.private_key;
+        }
+        set(&mut self.tls.redirect_http, layer.tls.redirect_http);
+        set(&mut self.log.access, layer.log.access);
+        set(&mut self.log.format, layer.log.format);
+        if layer.log.file.is_some() {
+            self.log.file = layer.log.file;
+        }
+        if let Some(routes) = layer.proxy {
+            self.proxy = routes
+                .into_iter()
+                .map(|route| ProxyRoute {
+                    path: route.path,
+                    upstreams: route.upstreams.unwrap_or_default(),
+                    balance: route.balance.unwrap_or_default(),
+                    timeout: route.timeout.unwrap_or(Duration::from_secs(30)),
+                    health_check: route.health_check,
+                    health_interval: route.health_interval.unwrap_or(Duration::from_secs(10)),
+                })
+                .collect();
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Invalid(message));
+
+        if self.listen.is_empty() && self.tls.listen.is_empty() {
+            return invalid(String::from("listen or tls.listen needs at least one address"));
+        }
+        let has_key_pair = self.tls.certificate.is_some() && self.tls.private_key.is_some();
+        if !self.tls.listen.is_empty() && !has_key_pair {
+            return invalid(String::from("tls.listen needs tls.certificate and tls.private_key"));
+        }
+        if self.tls.redirect_http && self.tls.listen.is_empty() {
+            return invalid(String::from("tls.redirect_http needs a tls.listen address to redirect to"));
+        }
+        if self.workers.min == 0 {
+            return invalid(String::from("workers.min must be at least 1"));
+        }
+        if self.workers.max < self.workers.min {
+            return invalid(format!(
+                "workers.max ({}) is below workers.min ({})",
+                self.workers.max, self.workers.min
+            ));
+        }
+        // a zero read timeout is an error for the socket, and a worker that retires right away is useless
+        let timeouts = [
+            ("workers.idle_timeout", self.workers.idle_timeout),
+            ("timeouts.idle", self.timeouts.idle),
+            ("timeouts.read", self.timeouts.read),
+            ("timeouts.write", self.timeouts.write),
+            ("timeouts.request", self.timeouts.request),
+        ];
+        for (setting, duration) in timeouts {
+            if duration.is_zero() {
+                return invalid(format!("{setting} must be longer than 0"));
+            }
+        }
+        // the request line alone needs a few bytes, and most clients send a Host header
+        if self.limits.max_header_bytes < 64 || self.limits.max_headers == 0 {
+            return invalid(String::from("limits.max_header_bytes must be at least 64 and limits.max_headers at least 1"));
+        }
+        if self.rate_limit.enabled {
+            let rates = std::iter::once((String::from("rate_limit"), &self.rate_limit.rate)).chain(
+                self.rate_limit.routes.iter().map(|(path, rate)| (format!("rate_limit.routes `{path}`"), rate)),
+            );
+            for (name, rate) in rates {
+                if rate.requests == 0 || rate.period.is_zero() {
+                    return invalid(format!("{name} needs at least 1 request in a period longer than 0"));
+                }
+            }
+            if let Some((path, _)) = self.rate_limit.routes.iter().find(|(path, _)| !path.starts_with('/')) {
+                return invalid(format!("rate_limit.routes path `{path}` must start with /"));
+            }
+        }
+        for route in &self.proxy {
+            let path = &route.path;
+            if !path.starts_with('/') {
+                return invalid(format!("proxy path `{path}` must start with /"));
+            }
+            if route.upstreams.is_empty() {
+                return invalid(format!("proxy `{path}` needs at least one upstream"));
+            }
+            if route.timeout.is_zero() || route.health_interval.is_zero() {
+                return invalid(format!("the timeout and the health_interval of proxy `{path}` must be longer than 0"));
+            }
+            if route.health_check.as_ref().is_some_and(|check| !check.starts_with('/')) {
+                return invalid(format!("the health_check of proxy `{path}` must be a path starting with /"));
+            }
+        }
+        match self.io {
+            IoMode::Epoll { threads: 0 } => return invalid(String::from("io.threads must be at least 1")),
+            IoMode::Epoll { .. } if !cfg!(target_os = "linux") => {
+                return invalid(String::from("io.mode `epoll` is only available on Linux"))
+            }
+            _ => {}
+        }
+        if self.cache.enabled && self.cache.capacity == 0 {
+            return invalid(String::from("cache.capacity must be at least 1, or cache.enabled false"));
+        }
+        if self.compression.level > 9 {
+            return invalid(format!("compression.level ({}) must be between 0 and 9", self.compression.level));
+        }
+        if !self.document_root.is_dir() {
+            return invalid(format!("document_root {} is not a directory", self.document_root.display()));
+        }
+        if self.index.is_empty() || self.index.contains('/') {
+            return invalid(format!("index `{}` must be a file name", self.index));
+        }
+        Ok(())
+    }
+}
+
+fn read(path: &Path) -> Result<Layer, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    // toml's messages include the line and the column, and the key when a value has the wrong type
+    toml::from_str(&contents).map_err(|err| ConfigError::Parse {
+        path: path.to_path_buf(),
+        message: err.to_string(),
+    })
+}
+
+/// Parses a duration like `250ms`, `5s`, `2m` or `1h`.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("`{value}` is not a duration, expected a number and a unit like 250ms, 5s, 2m or 1h");
+    let split = value.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "ms" => return Ok(Duration::from_millis(number)),
+        "s" => Some(number),
+        "m" => number.checked_mul(60),
+        "h" => number.checked_mul(60 * 60),
+        _ => return Err(invalid()),
+    };
+    // too many minutes or hours for a u64 of seconds
+    seconds.map(Duration::from_secs).ok_or_else(invalid)
+}
+
+fn parse_address(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse()
+        .map_err(|_| format!("`{value}` is not an address, expected an IP and a port like 127.0.0.1:7878 or [::1]:7878"))
+}
+
+fn parse_io_mode(value: &str) -> Result<IoModeName, String> {
+    match value {
+        "blocking" => Ok(IoModeName::Blocking),
+        "epoll" => Ok(IoModeName::Epoll),
+        _ => Err(format!("unknown I/O mode `{value}`, expected `blocking` or `epoll`")),
+    }
+}
+
+// `300/1m`: 300 requests per minute
+fn parse_rate(value: &str) -> Result<(u32, Duration), String> {
+    let invalid = || format!("`{value}` is not a rate, expected requests per duration like 300/1m");
+    let (requests, period) = value.split_once('/').ok_or_else(invalid)?;
+    let requests = requests.parse().map_err(|_| invalid())?;
+    Ok((requests, parse_duration(period).map_err(|_| invalid())?))
+}
+
+fn parse_balance(value: &str) -> Result<Balance, String> {
+    match value {
+        "round_robin" => Ok(Balance::RoundRobin),
+        "least_connections" => Ok(Balance::LeastConnections),
+        _ => Err(format!("unknown balance `{value}`, expected `round_robin` or `least_connections`")),
+    }
+}
+
+fn parse_number<T: FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("`{value}` is not a positive number"))
+}
+
+fn flag_value<T>(flag: &str, value: &str, parse: impl FnOnce(&str) -> Result<T, String>) -> Result<T, ConfigError> {
+    parse(value).map_err(|message| ConfigError::Usage(format!("{flag}: {message}")))
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_duration(&value).map(Some).map_err(de::Error::custom)
+}
+
+fn addresses<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<SocketAddr>>, D::Error> {
+    let values = Vec::<String>::deserialize(deserializer)?;
+    values
+        .iter()
+        .map(|value| parse_address(value))
+        .collect::<Result<_, _>>()
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+fn balance<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Balance>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_balance(&value).map(Some).map_err(de::Error::custom)
+}
+
+fn io_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<IoModeName>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_io_mode(&value).map(Some).map_err(de::Error::custom)
+}
+
+fn log_format<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<LogFormat>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    value.parse().map(Some).map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args, ConfigError> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("5s"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        for invalid in ["", "5", "s", "5 s", "-5s", "1.5s", "5d", "400000000000000000m", "18446744073709551615h"] {
+            assert!(parse_duration(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn reads_a_config_file() {
+        let config = Config::from_toml(
+            r#"
+            listen = ["0.0.0.0:8080", "[::]:8080"]
+            document_root = "src"
+            not_found_page = ""
+
+            [workers]
+            min = 2
+            max = 8
+
+            [io]
+            mode = "epoll"
+
+            [timeouts]
+            idle = "500ms"
+
+            [rate_limit]
+            enabled = true
+            period = "10s"
+            key_header = "X-Forwarded-For"
+
+            [[rate_limit.routes]]
+            path = "/login"
+            requests = 5
+
+            [log]
+            format = "json"
+            file = "access.log"
+
+            [[proxy]]
+            path = "/api"
+            upstreams = ["127.0.0.1:9001", "127.0.0.1:9002"]
+            balance = "least_connections"
+            health_check = "/health"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen, ["0.0.0.0:8080".parse().unwrap(), "[::]:8080".parse().unwrap()]);
+        assert_eq!(config.document_root, Path::new("src"));
+        assert_eq!(config.not_found_page, None);
+        assert_eq!((config.workers.min, config.workers.max, config.workers.queue_capacity), (2, 8, 64));
+        assert_eq!(config.io, IoMode::Epoll { threads: 2 });
+        assert_eq!(config.timeouts.idle, Duration::from_millis(500));
+        assert_eq!(config.timeouts.shutdown, Duration::from_secs(30));
+        assert_eq!(config.rate_limit.rate, Rate::new(300, Duration::from_secs(10)));
+        assert_eq!(config.rate_limit.key_header.as_deref(), Some("X-Forwarded-For"));
+        assert_eq!(config.rate_limit.routes, [(String::from("/login"), Rate::new(5, Duration::from_secs(10)))]);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.log.file.as_deref(), Some(Path::new("access.log")));
+        assert_eq!(config.proxy.len(), 1);
+        assert_eq!(config.proxy[0].upstreams.len(), 2);
+        assert_eq!(config.proxy[0].balance, Balance::LeastConnections);
+        assert_eq!(config.proxy[0].health_check.as_deref(), Some("/health"));
+        assert_eq!(config.proxy[0].health_interval, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn the_sample_file_has_the_defaults() {
+        assert_eq!(Config::from_toml(include_str!("../server.toml")).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = std::env::temp_dir().join(format!("server-config-{}.toml", std::process::id()));
+        fs::write(&path, "listen = [\"127.0.0.1:8080\"]\n[workers]\nmin = 2\nmax = 8\n").unwrap();
+        let config = Config::load(
+            args(&["--config", path.to_str().unwrap(), "--workers=6", "--listen", "127.0.0.1:9090", "src"]).unwrap(),
+        );
+        let _ = fs::remove_file(&path);
+
+        let config = config.unwrap();
+        assert_eq!(config.listen, ["127.0.0.1:9090".parse().unwrap()]);
+        assert_eq!((config.workers.min, config.workers.max), (6, 8));
+        assert_eq!(config.document_root, Path::new("src"));
+    }
+
+    #[test]
+    fn explains_what_is_wrong() {
+        let message = |result: Result<Config, ConfigError>| result.unwrap_err().to_string();
+
+        assert_eq!(
+            message(Config::from_toml("[workers]\nmin = 4\nmax = 2")),
+            "invalid configuration: workers.max (2) is below workers.min (4)"
+        );
+        assert_eq!(
+            message(Config::load(args(&["--workers", "0"]).unwrap())),
+            "invalid configuration: workers.min must be at least 1"
+        );
+        assert_eq!(
+            message(Config::load(args(&["--root", "no/such/dir"]).unwrap())),
+            "invalid configuration: document_root no/such/dir is not a directory"
+        );
+        assert_eq!(
+            message(Config::from_toml("[tls]\nlisten = [\"127.0.0.1:8443\"]\ncertificate = \"cert.pem\"")),
+            "invalid configuration: tls.listen needs tls.certificate and tls.private_key"
+        );
+        assert!(message(Config::from_toml("[timeouts]\nidle = \"5\"")).contains("`5` is not a duration"));
+        assert!(message(Config::from_toml("[workers]\nthreads = 4")).contains("unknown field `threads`"));
+        assert_eq!(
+            message(Config::from_toml(
+                "[rate_limit]\nenabled = true\n[[rate_limit.routes]]\npath = \"api\"\nrequests = 5"
+            )),
+            "invalid configuration: rate_limit.routes path `api` must start with /"
+        );
+        assert_eq!(
+            message(Config::from_toml("[[proxy]]\npath = \"/api\"\nupstreams = []")),
+            "invalid configuration: proxy `/api` needs at least one upstream"
+        );
+        let proxy = "[[proxy]]\npath = \"/\"\nupstreams = [\"127.0.0.1:9001\"]\nbalance = \"random\"";
+        assert!(message(Config::from_toml(proxy)).contains("unknown balance `random`"));
+        assert!(message(Config::from_toml("listen = [\"localhost\"]")).contains("`localhost` is not an address"));
+
+        let usage = |arguments: &[&str]| args(arguments).unwrap_err().to_string();
+        assert_eq!(usage(&["--threads", "4"]), "unknown option --threads, see --help");
+        assert_eq!(usage(&["--workers"]), "--workers needs a value, see --help");
+        assert_eq!(usage(&["--workers", "-1"]), "--workers: `-1` is not a positive number, see --help");
+        assert!(usage(&["--idle-timeout", "soon"]).starts_with("--idle-timeout: `soon` is not a duration"));
+        assert!(usage(&["--rate-limit", "300"]).starts_with("--rate-limit: `300` is not a rate"));
+    }
+}
@@ -0,0 +1,538 @@
+// ===== Persistent connections
+// Opening a TCP connection costs a round trip (and a lot more with TLS), so HTTP/1.1 keeps the connection
+// open after a response and lets the client send its next request on it. We keep reading requests on the
+// same stream until:
+// - the client asks us to stop with `Connection: close`
+// - the client is an HTTP/1.0 client that didn't ask for `Connection: keep-alive`
+// - the client doesn't send anything for `idle_timeout`
+//
+// A client may also send several requests without waiting for the responses ("pipelining").
+// Since we read and answer one request at a time from the same buffered reader, the responses
+// always go out in the order the requests came in.
+//
+// A client that is slow on purpose ("slowloris") can hold a connection, and in the blocking mode a worker,
+// by sending a request one byte at a time, or by never stopping the headers. So while a request comes in,
+// every read has `read_timeout`, the whole request has `request_timeout`, and it can't be bigger than the
+// `limits`. The client gets a 408, 431 or 413 and the connection is closed.
+
+use std::{
+    cell::RefCell,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+
+use rustls::{ServerConnection, StreamOwned};
+
+use crate::{
+    access_log::{AccessLog, Entry},
+    compression::Compression,
+    request::{Limits, Version},
+    shutdown::{Shutdown, TrackedConnection},
+    tls::TlsConfig,
+    websocket::{Upgrade, WebSocket},
+    Handler, Metrics, ParseError, Request, Response,
+};
+
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    /// How long an open connection may stay silent before we close it.
+    pub idle_timeout: Duration,
+    /// How long the client may stay silent in the middle of a request.
+    pub read_timeout: Duration,
+    /// How long a write may wait for a client that doesn't read its response.
+    pub write_timeout: Duration,
+    /// How long the client has to send a whole request, from its first byte. Checked between reads,
+    /// so it may run over by up to `read_timeout`.
+    pub request_timeout: Duration,
+    /// How big a request may be.
+    pub limits: Limits,
+    /// Where to count the requests and their latency, if anywhere.
+    pub metrics: Option<Metrics>,
+    /// Where to log every request, if anywhere.
+    pub access_log: Option<AccessLog>,
+    /// How to compress responses for clients that accept it, `None` to always send them as they are.
+    pub compression: Option<Compression>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(5),
+            write_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            limits: Limits::default(),
+            metrics: None,
+            access_log: None,
+            compression: None,
+        }
+    }
+}
+
+/// Reads requests from `stream` and writes back the responses of `handler` until the connection should be closed.
+///
+/// Once `shutdown` is triggered the connection is closed after the response to the current request,
+/// or right away if it's waiting for a request.
+pub fn handle_connection(stream: TcpStream, handler: &dyn Handler, config: &ConnectionConfig, shutdown: &Shutdown) {
+    let socket = match prepare(&stream, config, shutdown) {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    // BufReader adds buffering by managing calls to the `std::io::Read` trait methods for us.
+    // `&TcpStream` implements both `Read` and `Write`, so we can read through the buffer and write directly.
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+    serve(&mut reader, &mut writer, handler, config, Some(&socket), stream.peer_addr().ok(), false);
+}
+
+/// Like `handle_connection`, for a connection that starts with a TLS handshake.
+pub fn handle_tls_connection(
+    stream: TcpStream,
+    tls: &TlsConfig,
+    handler: &dyn Handler,
+    config: &ConnectionConfig,
+    shutdown: &Shutdown,
+) {
+    // the timeouts and the shutdown work on the socket under the TLS session, a shutdown closes the
+    // socket and rustls fails the read it was waiting in. The clone is the same socket, it lets us
+    // change the timeouts while the TLS session owns the stream.
+    let clone = match stream.try_clone() {
+        Ok(clone) => clone,
+        Err(_) => return,
+    };
+    let socket = match prepare(&clone, config, shutdown) {
+        Some(socket) => socket,
+        None => return,
+    };
+    let client = stream.peer_addr().ok();
+    let session = match ServerConnection::new(tls.server_config()) {
+        Ok(session) => session,
+        Err(_) => return,
+    };
+
+    // unlike `&TcpStream`, a TLS stream needs `&mut` to read and to write. Reads and writes never overlap,
+    // so the reader and the writer take turns borrowing it. The handshake happens on the first read.
+    let stream = RefCell::new(StreamOwned::new(session, stream));
+    let mut reader = BufReader::new(Shared(&stream));
+    serve(&mut reader, &mut Shared(&stream), handler, config, Some(&socket), client, true);
+
+    // tells the client the connection ends here and wasn't cut by an attacker
+    let mut stream = stream.into_inner();
+    stream.conn.send_close_notify();
+    let _ = stream.flush();
+}
+
+// The socket under the reader and the writer of `serve`, to change its read timeout between the requests
+// and during them, and to let a shutdown close it.
+struct Socket<'a> {
+    stream: &'a TcpStream,
+    tracked: TrackedConnection,
+}
+
+impl Socket<'_> {
+    fn set_read_timeout(&self, timeout: Duration) {
+        // only fails for a zero timeout, which the config doesn't allow
+        let _ = self.stream.set_read_timeout(Some(timeout));
+    }
+}
+
+// Gets the socket ready to be served, `None` if the connection is already gone.
+fn prepare<'a>(stream: &'a TcpStream, config: &ConnectionConfig, shutdown: &Shutdown) -> Option<Socket<'a>> {
+    // every read on the stream now fails with a timeout error if the client stays silent for too long,
+    // and every write if it doesn't read
+    stream.set_read_timeout(Some(config.idle_timeout)).ok()?;
+    stream.set_write_timeout(Some(config.write_timeout)).ok()?;
+    let tracked = shutdown.track(stream).ok()?;
+    Some(Socket { stream, tracked })
+}
+
+// Fails the reads once `deadline` passed, for `request_timeout`.
+struct Deadline<'r, R> {
+    reader: &'r mut R,
+    deadline: Instant,
+}
+
+impl<R: BufRead> Deadline<'_, R> {
+    fn check(&self) -> io::Result<()> {
+        if Instant::now() >= self.deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request deadline exceeded"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for Deadline<'_, R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.check()?;
+        self.reader.read(buffer)
+    }
+}
+
+impl<R: BufRead> BufRead for Deadline<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.check()?;
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount);
+    }
+}
+
+// Reads and writes through a stream that is shared by the reader and the writer of a connection.
+struct Shared<'a, S>(&'a RefCell<S>);
+
+impl<S: Read> Read for Shared<'_, S> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buffer)
+    }
+}
+
+impl<S: Write> Write for Shared<'_, S> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+fn serve<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    handler: &dyn Handler,
+    config: &ConnectionConfig,
+    socket: Option<&Socket<'_>>,
+    client: Option<SocketAddr>,
+    secure: bool,
+) {
+    let tracked = socket.map(|socket| &socket.tracked);
+    loop {
+        // wait for the first byte of the next request while marked as idle, so a shutdown can close us
+        if tracked.is_some_and(|tracked| !tracked.set_idle(true)) {
+            return;
+        }
+        if let Some(socket) = socket {
+            socket.set_read_timeout(config.idle_timeout);
+        }
+        match reader.fill_buf() {
+            Ok(buffer) if !buffer.is_empty() => {}
+            // the client closed the connection, or it was idle for too long
+            _ => return,
+        }
+        if let Some(tracked) = tracked {
+            tracked.set_idle(false);
+        }
+        // the latency we report starts with the first byte of the request
+        let started = Instant::now();
+        let record = |request: Option<&Request>, response: &Response| {
+            record(config, client, request, response, started.elapsed());
+        };
+
+        // the client started a request, now it has to keep sending until it's done
+        if let Some(socket) = socket {
+            socket.set_read_timeout(config.read_timeout);
+        }
+        let mut timed = Deadline {
+            reader: &mut *reader,
+            deadline: started + config.request_timeout,
+        };
+        let request = match Request::parse_with_limits(&mut timed, &config.limits) {
+            Ok(request) => Request { client, secure, ..request },
+            Err(ParseError::ConnectionClosed) => return,
+            Err(err) => {
+                // after a malformed request we can't know where the next one starts, so we close the connection.
+                // It's a 408 if the client was too slow, a 413 or a 431 if it sent too much.
+                let response = Response::error(err.status()).with_header("Connection", "close");
+                let _ = response.write_to(writer);
+                record(None, &response);
+                return;
+            }
+        };
+
+        let shutting_down = || tracked.is_some_and(TrackedConnection::is_shutting_down);
+        let (response, keep_alive) = respond(&request, handler, config, shutting_down);
+
+        // the client may have gone away already, there is nobody left to report the error to
+        let written = response.write_for(request.method, writer);
+        record(Some(&request), &response);
+        if let (Some(upgrade), Ok(())) = (&response.upgrade, &written) {
+            // the connection isn't HTTP anymore, it's the WebSocket's until the handler is done with it
+            if let Some(socket) = socket {
+                socket.set_read_timeout(config.idle_timeout);
+            }
+            upgrade.run(WebSocket::new(reader, writer, tracked));
+            return;
+        }
+        if written.is_err() || !keep_alive {
+            return;
+        }
+    }
+}
+
+/// Runs `handler` for `request` and gets its response ready to be sent, along with whether the connection
+/// stays open after it. `shutting_down` is asked once the handler returns, a slow handler can outlast the server.
+pub(crate) fn respond(
+    request: &Request,
+    handler: &dyn Handler,
+    config: &ConnectionConfig,
+    shutting_down: impl FnOnce() -> bool,
+) -> (Response, bool) {
+    let mut keep_alive = wants_keep_alive(request);
+    // a handler bug shouldn't leave the client without an answer. The worker would survive the panic anyway,
+    // but we don't know what state the handler left things in, so we close the connection too.
+    let mut response = match panic::catch_unwind(AssertUnwindSafe(|| handler.handle(request))) {
+        Ok(response) => response,
+        Err(_) => Response::error(500).with_header("Connection", "close"),
+    };
+    if let Some(compression) = &config.compression {
+        response = compression.apply(request, response);
+    }
+
+    // a handler can also decide to end the connection, and so does a server that is shutting down
+    if response.headers.has_token("Connection", "close") || shutting_down() {
+        keep_alive = false;
+    }
+    // a WebSocket would keep the connection open, so it isn't started anymore
+    if response.upgrade.is_some() && !keep_alive {
+        response = Response::error(503).with_header("Connection", "close");
+    }
+    match (keep_alive, request.version) {
+        (false, _) => response.headers.set("Connection", "close"),
+        // HTTP/1.0 clients assume the connection is closed unless we tell them otherwise
+        (true, Version::Http10) => response.headers.set("Connection", "keep-alive"),
+        (true, Version::Http11) => {}
+    }
+    (response, keep_alive)
+}
+
+/// Runs the WebSocket of a connection the event loop upgraded, on the current worker. `buffered` is what the
+/// client sent after the handshake, before the event loop let go of the connection.
+#[cfg(target_os = "linux")]
+pub(crate) fn run_upgraded(
+    stream: TcpStream,
+    buffered: Vec<u8>,
+    upgrade: &Upgrade,
+    config: &ConnectionConfig,
+    shutdown: &Shutdown,
+) {
+    let socket = match prepare(&stream, config, shutdown) {
+        Some(socket) => socket,
+        None => return,
+    };
+    let mut reader = BufReader::new(io::Cursor::new(buffered).chain(&stream));
+    let mut writer = &stream;
+    upgrade.run(WebSocket::new(&mut reader, &mut writer, Some(&socket.tracked)));
+}
+
+/// Counts the request in the metrics and writes it to the access log, if the config has them.
+pub(crate) fn record(
+    config: &ConnectionConfig,
+    client: Option<SocketAddr>,
+    request: Option<&Request>,
+    response: &Response,
+    duration: Duration,
+) {
+    if let Some(metrics) = &config.metrics {
+        metrics.observe(response.status, duration);
+    }
+    if let Some(log) = &config.access_log {
+        log.log(&Entry {
+            client,
+            request,
+            status: response.status,
+            bytes: response.sent_body_len(),
+            duration,
+        });
+    }
+}
+
+/// HTTP/1.1 connections are persistent unless the client says otherwise, HTTP/1.0 connections are not.
+fn wants_keep_alive(request: &Request) -> bool {
+    match request.version {
+        Version::Http11 => !request.headers.has_token("Connection", "close"),
+        Version::Http10 => request.headers.has_token("Connection", "keep-alive"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::TcpListener,
+        thread,
+        time::Instant,
+    };
+
+    use super::*;
+
+    fn echo_path(request: &Request) -> Response {
+        Response::text(200, request.path.clone())
+    }
+
+    fn exchange(raw: &str) -> String {
+        let mut output = Vec::new();
+        serve(&mut raw.as_bytes(), &mut output, &echo_path, &ConnectionConfig::default(), None, None, false);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        let output = exchange("GET /first HTTP/1.1\r\n\r\nGET /second HTTP/1.1\r\n\r\nGET /third HTTP/1.1\r\n\r\n");
+
+        let first = output.find("/first").unwrap();
+        let second = output.find("/second").unwrap();
+        let third = output.find("/third").unwrap();
+        assert!(first < second && second < third);
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 3);
+        assert!(!output.contains("Connection: close"));
+    }
+
+    #[test]
+    fn stops_after_connection_close() {
+        let output = exchange("GET /first HTTP/1.1\r\nConnection: close\r\n\r\nGET /second HTTP/1.1\r\n\r\n");
+
+        assert!(output.contains("Connection: close"));
+        assert!(output.contains("/first"));
+        assert!(!output.contains("/second"));
+    }
+
+    #[test]
+    fn http_1_0_closes_unless_asked_to_keep_alive() {
+        let output = exchange("GET /first HTTP/1.0\r\n\r\nGET /second HTTP/1.0\r\n\r\n");
+        assert!(output.contains("Connection: close"));
+        assert!(!output.contains("/second"));
+
+        let output = exchange("GET /first HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /second HTTP/1.0\r\n\r\n");
+        assert!(output.contains("Connection: keep-alive"));
+        assert!(output.contains("/second"));
+    }
+
+    #[test]
+    fn closes_after_a_bad_request() {
+        let output = exchange("NOPE\r\n\r\nGET /second HTTP/1.1\r\n\r\n");
+
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request"));
+        assert!(!output.contains("/second"));
+    }
+
+    #[test]
+    fn answers_500_when_the_handler_panics() {
+        let mut output = Vec::new();
+        let handler = |_: &Request| -> Response { panic!("handler bug") };
+        serve(&mut "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n".as_bytes(), &mut output, &handler, &ConnectionConfig::default(), None, None, false);
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 500 Internal Server Error"));
+        assert!(output.contains("Connection: close"));
+        assert_eq!(output.matches("HTTP/1.1").count(), 1);
+    }
+
+    #[test]
+    fn records_every_answer() {
+        let metrics = Metrics::new();
+        let config = ConnectionConfig {
+            metrics: Some(metrics.clone()),
+            ..ConnectionConfig::default()
+        };
+        let mut output = Vec::new();
+        let raw = "GET /first HTTP/1.1\r\n\r\nNOPE\r\n\r\n";
+        serve(&mut raw.as_bytes(), &mut output, &echo_path, &config, None, None, false);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("http_requests_total{code=\"200\"} 1\n"));
+        assert!(rendered.contains("http_requests_total{code=\"400\"} 1\n"));
+        assert!(rendered.contains("http_request_duration_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let config = ConnectionConfig {
+                idle_timeout: Duration::from_millis(100),
+                ..ConnectionConfig::default()
+            };
+            handle_connection(stream, &echo_path, &config, &Shutdown::new());
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+
+        let started = Instant::now();
+        let mut output = String::new();
+        // read_to_string only returns once the server closes the connection
+        client.read_to_string(&mut output).unwrap();
+
+        assert!(output.contains("/hello"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn answers_431_and_413_to_oversized_requests() {
+        let config = ConnectionConfig {
+            limits: Limits {
+                max_header_bytes: 256,
+                max_headers: 10,
+                max_body_size: 16,
+            },
+            ..ConnectionConfig::default()
+        };
+        let exchange = |raw: String| {
+            let mut output = Vec::new();
+            serve(&mut raw.as_bytes(), &mut output, &echo_path, &config, None, None, false);
+            String::from_utf8(output).unwrap()
+        };
+
+        let output = exchange(format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "x".repeat(300)));
+        assert!(output.starts_with("HTTP/1.1 431 Request Header Fields Too Large"));
+        let output = exchange(format!("GET / HTTP/1.1\r\n{}\r\n", "A: 1\r\n".repeat(11)));
+        assert!(output.starts_with("HTTP/1.1 431 Request Header Fields Too Large"));
+        let output = exchange(String::from("POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n"));
+        assert!(output.starts_with("HTTP/1.1 413 Content Too Large"));
+        assert!(output.contains("Connection: close"));
+    }
+
+    #[test]
+    fn answers_408_to_a_request_that_trickles_in() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let config = ConnectionConfig {
+                read_timeout: Duration::from_secs(1),
+                request_timeout: Duration::from_millis(300),
+                ..ConnectionConfig::default()
+            };
+            handle_connection(stream, &echo_path, &config, &Shutdown::new());
+        });
+
+        // a byte every 100ms never trips the read timeout, the request deadline stops it
+        let mut client = TcpStream::connect(address).unwrap();
+        let started = Instant::now();
+        for byte in b"GET / HTTP/1.1\r\nX-Slow: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa" {
+            // writing after the server closed would reset the connection, and lose its answer
+            client.set_nonblocking(true).unwrap();
+            let answered = client.peek(&mut [0]).is_ok();
+            client.set_nonblocking(false).unwrap();
+            if answered {
+                break;
+            }
+            client.write_all(&[*byte]).unwrap();
+            thread::sleep(Duration::from_millis(100));
+        }
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout"));
+        assert!(started.elapsed() < Duration::from_millis(900));
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+// ===== epoll
+// The few Linux system calls the event loop needs, wrapped so the rest of the code doesn't touch raw
+// file descriptors:
+// - an epoll instance watches many sockets and tells us which ones are ready to read or write,
+//   instead of a thread blocked on each of them
+// - an eventfd is a counter other threads can bump to wake up a thread waiting in `epoll_wait`
+// https://man7.org/linux/man-pages/man7/epoll.7.html
+
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    time::Duration,
+};
+
+/// What to wait for on a file descriptor. Errors and hang-ups are always reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Interest {
+    Read,
+    Write,
+    // the connection is busy with something else, we don't want to hear from it for now
+    Nothing,
+}
+
+impl Interest {
+    fn events(self) -> u32 {
+        match self {
+            Interest::Read => (libc::EPOLLIN | libc::EPOLLRDHUP) as u32,
+            Interest::Write => libc::EPOLLOUT as u32,
+            Interest::Nothing => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Event {
+    pub(crate) token: u64,
+    pub(crate) readable: bool,
+    pub(crate) writable: bool,
+    /// The connection failed or both sides closed it.
+    pub(crate) closed: bool,
+}
+
+pub(crate) struct Epoll {
+    fd: OwnedFd,
+    events: Vec<libc::epoll_event>,
+}
+
+impl Epoll {
+    pub(crate) fn new() -> io::Result<Self> {
+        // SAFETY: no pointers involved, the result is checked before it's used as a file descriptor
+        let fd = check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        Ok(Self {
+            // SAFETY: epoll_create1 succeeded, the descriptor is ours
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            events: vec![libc::epoll_event { events: 0, u64: 0 }; 256],
+        })
+    }
+
+    /// Starts watching `fd`, its events come back with `token`.
+    pub(crate) fn add(&self, fd: RawFd, token: u64, interest: Interest) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    pub(crate) fn modify(&self, fd: RawFd, token: u64, interest: Interest) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    /// Stops watching `fd`. Closing the descriptor does it too, as long as it wasn't duplicated.
+    pub(crate) fn delete(&self, fd: RawFd) -> io::Result<()> {
+        // SAFETY: a null event is allowed for EPOLL_CTL_DEL
+        check(unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) })?;
+        Ok(())
+    }
+
+    /// Waits until something is ready or `timeout` passed, and returns what is ready.
+    pub(crate) fn wait(&mut self, timeout: Duration) -> io::Result<Vec<Event>> {
+        // rounded up, a timeout of 0 would turn waiting for the next deadline into a busy loop
+        let millis = timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32;
+        // SAFETY: the buffer is valid for `len` events and the kernel writes at most that many
+        let ready = unsafe {
+            libc::epoll_wait(self.fd.as_raw_fd(), self.events.as_mut_ptr(), self.events.len() as i32, millis)
+        };
+        let ready = match check(ready) {
+            Ok(ready) => ready as usize,
+            // a signal arrived while we were waiting, like a timeout with nothing ready
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => 0,
+            Err(err) => return Err(err),
+        };
+
+        Ok(self.events[..ready]
+            .iter()
+            .map(|event| {
+                let flags = event.events as i32;
+                Event {
+                    token: event.u64,
+                    readable: flags & (libc::EPOLLIN | libc::EPOLLRDHUP) != 0,
+                    writable: flags & libc::EPOLLOUT != 0,
+                    closed: flags & (libc::EPOLLHUP | libc::EPOLLERR) != 0,
+                }
+            })
+            .collect())
+    }
+
+    fn control(&self, operation: i32, fd: RawFd, token: u64, interest: Interest) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: interest.events(),
+            u64: token,
+        };
+        // SAFETY: `event` lives for the whole call, the kernel copies it
+        check(unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), operation, fd, &mut event) })?;
+        Ok(())
+    }
+}
+
+/// Wakes up a thread waiting in `Epoll::wait`, once it watches the eventfd like any other descriptor.
+pub(crate) struct EventFd {
+    fd: OwnedFd,
+}
+
+impl EventFd {
+    pub(crate) fn new() -> io::Result<Self> {
+        // SAFETY: no pointers involved, the result is checked before it's used as a file descriptor
+        let fd = check(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })?;
+        // SAFETY: eventfd succeeded, the descriptor is ours
+        Ok(Self { fd: unsafe { OwnedFd::from_raw_fd(fd) } })
+    }
+
+    pub(crate) fn wake(&self) {
+        let one: u64 = 1;
+        // can only fail if the counter is about to overflow, and then the waiting thread is woken up anyway
+        // SAFETY: writes the 8 bytes of `one`
+        unsafe { libc::write(self.fd.as_raw_fd(), (&one as *const u64).cast(), 8) };
+    }
+
+    /// Resets the counter, so the descriptor isn't readable until the next `wake`.
+    pub(crate) fn reset(&self) {
+        let mut count: u64 = 0;
+        // fails with EAGAIN if nobody woke us up, which is fine
+        // SAFETY: reads at most 8 bytes into `count`
+        unsafe { libc::read(self.fd.as_raw_fd(), (&mut count as *mut u64).cast(), 8) };
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+fn check(result: i32) -> io::Result<i32> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
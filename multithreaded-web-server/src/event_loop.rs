@@ -0,0 +1,671 @@
+// ===== Event-driven connections
+// In the blocking mode every connection is a job that keeps a worker busy for as long as the connection
+// is open, even while it only waits for the client: four keep-alive browsers, or four clients on the
+// `/sleep` route, and a pool of four workers has nobody left for the fifth client.
+//
+// In the event-driven mode a few event loop threads own the connections. Their sockets are non-blocking
+// and epoll tells a loop which of them it can read or write without waiting, so one thread keeps
+// thousands of mostly idle connections going. Only a complete request goes to the pool, the worker runs
+// the handler and sends the response back to the loop, which writes it:
+//
+//   accept thread ── new connection ──▶ event loop ── request ──▶ pool worker (handler)
+//                                           ▲                          │
+//                                           └───────── response ───────┘
+//
+// A connection moves between three states:
+// - reading: waiting for the rest of a request, watched for input
+// - handling: a worker runs the handler, not watched at all (pipelined requests wait in the socket)
+// - writing: the response didn't fit in the socket's buffer, watched until it can take the rest
+//
+// A WebSocket leaves the loop once its 101 is written: it goes to a worker, with the socket made blocking again.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    os::fd::AsRawFd,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    connection, request,
+    epoll::{Epoll, Event, EventFd, Interest},
+    server::ServerConfig,
+    static_files::{self, FileBody},
+    websocket::Upgrade,
+    Handler, Limits, Method, Request, Response, Shutdown, ThreadPool,
+};
+
+// the token of the eventfd that wakes up the loop, connections count from 1
+const WAKER: u64 = 0;
+// how long the loop waits for events before it looks for connections that were idle for too long
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+const READ_CHUNK: usize = 16 * 1024;
+// chunks a connection reads per event at most. epoll reports it again if there is more, after the other
+// connections of the loop had their turn
+const READS_PER_EVENT: usize = 4;
+
+/// What other threads tell an event loop.
+pub(crate) enum Message {
+    /// A connection the accept loop got, for this loop to serve with the handler of its listener.
+    Connection(TcpStream, Arc<dyn Handler>),
+    /// A worker ran the handler for the request of connection `token`.
+    Response {
+        token: u64,
+        // boxed, the other messages are much smaller
+        request: Box<Request>,
+        response: Response,
+        keep_alive: bool,
+    },
+    /// The listeners are closed: finish the requests in progress, close the idle connections, then stop.
+    /// Whatever is still going at the deadline is abandoned.
+    Drain { deadline: Instant },
+}
+
+/// Sends messages to an event loop from any thread.
+#[derive(Clone)]
+pub(crate) struct LoopHandle {
+    sender: mpsc::Sender<Message>,
+    waker: Arc<EventFd>,
+}
+
+impl LoopHandle {
+    pub(crate) fn send(&self, message: Message) {
+        // the loop only stops after a drain, nothing sent after that needs an answer
+        if self.sender.send(message).is_ok() {
+            self.waker.wake();
+        }
+    }
+}
+
+/// What the event loops share with the rest of the server.
+pub(crate) struct Context<'a> {
+    pub(crate) pool: &'a ThreadPool,
+    pub(crate) config: &'a Arc<ServerConfig>,
+    pub(crate) shutdown: &'a Shutdown,
+}
+
+pub(crate) struct EventLoop {
+    epoll: Epoll,
+    waker: Arc<EventFd>,
+    // a handle to ourselves, every job gets a clone to send its response back
+    handle: LoopHandle,
+    messages: mpsc::Receiver<Message>,
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+}
+
+struct Connection {
+    stream: TcpStream,
+    // the server's, or the redirect to HTTPS on a plain listener
+    handler: Arc<dyn Handler>,
+    client: Option<SocketAddr>,
+    state: State,
+    // what the client sent that isn't part of a request we handled yet
+    input: Vec<u8>,
+    // the client shut down its side, no more input is coming
+    input_closed: bool,
+    // how far we got into the request that is arriving
+    pending: Pending,
+    output: Vec<u8>,
+    written: usize,
+    // the body of the response still to read from a file into `output`, and how much of it was read
+    file: Option<FileBody>,
+    file_read: u64,
+    // the response being written, and its request, to record once it's sent
+    answered: Option<(Option<Request>, Response)>,
+    close_after_write: bool,
+    // the WebSocket to start once the response is written
+    upgrade: Option<Upgrade>,
+    // when the first byte of the current request arrived, for the latency we report
+    started: Instant,
+    // when we last heard from the client, for the idle timeout
+    last_active: Instant,
+}
+
+// A request that trickles in is only looked at from where we stopped on the previous read, and parsed once
+// all of it is there, instead of being parsed from the start again on every read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pending {
+    // the end of the headers isn't in the first `searched` bytes
+    Head { searched: usize },
+    // the headers gave the length of the body, the request ends at `end`
+    Body { end: usize },
+    // a chunked body, the size line of the next chunk starts at `next`
+    Chunks { next: usize },
+    // all of the request is there, or it's malformed: the parser tells which
+    Parse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Reading,
+    Handling,
+    Writing,
+}
+
+impl EventLoop {
+    pub(crate) fn new() -> io::Result<Self> {
+        let epoll = Epoll::new()?;
+        let waker = Arc::new(EventFd::new()?);
+        epoll.add(waker.as_raw_fd(), WAKER, Interest::Read)?;
+        let (sender, messages) = mpsc::channel();
+
+        Ok(Self {
+            epoll,
+            handle: LoopHandle {
+                sender,
+                waker: Arc::clone(&waker),
+            },
+            waker,
+            messages,
+            connections: HashMap::new(),
+            next_token: WAKER + 1,
+        })
+    }
+
+    pub(crate) fn handle(&self) -> LoopHandle {
+        self.handle.clone()
+    }
+
+    /// Serves connections until a `Message::Drain`. Returns true if every connection was done by its deadline.
+    pub(crate) fn run(mut self, context: &Context<'_>) -> bool {
+        let mut deadline = None;
+        let mut last_sweep = Instant::now();
+
+        loop {
+            let events = match self.epoll.wait(SWEEP_INTERVAL) {
+                Ok(events) => events,
+                Err(err) => {
+                    eprintln!("Event loop failed: {err}");
+                    return false;
+                }
+            };
+            for event in events {
+                match event.token {
+                    // reset before reading the messages, a message sent after that wakes us up again
+                    WAKER => self.waker.reset(),
+                    token => self.ready(token, event, context),
+                }
+            }
+            while let Ok(message) = self.messages.try_recv() {
+                match message {
+                    Message::Connection(stream, handler) => self.add(stream, handler),
+                    Message::Response {
+                        token,
+                        request,
+                        response,
+                        keep_alive,
+                    } => self.answer(token, Some(*request), response, keep_alive, context),
+                    Message::Drain { deadline: at } => deadline = Some(at),
+                }
+            }
+
+            let now = Instant::now();
+            if deadline.is_some() || now - last_sweep >= SWEEP_INTERVAL {
+                self.sweep(now, deadline.is_some(), context);
+                last_sweep = now;
+            }
+            match deadline {
+                Some(_) if self.connections.is_empty() => return true,
+                Some(deadline) if now >= deadline => return false,
+                _ => {}
+            }
+        }
+    }
+
+    fn add(&mut self, stream: TcpStream, handler: Arc<dyn Handler>) {
+        let token = self.next_token;
+        self.next_token += 1;
+        if stream.set_nonblocking(true).is_err() || self.epoll.add(stream.as_raw_fd(), token, Interest::Read).is_err() {
+            return;
+        }
+
+        let now = Instant::now();
+        let connection = Connection {
+            client: stream.peer_addr().ok(),
+            stream,
+            handler,
+            state: State::Reading,
+            input: Vec::new(),
+            input_closed: false,
+            pending: Pending::Head { searched: 0 },
+            output: Vec::new(),
+            written: 0,
+            file: None,
+            file_read: 0,
+            answered: None,
+            close_after_write: false,
+            upgrade: None,
+            started: now,
+            last_active: now,
+        };
+        self.connections.insert(token, connection);
+    }
+
+    fn ready(&mut self, token: u64, event: Event, context: &Context<'_>) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        if event.closed {
+            self.close(token);
+            return;
+        }
+
+        match connection.state {
+            State::Reading if event.readable => {
+                let was_empty = connection.input.is_empty();
+                let limits = &context.config.connection.limits;
+                if connection.fill(max_request_size(limits)).is_err() {
+                    self.close(token);
+                    return;
+                }
+                let now = Instant::now();
+                if was_empty && !connection.input.is_empty() {
+                    connection.started = now;
+                }
+                connection.last_active = now;
+                self.next_request(token, context);
+            }
+            State::Writing if event.writable => self.write(token, context),
+            _ => {}
+        }
+    }
+
+    // Handles the next request of the connection if all of it arrived.
+    fn next_request(&mut self, token: u64, context: &Context<'_>) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        if connection.input.is_empty() {
+            if connection.input_closed {
+                self.close(token);
+            }
+            return;
+        }
+
+        let limits = &context.config.connection.limits;
+        connection.pending = advance(&connection.input, connection.pending, limits);
+        // `fill` stopped reading at the limits, a request that still isn't complete is too big for them
+        if connection.pending != Pending::Parse && connection.input.len() > max_request_size(limits) {
+            let status = match connection.pending {
+                Pending::Head { .. } => 431,
+                _ => 413,
+            };
+            let response = Response::error(status).with_header("Connection", "close");
+            self.answer(token, None, response, false, context);
+            return;
+        }
+        // a client that stopped sending in the middle of a request gets the parser's answer to that
+        if connection.pending != Pending::Parse && !connection.input_closed {
+            return;
+        }
+
+        // the parser reads from a `BufRead`, a slice of what we have so far is one
+        let mut unread = connection.input.as_slice();
+        match Request::parse_with_limits(&mut unread, limits) {
+            Ok(mut request) => {
+                let consumed = connection.input.len() - unread.len();
+                connection.input.drain(..consumed);
+                connection.pending = Pending::Head { searched: 0 };
+                request.client = connection.client;
+                self.run_handler(token, request, context);
+            }
+            // after a malformed request we can't know where the next one starts, so we close the connection
+            Err(err) => {
+                let response = Response::error(err.status()).with_header("Connection", "close");
+                self.answer(token, None, response, false, context);
+            }
+        }
+    }
+
+    // Runs the handler on the pool, the response comes back as a message.
+    fn run_handler(&mut self, token: u64, request: Request, context: &Context<'_>) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        connection.state = State::Handling;
+        let _ = self.epoll.modify(connection.stream.as_raw_fd(), token, Interest::Nothing);
+        let handler = Arc::clone(&connection.handler);
+
+        let handle = self.handle();
+        let config = Arc::clone(context.config);
+        let shutdown = context.shutdown.clone();
+        let submitted = context.pool.execute(move || {
+            let (response, keep_alive) =
+                connection::respond(&request, handler.as_ref(), &config.connection, || shutdown.is_triggered());
+            handle.send(Message::Response {
+                token,
+                request: Box::new(request),
+                response,
+                keep_alive,
+            });
+        });
+
+        // the request went down with the job, we can still tell the client to come back later
+        if submitted.is_err() {
+            let response = Response::error(503)
+                .with_header("Retry-After", context.config.retry_after.as_secs().max(1).to_string())
+                .with_header("Connection", "close");
+            self.answer(token, None, response, false, context);
+        }
+    }
+
+    // Starts writing a response.
+    fn answer(
+        &mut self,
+        token: u64,
+        request: Option<Request>,
+        response: Response,
+        keep_alive: bool,
+        context: &Context<'_>,
+    ) {
+        // the connection is gone if the client hung up while the handler was running
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        connection.output.clear();
+        // writing to a Vec can't fail
+        let method = request.as_ref().map_or(Method::Get, |request| request.method);
+        connection.file = response.write_buffered_for(method, &mut connection.output).unwrap_or(None).cloned();
+        connection.file_read = 0;
+        connection.written = 0;
+        connection.answered = Some((request, response));
+        connection.close_after_write = !keep_alive;
+        connection.state = State::Writing;
+        connection.last_active = Instant::now();
+        self.write(token, context);
+    }
+
+    // Writes as much of the response as the socket takes.
+    fn write(&mut self, token: u64, context: &Context<'_>) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let fd = connection.stream.as_raw_fd();
+        let written = connection.written;
+        let flushed = connection.flush();
+        if connection.written > written {
+            connection.last_active = Instant::now();
+        }
+        if let Ok(false) = flushed {
+            let _ = self.epoll.modify(fd, token, Interest::Write);
+            return;
+        }
+
+        if let Some((request, response)) = connection.answered.take() {
+            connection.upgrade = response.upgrade.clone();
+            let duration = connection.started.elapsed();
+            connection::record(&context.config.connection, connection.client, request.as_ref(), &response, duration);
+        }
+        if flushed.is_err() || connection.close_after_write {
+            self.close(token);
+            return;
+        }
+        if let Some(upgrade) = connection.upgrade.take() {
+            self.hand_over(token, upgrade, context);
+            return;
+        }
+
+        // back to waiting for the next request, which may already be in the buffer
+        let now = Instant::now();
+        connection.state = State::Reading;
+        connection.started = now;
+        connection.last_active = now;
+        let _ = self.epoll.modify(fd, token, Interest::Read);
+        self.next_request(token, context);
+    }
+
+    // A WebSocket keeps its worker for as long as it's open, like a connection in the blocking mode.
+    // The loop lets go of the connection and a job runs the WebSocket on a blocking socket.
+    fn hand_over(&mut self, token: u64, upgrade: Upgrade, context: &Context<'_>) {
+        let connection = match self.connections.remove(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let _ = self.epoll.delete(connection.stream.as_raw_fd());
+        if connection.stream.set_nonblocking(false).is_err() {
+            return;
+        }
+
+        let config = Arc::clone(context.config);
+        let shutdown = context.shutdown.clone();
+        // if the queue is full the stream is dropped, and the client sees the connection close
+        let _ = context.pool.execute(move || {
+            connection::run_upgraded(connection.stream, connection.input, &upgrade, &config.connection, &shutdown);
+        });
+    }
+
+    // Closes the connections that were quiet for too long, and the idle ones once we are draining.
+    // A client that is too slow to send its request gets a 408 first.
+    fn sweep(&mut self, now: Instant, draining: bool, context: &Context<'_>) {
+        let config = &context.config.connection;
+        let mut expired = Vec::new();
+        let mut too_slow = Vec::new();
+        for (token, connection) in &self.connections {
+            let quiet = now - connection.last_active;
+            // a request started arriving, otherwise the connection waits for the next one
+            let receiving = !connection.input.is_empty();
+            let too_long = now - connection.started >= config.request_timeout;
+            match connection.state {
+                State::Reading if !receiving && (quiet >= config.idle_timeout || draining) => expired.push(*token),
+                State::Reading if receiving && (quiet >= config.read_timeout || too_long) => too_slow.push(*token),
+                State::Writing if quiet >= config.write_timeout => expired.push(*token),
+                _ => {}
+            }
+        }
+
+        for token in expired {
+            self.close(token);
+        }
+        for token in too_slow {
+            let response = Response::error(408).with_header("Connection", "close");
+            self.answer(token, None, response, false, context);
+        }
+    }
+
+    fn close(&mut self, token: u64) {
+        if let Some(connection) = self.connections.remove(&token) {
+            // dropping the stream closes the socket, which also removes it from epoll
+            let _ = self.epoll.delete(connection.stream.as_raw_fd());
+        }
+    }
+}
+
+impl Connection {
+    // Reads what the socket has for us, `READS_PER_EVENT` chunks at most, and nothing once the input is over
+    // `limit` bytes.
+    fn fill(&mut self, limit: usize) -> io::Result<()> {
+        let mut buffer = [0; READ_CHUNK];
+        for _ in 0..READS_PER_EVENT {
+            if self.input.len() > limit {
+                return Ok(());
+            }
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.input_closed = true;
+                    return Ok(());
+                }
+                Ok(read) => self.input.extend_from_slice(&buffer[..read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    // Writes what the socket takes, true once the whole output is written. A body read from a file goes
+    // through the output a chunk at a time, the next chunk is read once the socket took the previous one.
+    fn flush(&mut self) -> io::Result<bool> {
+        loop {
+            while self.written < self.output.len() {
+                match self.stream.write(&self.output[self.written..]) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(written) => self.written += written,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let file = match &self.file {
+                Some(file) => file,
+                None => return Ok(true),
+            };
+            self.output.resize(static_files::CHUNK_SIZE, 0);
+            let read = file.read_at(self.file_read, &mut self.output)?;
+            self.output.truncate(read);
+            self.written = 0;
+            self.file_read += read as u64;
+            if read == 0 {
+                self.file = None;
+            }
+        }
+    }
+}
+
+// The most a request can take in the input: all the headers the limits allow, then all the body.
+fn max_request_size(limits: &Limits) -> usize {
+    limits.max_header_bytes.saturating_add(usize::try_from(limits.max_body_size).unwrap_or(usize::MAX))
+}
+
+// Looks at what arrived of a request since `pending`. Only the headers are parsed here, to learn where the
+// body ends, anything wrong with them is left for the parser to report.
+fn advance(input: &[u8], mut pending: Pending, limits: &Limits) -> Pending {
+    loop {
+        pending = match pending {
+            Pending::Head { searched } => match blank_line(input, searched.saturating_sub(2)) {
+                Some(end) => framing(&input[..end], limits),
+                // past the limit the parser answers 431
+                None if input.len() >= limits.max_header_bytes => return Pending::Parse,
+                None => return Pending::Head { searched: input.len() },
+            },
+            Pending::Body { end } if input.len() >= end => return Pending::Parse,
+            Pending::Chunks { next } => return chunks(input, next, limits),
+            pending => return pending,
+        };
+    }
+}
+
+// Where the body of a request with the head `head` ends.
+fn framing(head: &[u8], limits: &Limits) -> Pending {
+    let request = match Request::parse_head(&mut &head[..], limits) {
+        Ok(request) => request,
+        Err(_) => return Pending::Parse,
+    };
+    let headers = &request.headers;
+    if headers.contains("Transfer-Encoding") {
+        if headers.contains("Content-Length") || !headers.has_token("Transfer-Encoding", "chunked") {
+            return Pending::Parse;
+        }
+        return Pending::Chunks { next: head.len() };
+    }
+    match request::content_length(headers) {
+        // the parser refuses a body over the limit before reading any of it
+        Ok(Some(length)) if length <= limits.max_body_size => Pending::Body {
+            end: head.len() + length as usize,
+        },
+        _ => Pending::Parse,
+    }
+}
+
+// Follows the chunks of a body from the size line at `next`, as far as they arrived.
+fn chunks(input: &[u8], mut next: usize, limits: &Limits) -> Pending {
+    loop {
+        let line_end = match input[next..].iter().position(|&byte| byte == b'\n') {
+            Some(position) => next + position,
+            None if input.len() - next > request::MAX_CHUNK_LINE => return Pending::Parse,
+            None => return Pending::Chunks { next },
+        };
+        let line = input[next..line_end].strip_suffix(b"\r").unwrap_or(&input[next..line_end]);
+        let size = line.split(|&byte| byte == b';').next().unwrap_or_default();
+        let size = match std::str::from_utf8(size).ok().and_then(|size| u64::from_str_radix(size.trim(), 16).ok()) {
+            Some(size) if size <= limits.max_body_size => size as usize,
+            _ => return Pending::Parse,
+        };
+        if size == 0 {
+            // the trailers end with an empty line, like the headers
+            return match blank_line(input, line_end) {
+                Some(_) => Pending::Parse,
+                None if input.len() - line_end > limits.max_header_bytes => Pending::Parse,
+                None => Pending::Chunks { next },
+            };
+        }
+        // the data, then a CRLF (or a bare LF)
+        let data_end = line_end + 1 + size;
+        next = match (input.get(data_end), input.get(data_end + 1)) {
+            (Some(b'\n'), _) => data_end + 1,
+            (Some(b'\r'), Some(b'\n')) => data_end + 2,
+            (None, _) | (Some(b'\r'), None) => return Pending::Chunks { next },
+            _ => return Pending::Parse,
+        };
+    }
+}
+
+// The end of the first empty line after a line ending at or after `from`, bare LFs included.
+fn blank_line(input: &[u8], from: usize) -> Option<usize> {
+    (from..input.len()).filter(|&index| input[index] == b'\n').find_map(|index| match &input[index + 1..] {
+        [b'\n', ..] => Some(index + 2),
+        [b'\r', b'\n', ..] => Some(index + 3),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what `advance` says after each of `pieces` arrived
+    fn trickle(pieces: &[&str]) -> Vec<Pending> {
+        let mut input = Vec::new();
+        let mut pending = Pending::Head { searched: 0 };
+        pieces
+            .iter()
+            .map(|piece| {
+                input.extend_from_slice(piece.as_bytes());
+                pending = advance(&input, pending, &Limits::default());
+                pending
+            })
+            .collect()
+    }
+
+    #[test]
+    fn waits_for_the_whole_request() {
+        let head = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(
+            trickle(&["POST / HTTP/1.1\r\nContent-Le", "ngth: 5\r\n\r", "\nhel", "lo"]),
+            [
+                Pending::Head { searched: 27 },
+                Pending::Head { searched: 37 },
+                Pending::Body { end: head.len() + 5 },
+                Pending::Parse
+            ]
+        );
+
+        let head = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let chunks = trickle(&[head, "5\r\nhel", "lo\r\n", "0\r\n", "\r\n"]);
+        let second = head.len() + "5\r\nhello\r\n".len();
+        assert_eq!(
+            chunks,
+            [
+                Pending::Chunks { next: head.len() },
+                Pending::Chunks { next: head.len() },
+                Pending::Chunks { next: second },
+                Pending::Chunks { next: second },
+                Pending::Parse
+            ]
+        );
+
+        // nothing to wait for without a body, or once the request is malformed
+        assert_eq!(trickle(&["GET / HTTP/1.1\n\n"]), [Pending::Parse]);
+        assert_eq!(trickle(&["POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n"]), [Pending::Parse]);
+        assert_eq!(trickle(&["POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"]), [Pending::Parse]);
+    }
+}
@@ -0,0 +1,303 @@
+// ===== Caching files in memory
+// The same few pages, stylesheets and scripts make up most of the requests. Instead of reading them from
+// the disk every time, we keep the responses built from them (body and headers) in memory, up to a number
+// of bytes. When a new file doesn't fit, the files that went unused the longest make room for it: a "least
+// recently used" (LRU) cache.
+//
+// A file can change while it's cached. Every request still looks at the file's metadata (one `stat`, no
+// read), and a modification time or a size that is not the one we cached means the copy is stale: we
+// read the file again and replace it.
+//
+// The hits and misses are counted, `Metrics::watch_cache` puts them next to the other metrics. A good
+// hit rate means the cache is big enough for the files people ask for.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, Metadata},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use crate::{headers::Headers, Response};
+
+/// A byte-bounded LRU cache of the `200` responses of files, see `StaticFiles::cache`.
+///
+/// Cloning gives another handle to the same cache.
+#[derive(Debug, Clone)]
+pub struct FileCache {
+    inner: Arc<Inner>,
+    max_file_size: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    capacity: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    by_path: HashMap<PathBuf, Entry>,
+    // the paths by when they were last used, the first one goes first
+    by_use: BTreeMap<u64, PathBuf>,
+    // incremented on every use, a clock that never gives the same time twice
+    clock: u64,
+    size: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    // what the file was when we read it
+    modified: SystemTime,
+    len: u64,
+    // shared, a hit only clones the handle under the lock and copies the body once it's released
+    response: Arc<Cached>,
+    // what it counts for in the capacity
+    size: usize,
+    last_used: u64,
+}
+
+#[derive(Debug)]
+struct Cached {
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+/// A snapshot of the counters of a `FileCache`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl FileCache {
+    /// A cache holding up to `capacity` bytes of responses. Files bigger than an eighth of it aren't
+    /// cached, so that one large download doesn't push out all the small files.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                capacity,
+                entries: Mutex::new(Entries::default()),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+            max_file_size: capacity as u64 / 8,
+        }
+    }
+
+    /// Sets the size of the biggest file that gets cached. Bigger files are read on every request.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// The response for the file at `path`, whose current metadata is `metadata`. From the cache if it
+    /// has the file as it is now, otherwise the file is read and `build` turns its contents into the
+    /// response, which is cached for the next time.
+    pub fn response(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        build: impl FnOnce(Vec<u8>) -> Response,
+    ) -> io::Result<Response> {
+        let modified = metadata.modified().ok();
+        if let Some(modified) = modified {
+            if let Some(response) = self.get(path, modified, metadata.len()) {
+                self.inner.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(response);
+            }
+        }
+        self.inner.misses.fetch_add(1, Ordering::Relaxed);
+
+        // read without holding the lock, the other requests don't wait for the disk
+        let response = build(fs::read(path)?);
+        match modified {
+            Some(modified) if metadata.len() <= self.max_file_size => {
+                self.insert(path, modified, metadata.len(), &response)
+            }
+            // without a modification time we couldn't tell when the copy gets stale
+            _ => {}
+        }
+        Ok(response)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.lock();
+        CacheStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+            entries: entries.by_path.len(),
+            bytes: entries.size,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        // the entries are consistent between statements, a panic can't leave them half updated
+        self.inner.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn get(&self, path: &Path, modified: SystemTime, len: u64) -> Option<Response> {
+        let cached = {
+            let mut entries = self.lock();
+            let entry = entries.by_path.get(path)?;
+            if entry.modified != modified || entry.len != len {
+                entries.remove(path);
+                return None;
+            }
+            let cached = Arc::clone(&entry.response);
+            entries.touch(path);
+            cached
+        };
+        let mut response = Response::new(200).with_body(cached.body.clone());
+        response.headers = cached.headers.clone();
+        Some(response)
+    }
+
+    fn insert(&self, path: &Path, modified: SystemTime, len: u64, response: &Response) {
+        let entry_size = size(path, &response.headers, &response.body);
+        if entry_size > self.inner.capacity {
+            return;
+        }
+        // copied before taking the lock
+        let cached = Arc::new(Cached {
+            headers: response.headers.clone(),
+            body: response.body.clone(),
+        });
+        let mut entries = self.lock();
+        // another request may have read the same file in the meantime
+        entries.remove(path);
+        while entries.size + entry_size > self.inner.capacity {
+            let oldest = match entries.by_use.first_key_value() {
+                Some((_, oldest)) => oldest.clone(),
+                None => break,
+            };
+            entries.remove(&oldest);
+        }
+
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.by_use.insert(last_used, path.to_path_buf());
+        entries.size += entry_size;
+        entries.by_path.insert(
+            path.to_path_buf(),
+            Entry {
+                modified,
+                len,
+                response: cached,
+                size: entry_size,
+                last_used,
+            },
+        );
+    }
+}
+
+impl Entries {
+    fn touch(&mut self, path: &Path) {
+        self.clock += 1;
+        let now = self.clock;
+        if let Some(entry) = self.by_path.get_mut(path) {
+            self.by_use.remove(&entry.last_used);
+            entry.last_used = now;
+            self.by_use.insert(now, path.to_path_buf());
+        }
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.by_path.remove(path) {
+            self.by_use.remove(&entry.last_used);
+            self.size -= entry.size;
+        }
+    }
+}
+
+// What an entry counts for in the capacity. The body is most of it, the rest keeps a cache full of
+// empty files from growing forever.
+fn size(path: &Path, headers: &Headers, body: &[u8]) -> usize {
+    let headers: usize = headers.iter().map(|(name, value)| name.len() + value.len()).sum();
+    body.len() + headers + path.as_os_str().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, time::Duration};
+
+    use super::*;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("file-cache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn read(cache: &FileCache, path: &Path) -> Vec<u8> {
+        let build = |contents| Response::new(200).with_header("Content-Type", "text/plain").with_body(contents);
+        let response = cache.response(path, &fs::metadata(path).unwrap(), build).unwrap();
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plain"));
+        response.body
+    }
+
+    #[test]
+    fn reads_the_file_again_once_it_changed() {
+        let path = directory("changed").join("page.txt");
+        fs::write(&path, "first").unwrap();
+        let cache = FileCache::new(1024 * 1024);
+
+        assert_eq!(read(&cache, &path), b"first");
+        assert_eq!(read(&cache, &path), b"first");
+        assert_eq!((cache.stats().hits, cache.stats().misses), (1, 1));
+
+        // same size, only the modification time tells the new contents apart
+        fs::write(&path, "again").unwrap();
+        let later = fs::metadata(&path).unwrap().modified().unwrap() + Duration::from_secs(1);
+        File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert_eq!(read(&cache, &path), b"again");
+        assert_eq!(read(&cache, &path), b"again");
+        assert_eq!((cache.stats().hits, cache.stats().misses), (2, 2));
+        assert_eq!(cache.stats().entries, 1);
+    }
+
+    #[test]
+    fn drops_the_least_recently_used_files() {
+        let directory = directory("lru");
+        let [a, b, c] = ["a", "b", "c"].map(|name| {
+            let path = directory.join(name);
+            fs::write(&path, [b'x'; 100]).unwrap();
+            path
+        });
+        // room for two of the files and their headers
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "text/plain");
+        let one = size(&a, &headers, &[0; 100]);
+        let cache = FileCache::new(2 * one + 1).max_file_size(100);
+
+        read(&cache, &a);
+        read(&cache, &b);
+        read(&cache, &a);
+        read(&cache, &c);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 3, entries: 2, bytes: 2 * one });
+
+        // `b` was the one left out, `a` and `c` are still there
+        read(&cache, &a);
+        read(&cache, &c);
+        assert_eq!(cache.stats().hits, 3);
+        read(&cache, &b);
+        assert_eq!(cache.stats().misses, 4);
+
+        // too big to be cached at all
+        let big = directory.join("big");
+        fs::write(&big, [b'x'; 101]).unwrap();
+        read(&cache, &big);
+        read(&cache, &big);
+        assert_eq!(cache.stats().misses, 6);
+    }
+}
@@ -0,0 +1,108 @@
+// ===== HTTP headers
+// Header names are case-insensitive ("Content-Length" and "content-length" are the same header)
+// so we can't simply put them in a HashMap<String, String>.
+// A header can also legally appear more than once, so we keep every (name, value) pair in the order
+// we received it and compare names with `eq_ignore_ascii_case` on lookup.
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the first value of the header `name`, ignoring case.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every value of the header `name`, in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Adds a value without touching existing values of the same header.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Replaces every value of the header `name` with `value`.
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    /// Returns true if the comma separated header `name` contains `token` (e.g. `Connection: keep-alive, Upgrade`).
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_ignores_case() {
+        let mut headers = Headers::new();
+        headers.append("Content-Type", "text/html");
+
+        assert_eq!(headers.get("content-type"), Some("text/html"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("text/html"));
+        assert_eq!(headers.get("content-length"), None);
+    }
+
+    #[test]
+    fn set_replaces_all_values() {
+        let mut headers = Headers::new();
+        headers.append("Accept", "text/html");
+        headers.append("accept", "text/plain");
+        assert_eq!(headers.get_all("Accept").count(), 2);
+
+        headers.set("ACCEPT", "*/*");
+        assert_eq!(headers.get_all("accept").collect::<Vec<_>>(), vec!["*/*"]);
+    }
+
+    #[test]
+    fn finds_tokens_in_lists() {
+        let mut headers = Headers::new();
+        headers.append("Connection", "keep-alive, Upgrade");
+
+        assert!(headers.has_token("connection", "upgrade"));
+        assert!(!headers.has_token("connection", "close"));
+    }
+}
@@ -0,0 +1,156 @@
+// ===== HTTP dates
+// Headers like `Last-Modified` and `If-Modified-Since` carry dates in the "IMF-fixdate" format:
+// Sun, 06 Nov 1994 08:49:37 GMT
+// https://www.rfc-editor.org/rfc/rfc9110#name-date-time-formats
+//
+// The standard library only gives us seconds since the UNIX epoch, so we convert to and from
+// calendar dates ourselves using Howard Hinnant's `civil_from_days` / `days_from_civil` algorithms:
+// https://howardhinnant.github.io/date_algorithms.html
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// A date broken down into its calendar fields, always in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    /// 1 to 12
+    pub month: u32,
+    /// 1 to 31
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// 0 is Thursday, the day of the week of 1970-01-01
+    weekday: usize,
+}
+
+impl DateTime {
+    pub fn from_system_time(time: SystemTime) -> Self {
+        // dates before 1970 don't show up in HTTP headers, we clamp them to the epoch
+        let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let days = seconds.div_euclid(86_400);
+        let seconds_of_day = seconds.rem_euclid(86_400) as u32;
+        let (year, month, day) = civil_from_days(days);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: seconds_of_day / 3600,
+            minute: seconds_of_day % 3600 / 60,
+            second: seconds_of_day % 60,
+            weekday: days.rem_euclid(7) as usize,
+        }
+    }
+
+    pub fn month_name(&self) -> &'static str {
+        MONTHS[self.month as usize - 1]
+    }
+
+    pub fn weekday_name(&self) -> &'static str {
+        DAYS[self.weekday]
+    }
+}
+
+/// Formats `time` as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format(time: SystemTime) -> String {
+    let date = DateTime::from_system_time(time);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        date.weekday_name(),
+        date.day,
+        date.month_name(),
+        date.year,
+        date.hour,
+        date.minute,
+        date.second
+    )
+}
+
+/// Parses an IMF-fixdate. Returns `None` for anything else, which callers should treat
+/// as if the header wasn't sent at all.
+pub fn parse(value: &str) -> Option<SystemTime> {
+    // Sun, 06 Nov 1994 08:49:37 GMT
+    let (_, rest) = value.trim().split_once(", ")?;
+    let mut parts = rest.split(' ');
+    let (day, month, year, time, zone) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || zone != "GMT" || day.len() != 2 || year.len() != 4 {
+        return None;
+    }
+
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u32 + 1;
+    let year: i64 = year.parse().ok()?;
+
+    let mut time = time.split(':');
+    let (hour, minute, second) = (time.next()?, time.next()?, time.next()?);
+    if time.next().is_some() {
+        return None;
+    }
+    let (hour, minute, second): (u64, u64, u64) = (hour.parse().ok()?, minute.parse().ok()?, second.parse().ok()?);
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let seconds = u64::try_from(days).ok()? * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = i64::from(if month > 2 { month - 3 } else { month + 9 });
+    let day_of_year = (153 * shifted_month + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_imf_fixdate() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(format(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        // 2024 is a leap year
+        assert_eq!(format(UNIX_EPOCH + Duration::from_secs(1_709_164_800)), "Thu, 29 Feb 2024 00:00:00 GMT");
+    }
+
+    #[test]
+    fn parses_what_it_formats() {
+        for seconds in [0, 784_111_777, 951_782_400, 1_709_164_800, 4_102_444_799] {
+            let time = UNIX_EPOCH + Duration::from_secs(seconds);
+            assert_eq!(parse(&format(time)), Some(time));
+        }
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), None);
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 PST"), None);
+        assert_eq!(parse("yesterday"), None);
+    }
+}
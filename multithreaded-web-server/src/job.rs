@@ -0,0 +1,174 @@
+// ===== Getting results back from the pool
+// `ThreadPool::execute` is fire-and-forget. `ThreadPool::spawn` wraps the job so that its return value
+// (or its panic) is sent back through a channel, and gives the caller the receiving end as a `JobHandle`.
+// This is the same idea as `thread::spawn` returning a `JoinHandle<T>`, except the job runs on a pool thread.
+
+use std::{
+    any::Any,
+    error::Error,
+    fmt,
+    sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError},
+    thread,
+    time::Duration,
+};
+
+/// Why a job didn't produce a value.
+pub enum JobError {
+    /// The job panicked, this is the value it panicked with, like `JoinHandle::join` returns.
+    /// Pass it to `std::panic::resume_unwind` to propagate the panic to the caller's thread.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The job was dropped without running, because the pool shut down before a worker got to it.
+    Cancelled,
+}
+
+impl JobError {
+    /// The message of a job that panicked with `panic!("...")`.
+    pub fn panic_message(&self) -> Option<&str> {
+        match self {
+            // `panic!("literal")` panics with a `&'static str`, `panic!("{x}")` with a `String`
+            JobError::Panicked(payload) => payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str)),
+            JobError::Cancelled => None,
+        }
+    }
+}
+
+impl fmt::Debug for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Panicked(_) => f.debug_tuple("Panicked").field(&self.panic_message()).finish(),
+            JobError::Cancelled => f.write_str("Cancelled"),
+        }
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self, self.panic_message()) {
+            (JobError::Panicked(_), Some(message)) => write!(f, "job panicked: {message}"),
+            (JobError::Panicked(_), None) => write!(f, "job panicked"),
+            (JobError::Cancelled, _) => write!(f, "job was cancelled before it ran"),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+/// A handle to the result of a job submitted with `ThreadPool::spawn`.
+///
+/// Dropping the handle doesn't cancel the job, its result is just thrown away.
+pub struct JobHandle<T> {
+    // `None` once the result has been handed out by `try_join` or `join_timeout`
+    receiver: Option<Receiver<thread::Result<T>>>,
+}
+
+impl<T> JobHandle<T> {
+    pub(crate) fn new(receiver: Receiver<thread::Result<T>>) -> Self {
+        Self {
+            receiver: Some(receiver),
+        }
+    }
+
+    /// Blocks until the job has finished and returns its result.
+    pub fn join(mut self) -> Result<T, JobError> {
+        // the sender is dropped without sending when the job is dropped without running
+        match self.take_receiver().recv() {
+            Ok(result) => result.map_err(JobError::Panicked),
+            Err(_) => Err(JobError::Cancelled),
+        }
+    }
+
+    /// Returns the result if the job has finished, `None` if it's still queued or running.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called again after the result was returned.
+    pub fn try_join(&mut self) -> Option<Result<T, JobError>> {
+        let result = match self.receiver().try_recv() {
+            Ok(result) => result.map_err(JobError::Panicked),
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(JobError::Cancelled),
+        };
+        self.receiver = None;
+        Some(result)
+    }
+
+    /// Waits up to `timeout` for the job to finish, returns `None` if it's still queued or running.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called again after the result was returned.
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<T, JobError>> {
+        let result = match self.receiver().recv_timeout(timeout) {
+            Ok(result) => result.map_err(JobError::Panicked),
+            Err(RecvTimeoutError::Timeout) => return None,
+            Err(RecvTimeoutError::Disconnected) => Err(JobError::Cancelled),
+        };
+        self.receiver = None;
+        Some(result)
+    }
+
+    fn receiver(&self) -> &Receiver<thread::Result<T>> {
+        self.receiver.as_ref().expect("JobHandle polled after its result was taken")
+    }
+
+    fn take_receiver(&mut self) -> Receiver<thread::Result<T>> {
+        self.receiver.take().expect("JobHandle polled after its result was taken")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::ThreadPool;
+
+    #[test]
+    fn returns_the_value_of_the_job() {
+        let pool = ThreadPool::build(2).unwrap();
+
+        let handles: Vec<_> = (1..=4u64)
+            .map(|n| pool.spawn(move || (1..=n).product::<u64>()).unwrap())
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+
+        assert_eq!(results, vec![1, 2, 6, 24]);
+    }
+
+    #[test]
+    fn passes_on_panics() {
+        let pool = ThreadPool::build(1).unwrap();
+
+        let error = pool.spawn(|| -> u8 { panic!("bad input {}", 42) }).unwrap().join().unwrap_err();
+        assert_eq!(error.panic_message(), Some("bad input 42"));
+        assert_eq!(error.to_string(), "job panicked: bad input 42");
+
+        // the worker survived the panic
+        assert_eq!(pool.spawn(|| 7).unwrap().join().unwrap(), 7);
+    }
+
+    #[test]
+    fn polls_without_blocking() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (release, wait) = mpsc::channel::<()>();
+
+        let mut handle = pool.spawn(move || wait.recv().unwrap()).unwrap();
+        assert!(handle.try_join().is_none());
+        assert!(handle.join_timeout(Duration::from_millis(20)).is_none());
+
+        release.send(()).unwrap();
+        assert!(matches!(handle.join_timeout(Duration::from_secs(5)), Some(Ok(()))));
+    }
+
+    #[test]
+    fn dropped_jobs_are_cancelled() {
+        let (sender, receiver) = mpsc::channel::<thread::Result<()>>();
+        let handle = JobHandle::new(receiver);
+        drop(sender);
+
+        assert!(matches!(handle.join(), Err(JobError::Cancelled)));
+    }
+}
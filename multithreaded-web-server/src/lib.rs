@@ -1,12 +1,269 @@
-use std::{sync::{mpsc::{self, Receiver}, Arc, Mutex}, thread};
+use std::{
+    error::Error,
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub mod access_log;
+pub mod compression;
+pub mod conditional;
+pub mod config;
+pub mod connection;
+#[cfg(target_os = "linux")]
+mod epoll;
+#[cfg(target_os = "linux")]
+mod event_loop;
+pub mod file_cache;
+pub mod headers;
+pub mod http_date;
+pub mod job;
+pub mod metrics;
+pub mod middleware;
+pub mod proxy;
+pub mod range;
+pub mod rate_limit;
+pub mod request;
+pub mod response;
+pub mod router;
+mod scheduler;
+pub mod server;
+pub mod shutdown;
+pub mod static_files;
+pub mod tls;
+pub mod websocket;
+
+pub use access_log::{AccessLog, LogFormat};
+pub use compression::Compression;
+pub use config::{Config, ConfigError};
+pub use file_cache::{CacheStats, FileCache};
+pub use headers::Headers;
+pub use job::{JobError, JobHandle};
+pub use metrics::Metrics;
+pub use middleware::{Chain, Middleware};
+pub use proxy::{Balance, Proxy};
+pub use rate_limit::{Rate, RateLimiter};
+pub use request::{Limits, Method, ParseError, Request, Version};
+pub use response::Response;
+pub use router::{Handler, Params, Router};
+pub use server::{IoMode, Listener, Server, ServerConfig};
+pub use shutdown::Shutdown;
+pub use static_files::StaticFiles;
+pub use tls::{HttpsRedirect, TlsConfig};
+pub use websocket::{Message, WebSocket};
+
+use scheduler::{Next, Scheduler};
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    workers: Mutex<Vec<Worker>>,
+    // false once the pool is shutting down, so `execute` can tell the caller instead of panicking.
+    // Submitting only needs a read lock, many threads can do it at the same time.
+    open: RwLock<bool>,
+    queue_policy: QueuePolicy,
+    // every worker sends its id here when its thread is about to exit
+    exited: Mutex<Receiver<usize>>,
+    // what a new worker needs when the pool grows
+    exit_sender: mpsc::Sender<usize>,
+    state: Arc<PoolState>,
+    max_size: usize,
+    next_id: AtomicUsize,
+    // panics of the workers that retired, `panic_count` keeps counting them
+    retired_panics: AtomicU64,
+}
+
+// What the pool and its workers share: the queues, and the counters to decide when to grow and when to shrink.
+struct PoolState {
+    scheduler: Scheduler,
+    // workers that are alive, and the most there ever were
+    size: AtomicUsize,
+    peak: AtomicUsize,
+    min_size: usize,
+    idle_timeout: Duration,
+    // how many jobs each worker that is still alive ran, by worker id
+    jobs: Mutex<Vec<(usize, Arc<AtomicU64>)>>,
+}
+
+/// A handle to watch a `ThreadPool` from anywhere, see `ThreadPool::monitor`.
+#[derive(Clone)]
+pub struct PoolMonitor {
+    state: Arc<PoolState>,
+}
+
+impl PoolMonitor {
+    /// How many workers are alive right now.
+    pub fn size(&self) -> usize {
+        self.state.size.load(Ordering::SeqCst)
+    }
+
+    /// How many workers are running a job.
+    pub fn busy(&self) -> usize {
+        self.size().saturating_sub(self.state.scheduler.idle())
+    }
+
+    /// How many jobs were submitted that no worker took yet.
+    pub fn queued(&self) -> usize {
+        self.state.scheduler.queued()
+    }
+
+    /// How many jobs each worker that is still alive ran, by worker id.
+    pub fn jobs_per_worker(&self) -> Vec<(usize, u64)> {
+        self.state
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, jobs)| (*id, jobs.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+impl fmt::Debug for PoolMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolMonitor")
+            .field("size", &self.size())
+            .field("busy", &self.busy())
+            .field("queued", &self.queued())
+            .finish()
+    }
+}
+
+impl PoolState {
+    // Claims a slot for a new worker, false if the pool is at `max_size` already.
+    fn try_grow(&self, max_size: usize) -> bool {
+        match self.size.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| (size < max_size).then_some(size + 1)) {
+            Ok(size) => {
+                self.peak.fetch_max(size + 1, Ordering::SeqCst);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Gives up a worker's slot, false if the pool would go below `min_size`.
+    fn try_shrink(&self) -> bool {
+        self.size
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| (size > self.min_size).then(|| size - 1))
+            .is_ok()
+    }
+}
+
+/// What `execute` does when a bounded queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueuePolicy {
+    /// Wait until a worker takes a job off the queue.
+    #[default]
+    Block,
+    /// Return `ExecuteError::QueueFull` right away, the caller decides what to do with the work.
+    FailFast,
+    /// Run the job on the caller's thread. This naturally slows down whoever is submitting too fast.
+    CallerRuns,
 }
 
+/// Configures a `ThreadPool` before creating it, see `ThreadPool::builder`.
+#[derive(Debug, Clone)]
+pub struct ThreadPoolBuilder {
+    size: usize,
+    max_size: Option<usize>,
+    idle_timeout: Duration,
+    queue_capacity: Option<usize>,
+    queue_policy: QueuePolicy,
+}
+
+impl ThreadPoolBuilder {
+    /// Lets the pool grow up to `max_size` workers when jobs are queued faster than the workers take them.
+    /// The pool starts with the size given to `ThreadPool::builder` and never shrinks below it.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// How long a worker above the minimum size may wait for a job before it retires, 60 seconds by default.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Limits how many jobs can wait for a worker. Without a limit the queue grows as long as there is memory.
+    ///
+    /// Jobs that an idle worker is about to take don't count, so with a capacity of 0 a job is only accepted
+    /// if a worker is free to run it.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// What to do when the queue is full, `QueuePolicy::Block` by default.
+    pub fn queue_policy(mut self, policy: QueuePolicy) -> Self {
+        self.queue_policy = policy;
+        self
+    }
+
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::from_builder(self)
+    }
+}
+
+/// Why a `ThreadPool` couldn't be created.
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// A pool needs at least one thread to run jobs.
+    ZeroSize,
+    /// The maximum size is smaller than the size the pool starts with.
+    MaxBelowMin,
+    /// The OS refused to create a thread, usually because of a resource limit.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => write!(f, "a thread pool needs at least one thread"),
+            PoolCreationError::MaxBelowMin => write!(f, "a thread pool's maximum size can't be below its initial size"),
+            PoolCreationError::Spawn(err) => write!(f, "failed to spawn a worker thread: {err}"),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::ZeroSize | PoolCreationError::MaxBelowMin => None,
+            PoolCreationError::Spawn(err) => Some(err),
+        }
+    }
+}
+
+/// Why a job couldn't be submitted to a `ThreadPool`. The job is dropped without running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    /// `shutdown_timeout` was called, the pool doesn't take new jobs anymore.
+    ShuttingDown,
+    /// Every worker thread has exited, nobody would ever run the job.
+    NoWorkers,
+    /// The queue is at capacity and the pool uses `QueuePolicy::FailFast`.
+    QueueFull,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::ShuttingDown => write!(f, "the thread pool is shutting down"),
+            ExecuteError::NoWorkers => write!(f, "the thread pool has no workers left"),
+            ExecuteError::QueueFull => write!(f, "the thread pool's queue is full"),
+        }
+    }
+}
+
+impl Error for ExecuteError {}
+
 impl ThreadPool {
     /// Create a new ThreadPool.
     ///
@@ -14,35 +271,96 @@ impl ThreadPool {
     ///
     /// # Panics
     ///
-    /// The `new` function will panic if the size is zero.
+    /// The `new` function will panic if the size is zero or if the threads can't be created.
+    /// Use `build` to handle these errors instead.
     pub fn new(size: usize) -> Self {
-        assert!(size > 0);
+        match Self::build(size) {
+            Ok(pool) => pool,
+            Err(err) => panic!("failed to create the thread pool: {err}"),
+        }
+    }
+
+    /// Create a new ThreadPool, like `new` but returns an error instead of panicking.
+    pub fn build(size: usize) -> Result<Self, PoolCreationError> {
+        Self::builder(size).build()
+    }
+
+    /// Starts configuring a pool of `size` threads, for when the defaults of `build` don't fit:
+    ///
+    /// ```
+    /// use multithreaded_web_server::{QueuePolicy, ThreadPool};
+    ///
+    /// let pool = ThreadPool::builder(4)
+    ///     .max_size(16)
+    ///     .queue_capacity(64)
+    ///     .queue_policy(QueuePolicy::FailFast)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn builder(size: usize) -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            size,
+            max_size: None,
+            idle_timeout: Duration::from_secs(60),
+            queue_capacity: None,
+            queue_policy: QueuePolicy::default(),
+        }
+    }
+
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<Self, PoolCreationError> {
+        let size = builder.size;
+        if size == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+        let max_size = builder.max_size.unwrap_or(size);
+        if max_size < size {
+            return Err(PoolCreationError::MaxBelowMin);
+        }
         // with_capacity is similar to `Vec::new` but it preallocates space in the vector
         // doing it upfront is slightly more efficient than using `Vec::new` which resizes itself as elements are added
         let mut workers = Vec::with_capacity(size);
 
-        let (sender, receiver) = mpsc::channel();
-
-        // We have to use Arc (thread-safe smart pointer) to share ownership across multiple threads
-        // and allow threads to mutate the value we need to use Arc<Mutex<T>>.
-        // Arc will allow multiple Wokers to use the receiver
-        // while Mutex will ensure only one worker gets a job from the receiver at a time.
-        let receiver = Arc::new(Mutex::new(receiver));
+        // We have to use Arc (thread-safe smart pointer) to share ownership across multiple threads.
+        // The scheduler in there does its own locking, see scheduler.rs.
+        let (exit_sender, exited) = mpsc::channel();
+        let state = Arc::new(PoolState {
+            scheduler: Scheduler::new(builder.queue_capacity),
+            size: AtomicUsize::new(size),
+            peak: AtomicUsize::new(size),
+            min_size: size,
+            idle_timeout: builder.idle_timeout,
+            jobs: Mutex::new(Vec::new()),
+        });
 
         // create some threads and store them in the vector
         // we want to create threads and have them `wait` for code to that we will send later
         for id in 0..size {
             // we clone Arc to bump the reference count.
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            // If a thread can't be spawned we return early, the workers created so far exit on their own.
+            match Worker::new(id, exit_sender.clone(), Arc::clone(&state)) {
+                Ok(worker) => workers.push(worker),
+                Err(err) => {
+                    state.scheduler.close();
+                    return Err(PoolCreationError::Spawn(err));
+                }
+            }
         }
 
-        Self {
-            workers,
-            sender: Some(sender),
-        }
+        Ok(Self {
+            workers: Mutex::new(workers),
+            open: RwLock::new(true),
+            queue_policy: builder.queue_policy,
+            exited: Mutex::new(exited),
+            exit_sender,
+            state,
+            max_size,
+            next_id: AtomicUsize::new(size),
+            retired_panics: AtomicU64::new(0),
+        })
     }
 
-    pub fn execute<F>(&self, f: F) 
+    /// Sends `f` to be run by one of the workers.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
     where 
         // : we need Send to transfer the closure from one thread to another 
         // and 'static because we don’t know how long the thread will take to execute.
@@ -50,21 +368,193 @@ impl ThreadPool {
         // a closure that takes no parameters and returns the unit type ()
         F: FnOnce() + Send + 'static
     {
-        let job = Box::new(f);
+        let job: Job = Box::new(f);
+
+        {
+            let open = self.open.read().unwrap();
+            if !*open {
+                return Err(ExecuteError::ShuttingDown);
+            }
+            // workers only exit on their own when there are more than the minimum, unless a thread died
+            if self.size() == 0 {
+                return Err(ExecuteError::NoWorkers);
+            }
+
+            // more jobs waiting than workers to take them: add a worker, if we are allowed to.
+            // Growing under the read lock means `shutdown_timeout` can't miss the new worker.
+            let scheduler = &self.state.scheduler;
+            if scheduler.queued() >= scheduler.idle() {
+                self.grow();
+            }
+
+            let reserved = match self.queue_policy {
+                QueuePolicy::Block => {
+                    scheduler.reserve();
+                    true
+                }
+                QueuePolicy::FailFast | QueuePolicy::CallerRuns => scheduler.try_reserve(),
+            };
+            if reserved {
+                scheduler.submit(job);
+                return Ok(());
+            }
+            // the lock is released here, running the job while holding it would block `shutdown_timeout`
+        }
+
+        match self.queue_policy {
+            QueuePolicy::CallerRuns => {
+                job();
+                Ok(())
+            }
+            _ => Err(ExecuteError::QueueFull),
+        }
+    }
+
+    /// Sends `f` to be run by one of the workers and returns a handle to get its result back.
+    ///
+    /// A panic in `f` doesn't take the worker down, it's caught and returned by the handle as `JobError::Panicked`.
+    pub fn spawn<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // the job sends exactly one message, a buffer of one means it never blocks on a slow caller
+        let (sender, receiver) = mpsc::sync_channel(1);
+
+        self.execute(move || {
+            // AssertUnwindSafe: nothing the job touched is observed after a panic, except through the payload
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            // the caller may have dropped the handle, nobody wants the result then
+            let _ = sender.send(result);
+        })?;
+
+        Ok(JobHandle::new(receiver))
+    }
+
+    /// How many workers are alive right now.
+    pub fn size(&self) -> usize {
+        self.state.size.load(Ordering::SeqCst)
+    }
+
+    /// The most workers the pool ever had at the same time.
+    pub fn peak_size(&self) -> usize {
+        self.state.peak.load(Ordering::SeqCst)
+    }
+
+    /// Gives a handle to read the pool's gauges and counters while the pool itself is owned by someone else,
+    /// a `Server` for example.
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor {
+            state: Arc::clone(&self.state),
+        }
+    }
 
-        self.sender.as_ref().unwrap().send(job).unwrap();
+    /// How many jobs panicked since the pool was created. The workers survive these panics.
+    pub fn panic_count(&self) -> u64 {
+        let retired = self.retired_panics.load(Ordering::Relaxed);
+        retired + self.panics_per_worker().iter().map(|(_, panics)| panics).sum::<u64>()
+    }
+
+    /// How many jobs panicked on each worker that is still alive, by worker id.
+    pub fn panics_per_worker(&self) -> Vec<(usize, u64)> {
+        self.workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|worker| (worker.id, worker.panics.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    // Adds a worker unless the pool is at its maximum size already.
+    fn grow(&self) {
+        if !self.state.try_grow(self.max_size) {
+            return;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut workers = self.workers.lock().unwrap();
+        self.remove_retired(&mut workers);
+        match Worker::new(id, self.exit_sender.clone(), Arc::clone(&self.state)) {
+            Ok(worker) => workers.push(worker),
+            // the job is queued anyway, the workers we have will get to it
+            Err(err) => {
+                self.state.size.fetch_sub(1, Ordering::SeqCst);
+                eprintln!("Failed to add a worker: {err}");
+            }
+        }
+    }
+
+    // Joins the workers that retired since the last time, so `workers` doesn't grow forever.
+    fn remove_retired(&self, workers: &mut Vec<Worker>) {
+        // `shutdown_timeout` holds this lock while it waits for the workers, it takes care of them then
+        let exited = match self.exited.try_lock() {
+            Ok(exited) => exited,
+            Err(_) => return,
+        };
+        for id in exited.try_iter() {
+            if let Some(index) = workers.iter().position(|worker| worker.id == id) {
+                let worker = workers.remove(index);
+                self.state.jobs.lock().unwrap().retain(|(worker, _)| *worker != id);
+                self.retired_panics.fetch_add(worker.panics.load(Ordering::Relaxed), Ordering::Relaxed);
+                if let Some(thread) = worker.thread {
+                    let _ = thread.join();
+                }
+            }
+        }
+    }
+
+    /// Stops accepting jobs and waits up to `timeout` for the workers to finish the jobs that are
+    /// running or still queued.
+    ///
+    /// Returns true if every worker finished in time. Workers that are still busy after the deadline
+    /// are detached: they keep running until the process exits, but nobody waits for them anymore.
+    pub fn shutdown_timeout(&self, timeout: Duration) -> bool {
+        // workers exit once the queues are closed and empty, like in `drop`
+        *self.open.write().unwrap() = false;
+        self.state.scheduler.close();
+
+        // `JoinHandle::join` can't time out, so we wait for the exit notices instead and only join
+        // the threads we know are done. A timeout too large for an `Instant` means waiting forever.
+        let deadline = Instant::now().checked_add(timeout);
+        let exited = self.exited.lock().unwrap();
+        let mut workers = self.workers.lock().unwrap();
+        let mut running = workers.iter().filter(|worker| worker.thread.is_some()).count();
+        while running > 0 {
+            let id = match deadline {
+                Some(deadline) => exited.recv_timeout(deadline.saturating_duration_since(Instant::now())).ok(),
+                None => exited.recv().ok(),
+            };
+            let id = match id {
+                Some(id) => id,
+                None => break,
+            };
+            let worker = workers.iter_mut().find(|worker| worker.id == id);
+            if let Some(thread) = worker.and_then(|worker| worker.thread.take()) {
+                let _ = thread.join();
+                running -= 1;
+            }
+        }
+
+        for worker in workers.iter_mut() {
+            if worker.thread.take().is_some() {
+                eprintln!("Worker {} is still busy, not waiting for it", worker.id);
+            }
+        }
+        running == 0
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        *self.open.get_mut().unwrap() = false;
+        self.state.scheduler.close();
         // we use &mut because self is a mutable reference and we also need to be able to mutate worker
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
-
+        for worker in self.workers.get_mut().unwrap().iter_mut() {
+            // jobs can't panic the thread anymore, but a panic while joining a thread would abort the process
             if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+                if thread.join().is_err() {
+                    eprintln!("Worker {} had panicked", worker.id);
+                }
             }
         }
     }
@@ -74,39 +564,301 @@ struct Worker {
     id: usize,
     // we are using Option so we can move the thread out of the worker instance when calling
     // `thread.join()` as `join` consumes the thread.
-    thread: Option<thread::JoinHandle<()>>
+    thread: Option<thread::JoinHandle<()>>,
+    // how many jobs panicked on this worker
+    panics: Arc<AtomicU64>,
 }
 
 // we want Worker to fetch the code to run from a queue under ThreadPool
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> Self {
-        // thread::spawn will panic if the system fails to create a thread (because of resource limit for example)
-        // this will cause our program to crash 
-        // in production, use `std::thread::Builder` which returns a `Result` instead of panicking
-        // We have to loop instead of while because of:
-        // the Mutex struct has no public unlock method because the ownership of the lock is based on the lifetime of the MutexGuard<T> within the LockResult<MutexGuard<T>> that the lock method returns. At compile time, the borrow checker can then enforce the rule that a resource guarded by a Mutex cannot be accessed unless we hold the lock. However, this implementation can also result in the lock being held longer than intended if we aren’t mindful of the lifetime of the MutexGuard<T>.
-        // more here: https://doc.rust-lang.org/book/ch20-02-multithreaded.html#implementing-the-execute-method
-        // in short: `let` drops any temporary values by the end of expression. 
-        // `if let`, `while let` and `match` does not drop temporary values until the end of the associated block.
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-
-            match message {
-                Ok(job) => {
-                    println!("Worker {id} got a job; executing");
-
-                    job();
-                }
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down.");
-                    break;
+    fn new(id: usize, exited: mpsc::Sender<usize>, state: Arc<PoolState>) -> io::Result<Self> {
+        let panics = Arc::new(AtomicU64::new(0));
+        let worker_panics = Arc::clone(&panics);
+        let jobs = Arc::new(AtomicU64::new(0));
+        state.jobs.lock().unwrap().push((id, Arc::clone(&jobs)));
+        // counted here rather than on the new thread, so the worker is idle as soon as `new` returns
+        state.scheduler.add_worker();
+        let thread_state = Arc::clone(&state);
+
+        // thread::spawn would panic if the system fails to create a thread (because of resource limit for example)
+        // and crash our program, `thread::Builder::spawn` returns a `Result` instead so the caller can handle it.
+        let spawned = thread::Builder::new().name(format!("worker-{id}")).spawn(move || {
+            let state = thread_state;
+            let _notice = ExitNotice { id, sender: exited };
+            let scheduler = &state.scheduler;
+            let mut retire = Retire {
+                state: &state,
+                busy: false,
+            };
+
+            loop {
+                match scheduler.next(state.idle_timeout) {
+                    Next::Job(job) => {
+                        retire.busy = true;
+                        jobs.fetch_add(1, Ordering::Relaxed);
+                        // a panic would otherwise unwind and end this thread, and the pool would quietly
+                        // lose a worker for every bad job. The panic hook has already printed the message.
+                        // AssertUnwindSafe: the job is gone after this call, nothing it touched is reused here
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            panics.fetch_add(1, Ordering::Relaxed);
+                            eprintln!("Worker {id} recovered from a panicking job");
+                        }
+                        scheduler.job_done();
+                        retire.busy = false;
+                    }
+                    // workers above the minimum size retire when there isn't enough work for them
+                    Next::TimedOut => {
+                        if state.try_shrink() {
+                            break;
+                        }
+                    }
+                    Next::Closed => {
+                        state.size.fetch_sub(1, Ordering::SeqCst);
+                        break;
+                    }
                 }
             }
         });
 
-        Self {
-            id,
-            thread: Some(thread)
+        match spawned {
+            Ok(thread) => Ok(Self {
+                id,
+                thread: Some(thread),
+                panics: worker_panics,
+            }),
+            Err(err) => {
+                state.scheduler.remove_worker();
+                state.jobs.lock().unwrap().retain(|(worker, _)| *worker != id);
+                Err(err)
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+// Takes a worker out of the scheduler when its thread exits. A panic that isn't a job's (dropping the
+// payload of a job's panic can panic too) still ends the thread: the worker then gives up its slot as
+// well, so that the pool knows it has one less, and finishes the job it was running as far as the
+// scheduler is concerned.
+struct Retire<'a> {
+    state: &'a PoolState,
+    busy: bool,
+}
+
+impl Drop for Retire<'_> {
+    fn drop(&mut self) {
+        let scheduler = &self.state.scheduler;
+        if thread::panicking() {
+            if self.busy {
+                scheduler.job_done();
+            }
+            self.state.size.fetch_sub(1, Ordering::SeqCst);
+        }
+        scheduler.remove_worker();
+    }
+}
+
+// Tells the pool that a worker's thread is exiting. Sending from `drop` means the notice
+// is also sent if the thread unwinds.
+struct ExitNotice {
+    id: usize,
+    sender: mpsc::Sender<usize>,
+}
+
+impl Drop for ExitNotice {
+    fn drop(&mut self) {
+        let _ = self.sender.send(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_rejects_an_empty_pool() {
+        assert!(matches!(ThreadPool::build(0), Err(PoolCreationError::ZeroSize)));
+    }
+
+    #[test]
+    fn runs_submitted_jobs() {
+        let pool = ThreadPool::build(2).unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        for i in 0..4 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap()).unwrap();
+        }
+
+        let mut results: Vec<i32> = receiver.iter().take(4).collect();
+        results.sort();
+        assert_eq!(results, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_jobs_once_shutting_down() {
+        let pool = ThreadPool::build(1).unwrap();
+        assert!(pool.shutdown_timeout(Duration::from_secs(1)));
+
+        assert_eq!(pool.execute(|| {}), Err(ExecuteError::ShuttingDown));
+    }
+
+    #[test]
+    fn applies_the_policy_when_the_queue_is_full() {
+        let (release, wait) = mpsc::channel::<()>();
+        let wait = Arc::new(Mutex::new(wait));
+        let block_worker = |pool: &ThreadPool| {
+            let wait = Arc::clone(&wait);
+            pool.execute(move || wait.lock().unwrap().recv().unwrap()).unwrap();
+        };
+
+        let pool = ThreadPool::builder(1)
+            .queue_capacity(1)
+            .queue_policy(QueuePolicy::FailFast)
+            .build()
+            .unwrap();
+        block_worker(&pool);
+        // the worker may not have taken the first job off the queue yet, so one of these two fills it up
+        let _ = pool.execute(|| {});
+        let _ = pool.execute(|| {});
+        assert_eq!(pool.execute(|| {}), Err(ExecuteError::QueueFull));
+        release.send(()).unwrap();
+
+        let pool = ThreadPool::builder(1)
+            .queue_capacity(0)
+            .queue_policy(QueuePolicy::CallerRuns)
+            .build()
+            .unwrap();
+        block_worker(&pool);
+        let caller = thread::current().id();
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(thread::current().id()).unwrap()).unwrap();
+        assert_eq!(receiver.recv().unwrap(), caller);
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn grows_under_load_and_shrinks_when_idle() {
+        let pool = ThreadPool::builder(1)
+            .max_size(3)
+            .idle_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        assert!(matches!(ThreadPool::builder(2).max_size(1).build(), Err(PoolCreationError::MaxBelowMin)));
+
+        let (release, wait) = mpsc::channel::<()>();
+        let wait = Arc::new(Mutex::new(wait));
+        let (started, running) = mpsc::channel();
+        for _ in 0..5 {
+            let wait = Arc::clone(&wait);
+            let started = started.clone();
+            pool.execute(move || {
+                started.send(()).unwrap();
+                let _ = wait.lock().unwrap().recv();
+            })
+            .unwrap();
+        }
+        // three jobs run at the same time, which needs three workers
+        for _ in 0..3 {
+            running.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(pool.size(), 3);
+        assert_eq!(pool.peak_size(), 3);
+
+        drop(release);
+        // the workers above the minimum retire after an idle timeout
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.size() > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.peak_size(), 3);
+
+        // and the pool grows again when it's needed
+        let handles: Vec<_> = (0..3).map(|n| pool.spawn(move || n * 2).unwrap()).collect();
+        let results: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(results, vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn runs_jobs_submitted_by_jobs() {
+        // every job submits two smaller ones, 2^10 - 1 jobs in total, most of them from inside the pool
+        fn split(pool: Arc<ThreadPool>, depth: u32, sender: mpsc::Sender<u32>) {
+            sender.send(depth).unwrap();
+            if depth > 1 {
+                for _ in 0..2 {
+                    let (next, sender) = (Arc::clone(&pool), sender.clone());
+                    pool.execute(move || split(next, depth - 1, sender)).unwrap();
+                }
+            }
+        }
+
+        let pool = Arc::new(ThreadPool::build(4).unwrap());
+        let (sender, receiver) = mpsc::channel();
+        let root = Arc::clone(&pool);
+        pool.execute(move || split(root, 10, sender)).unwrap();
+
+        let jobs = receiver.iter().take(1023).count();
+        assert_eq!(jobs, 1023);
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+        // the pool must not be dropped by one of its own workers, it would wait for itself
+        while Arc::strong_count(&pool) > 1 {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let pool = ThreadPool::build(2).unwrap();
+        for _ in 0..4 {
+            pool.execute(|| panic!("bad job")).unwrap();
+        }
+
+        // both workers are still there: two jobs that wait for each other only finish if they run at the same time
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..2 {
+            let (barrier, sender) = (Arc::clone(&barrier), sender.clone());
+            pool.execute(move || {
+                barrier.wait();
+                sender.send(i).unwrap();
+            })
+            .unwrap();
+        }
+        let mut results: Vec<i32> = (0..2).map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        results.sort();
+        assert_eq!(results, vec![0, 1]);
+
+        assert!(pool.shutdown_timeout(Duration::from_secs(5)));
+        assert_eq!(pool.panic_count(), 4);
+        assert_eq!(pool.panics_per_worker().len(), 2);
+    }
+
+    #[test]
+    fn reports_a_pool_without_workers() {
+        // a panic payload that panics again when it's dropped, outside of the job
+        struct Bomb;
+        impl Drop for Bomb {
+            fn drop(&mut self) {
+                panic!("this payload kills its worker");
+            }
+        }
+
+        let pool = ThreadPool::build(1).unwrap();
+        pool.execute(|| panic::panic_any(Bomb)).unwrap();
+
+        // the worker needs a moment to unwind and give up its slot
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut result = Ok(());
+        while Instant::now() < deadline {
+            result = pool.execute(|| {});
+            if result.is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(result, Err(ExecuteError::NoWorkers));
+
+        // reaps the dead worker so dropping the pool doesn't try to join it
+        assert!(pool.shutdown_timeout(Duration::from_secs(1)));
+    }
+}
@@ -1,62 +1,212 @@
 use std::{
-    fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream}, thread, time::Duration,
+    env, io,
+    net::TcpListener, process, sync::Arc, thread, time::Duration,
+};
+use multithreaded_web_server::{
+    config::{Args, Log, ProxyRoute, RateLimit, USAGE},
+    connection::ConnectionConfig,
+    AccessLog, Compression, Config, FileCache, Handler, Metrics, Proxy, QueuePolicy, Listener, RateLimiter, Request, Router, Server, ServerConfig, Shutdown, StaticFiles, ThreadPool, TlsConfig,
 };
-use multithreaded_web_server::ThreadPool;
 
 fn main() {
+    let config = match Args::parse(env::args().skip(1)) {
+        Ok(args) if args.help => {
+            print!("{USAGE}");
+            return;
+        }
+        Ok(args) => Config::load(args),
+        Err(err) => Err(err),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(2);
+        }
+    };
+
     // in networking, connecting to a port to listen to is known as "binding to a port"
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+    let tls = match (&config.tls.certificate, &config.tls.private_key) {
+        (Some(certificate), Some(private_key)) => match TlsConfig::from_pem_files(certificate, private_key) {
+            Ok(tls) => Some(tls),
+            Err(err) => {
+                eprintln!("Failed to load the TLS certificate: {err}");
+                process::exit(2);
+            }
+        },
+        _ => None,
+    };
+    let plain = config.listen.iter().map(|address| (address, None));
+    let secure = config.tls.listen.iter().map(|address| (address, tls.clone()));
+    let mut listeners = Vec::new();
+    for (address, tls) in plain.chain(secure) {
+        match TcpListener::bind(address) {
+            Ok(socket) => listeners.push(match tls {
+                Some(tls) => Listener::tls(socket, tls),
+                None => Listener::plain(socket),
+            }),
+            Err(err) => {
+                eprintln!("Failed to listen on {address}: {err}");
+                process::exit(2);
+            }
+        }
+    }
+    // the pool starts with `workers.min` workers and adds more, up to `workers.max`, while connections
+    // are waiting for one. At most `queue_capacity` connections wait, the ones after that get a 503
+    // instead of waiting forever.
+    let pool = ThreadPool::builder(config.workers.min)
+        .max_size(config.workers.max)
+        .idle_timeout(config.workers.idle_timeout)
+        .queue_capacity(config.workers.queue_capacity)
+        .queue_policy(QueuePolicy::FailFast)
+        .build();
+    let pool = match pool {
+        Ok(pool) => pool,
+        Err(err) => {
+            eprintln!("Failed to start the workers: {err}");
+            process::exit(2);
+        }
+    };
+    let access_log = match access_log(&config.log) {
+        Ok(access_log) => access_log,
+        Err(err) => {
+            eprintln!("Failed to open the access log: {err}");
+            process::exit(2);
+        }
+    };
+    let metrics = Metrics::new();
+    let server_config = ServerConfig {
+        connection: ConnectionConfig {
+            idle_timeout: config.timeouts.idle,
+            read_timeout: config.timeouts.read,
+            write_timeout: config.timeouts.write,
+            request_timeout: config.timeouts.request,
+            limits: config.limits,
+            access_log,
+            compression: config.compression.enabled.then_some(Compression {
+                min_size: config.compression.min_size,
+                level: config.compression.level,
+            }),
+            ..ConnectionConfig::default()
+        },
+        shutdown_timeout: config.timeouts.shutdown,
+        retry_after: config.timeouts.retry_after,
+        io: config.io,
+    };
+    let mut listeners = listeners.into_iter();
+    let first = listeners.next().expect("the config has at least one address");
+    let handler = proxied(&config.proxy, router(static_files(&config, &metrics), metrics.clone()));
+    let server = Server::new(first, pool, handler, server_config);
+    let mut server = listeners.fold(server, Server::with_listener).with_metrics(metrics);
+    if config.tls.redirect_http {
+        server = server.redirect_to_https(config.tls.listen[0].port());
+    }
+    if config.rate_limit.enabled {
+        server = server.with_middleware(rate_limiter(&config.rate_limit));
+    }
 
-    // incoming gives an iterator over a sequence of streams.
-    // a single stream represents an open connection between the client and the server.
-    // we are actually iterating over connection
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+    // Ctrl-C or `kill` start a graceful shutdown instead of killing the process right away
+    let shutdown = Shutdown::new();
+    if let Err(err) = shutdown.trigger_on_signals() {
+        eprintln!("Failed to handle the shutdown signals, Ctrl-C stops the server without draining it: {err}");
+    }
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
-        // connection is closed as part of the drop implementation
+    // the exit status tells a supervisor (systemd, docker, a script) if requests were cut short
+    match server.run(&shutdown) {
+        Ok(true) => println!("Shut down cleanly."),
+        Ok(false) => {
+            eprintln!("Some connections were still being handled after the shutdown timeout.");
+            process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Server error: {err}");
+            process::exit(2);
+        }
+    }
+}
+
+fn static_files(config: &Config, metrics: &Metrics) -> StaticFiles {
+    let mut files = StaticFiles::new(&config.document_root)
+        .index(&config.index)
+        .precompressed(config.compression.enabled && config.compression.precompressed);
+    if config.cache.enabled {
+        let cache = FileCache::new(config.cache.capacity).max_file_size(config.cache.max_file_size);
+        // the hit rate shows up next to the other metrics, to tell whether the capacity is big enough
+        metrics.watch_cache(cache.clone());
+        files = files.cache(cache);
+    }
+    match &config.not_found_page {
+        Some(page) => files.not_found_page(page),
+        None => files,
     }
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    // BufReader adds buffering by managing calls to the `std::io::Read` trait methods for us.
-    let buf_reader = BufReader::new(&mut stream);
-    let http_request: Vec<_> = buf_reader
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty()) // the browser signals the end of an HTTP request by sending two newline characters in a row
+// The requests under the path of a `[[proxy]]` go to its upstreams, the other ones to `router`.
+fn proxied(routes: &[ProxyRoute], router: Router) -> impl Handler {
+    let proxies: Vec<(String, Proxy)> = routes
+        .iter()
+        .map(|route| {
+            let proxy = Proxy::new(route.upstreams.iter().copied()).balance(route.balance).timeout(route.timeout);
+            let proxy = match &route.health_check {
+                Some(path) => proxy.health_check(path.as_str(), route.health_interval),
+                None => proxy,
+            };
+            (route.path.trim_end_matches('/').to_string(), proxy)
+        })
         .collect();
 
-    // we are reading the first line because its the one that
-    // has the Method Request-URI HTTP-Version CRLF
-    // HTTP is text-based, request has the following format
-    // Method Request-URI HTTP-Version CRLF
-    // headers CRLF
-    // message-body
-    let first_line = http_request.first().unwrap().as_ref();
-    // IMPORTANT: We need to explicitly match on a slice of request_line to pattern match against the string literal values.
-    // match doesn't do automatic referencing and dereferencing like the equality method does.
-    let (status_line, filename) = match first_line {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "index.html"),
-        "GET /sleep HTTP/1.1" => {
-            thread::sleep(Duration::from_secs(5));
-            ("HTTP/1.1 200 OK", "index.html")
+    move |request: &Request| {
+        // `/api` takes `/api` and `/api/items`, not `/apiary`
+        let proxy = proxies.iter().find(|(path, _)| match request.path.strip_prefix(path.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        });
+        match proxy {
+            Some((_, proxy)) => proxy.handle(request),
+            None => router.handle(request),
         }
-        _ => ("HTTP/1.1 404 NOT FOUND", "404.html"),
-    };
+    }
+}
 
-    let contents = fs::read_to_string(filename).unwrap();
-    let length = contents.len();
+fn rate_limiter(config: &RateLimit) -> RateLimiter {
+    let limiter = config.routes.iter().fold(RateLimiter::new(config.rate), |limiter, (path, rate)| {
+        limiter.route(path.as_str(), *rate)
+    });
+    match &config.key_header {
+        Some(name) => limiter.key_header(name.as_str()),
+        None => limiter,
+    }
+}
 
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+// `None` when the access log is turned off. A log file is reopened on SIGHUP, for logrotate.
+fn access_log(config: &Log) -> io::Result<Option<AccessLog>> {
+    if !config.access {
+        return Ok(None);
+    }
+    let log = match &config.file {
+        Some(path) => {
+            let log = AccessLog::file(path, config.format)?;
+            #[cfg(unix)]
+            log.reopen_on_sighup()?;
+            log
+        }
+        None => AccessLog::stdout(config.format),
+    };
+    Ok(Some(log))
+}
 
-    stream.write_all(response.as_bytes()).unwrap();
-    // println!("Request: {:#?}", http_request)
+fn router(files: StaticFiles, metrics: Metrics) -> Router {
+    let files = Arc::new(files);
+    let index = Arc::clone(&files);
+
+    Router::new()
+        // for Prometheus to scrape
+        .get("/metrics", move |request, _| metrics.handle(request))
+        .get("/sleep", move |request, _| {
+            thread::sleep(Duration::from_secs(5));
+            index.serve(request, "/")
+        })
+        .get("/*path", move |request, params| files.serve(request, params.get("path").unwrap_or_default()))
 }
 
 // ===== Improving Throughput with a Thread pool
@@ -0,0 +1,188 @@
+// ===== Metrics
+// Counters and gauges about the requests and the thread pool, in the Prometheus text exposition format:
+// https://prometheus.io/docs/instrumenting/exposition_formats/
+//
+// Prometheus scrapes an endpoint (usually `/metrics`) every few seconds and does the math itself:
+// we only keep running totals, rates and averages are computed from the difference between two scrapes.
+// A histogram is a set of counters, one per bucket: how many requests took at most 5ms, at most 10ms, ...
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::{FileCache, Handler, PoolMonitor, Request, Response};
+
+// upper bounds of the latency buckets in seconds, the default buckets of the Prometheus client libraries
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Collects what the server does, cloning gives another handle to the same counters.
+///
+/// Pass it to `Server::with_metrics` to record every request, and serve it (it's a `Handler`) to let
+/// Prometheus scrape it.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requests: Mutex<BTreeMap<u16, u64>>,
+    // requests per bucket, the last one is for requests slower than every bound
+    latency_buckets: [AtomicU64; BUCKETS.len() + 1],
+    latency_sum_micros: AtomicU64,
+    pool: OnceLock<PoolMonitor>,
+    cache: OnceLock<FileCache>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a request answered with `status` after `duration`.
+    pub fn observe(&self, status: u16, duration: Duration) {
+        *self.inner.requests.lock().unwrap().entry(status).or_insert(0) += 1;
+
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(BUCKETS.len());
+        self.inner.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.inner.latency_sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Adds the gauges of a thread pool to the metrics. Only the first pool is kept.
+    pub fn watch_pool(&self, pool: PoolMonitor) {
+        let _ = self.inner.pool.set(pool);
+    }
+
+    /// Adds the counters of a file cache to the metrics. Only the first cache is kept.
+    pub fn watch_cache(&self, cache: FileCache) {
+        let _ = self.inner.cache.set(cache);
+    }
+
+    /// Everything collected so far, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        // writing to a String can't fail
+        let _ = self.write(&mut output);
+        output
+    }
+
+    fn write(&self, output: &mut String) -> fmt::Result {
+        header(output, "http_requests_total", "counter", "Requests answered, by status code.")?;
+        for (status, count) in self.inner.requests.lock().unwrap().iter() {
+            writeln!(output, "http_requests_total{{code=\"{status}\"}} {count}")?;
+        }
+
+        // Prometheus buckets are cumulative: every bucket also counts the requests of the smaller ones
+        header(output, "http_request_duration_seconds", "histogram", "Time from the first byte of a request to the end of its response.")?;
+        let mut count = 0;
+        for (index, bucket) in self.inner.latency_buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            match BUCKETS.get(index) {
+                Some(bound) => writeln!(output, "http_request_duration_seconds_bucket{{le=\"{bound}\"}} {count}")?,
+                None => writeln!(output, "http_request_duration_seconds_bucket{{le=\"+Inf\"}} {count}")?,
+            }
+        }
+        let sum = self.inner.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        writeln!(output, "http_request_duration_seconds_sum {sum}")?;
+        writeln!(output, "http_request_duration_seconds_count {count}")?;
+
+        if let Some(cache) = self.inner.cache.get() {
+            let stats = cache.stats();
+            header(output, "file_cache_hits_total", "counter", "Files served from the cache.")?;
+            writeln!(output, "file_cache_hits_total {}", stats.hits)?;
+            header(output, "file_cache_misses_total", "counter", "Files read from the disk, not cached or stale.")?;
+            writeln!(output, "file_cache_misses_total {}", stats.misses)?;
+            header(output, "file_cache_entries", "gauge", "Files in the cache.")?;
+            writeln!(output, "file_cache_entries {}", stats.entries)?;
+            header(output, "file_cache_bytes", "gauge", "Bytes the cached files take up.")?;
+            writeln!(output, "file_cache_bytes {}", stats.bytes)?;
+        }
+
+        let pool = match self.inner.pool.get() {
+            Some(pool) => pool,
+            None => return Ok(()),
+        };
+        header(output, "thread_pool_workers", "gauge", "Worker threads alive.")?;
+        writeln!(output, "thread_pool_workers {}", pool.size())?;
+        header(output, "thread_pool_busy_workers", "gauge", "Worker threads running a job.")?;
+        writeln!(output, "thread_pool_busy_workers {}", pool.busy())?;
+        header(output, "thread_pool_queued_jobs", "gauge", "Jobs waiting for a worker.")?;
+        writeln!(output, "thread_pool_queued_jobs {}", pool.queued())?;
+        header(output, "thread_pool_jobs_total", "counter", "Jobs run, by worker.")?;
+        for (worker, jobs) in pool.jobs_per_worker() {
+            writeln!(output, "thread_pool_jobs_total{{worker=\"{worker}\"}} {jobs}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Handler for Metrics {
+    fn handle(&self, _: &Request) -> Response {
+        Response::new(200)
+            .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            .with_body(self.render())
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+fn header(output: &mut String, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(output, "# HELP {name} {help}")?;
+    writeln!(output, "# TYPE {name} {kind}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadPool;
+
+    #[test]
+    fn renders_requests_and_latency() {
+        let metrics = Metrics::new();
+        metrics.observe(200, Duration::from_millis(3));
+        metrics.observe(200, Duration::from_millis(40));
+        metrics.observe(404, Duration::from_secs(20));
+
+        let output = metrics.render();
+        assert!(output.contains("# TYPE http_requests_total counter\n"));
+        assert!(output.contains("http_requests_total{code=\"200\"} 2\n"));
+        assert!(output.contains("http_requests_total{code=\"404\"} 1\n"));
+        assert!(output.contains("http_request_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(output.contains("http_request_duration_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(output.contains("http_request_duration_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(output.contains("http_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(output.contains("http_request_duration_seconds_sum 20.043\n"));
+        assert!(output.contains("http_request_duration_seconds_count 3\n"));
+        // no pool or cache to report on
+        assert!(!output.contains("thread_pool"));
+        assert!(!output.contains("file_cache"));
+    }
+
+    #[test]
+    fn renders_the_pool() {
+        let pool = ThreadPool::build(2).unwrap();
+        let metrics = Metrics::new();
+        metrics.watch_pool(pool.monitor());
+        pool.spawn(|| ()).unwrap().join().unwrap();
+
+        let output = metrics.render();
+        assert!(output.contains("thread_pool_workers 2\n"));
+        assert!(output.contains("thread_pool_queued_jobs 0\n"));
+        assert!(output.contains("thread_pool_jobs_total{worker=\"0\"} "));
+        assert!(output.contains("thread_pool_jobs_total{worker=\"1\"} "));
+        let jobs: u64 = pool.monitor().jobs_per_worker().iter().map(|(_, jobs)| jobs).sum();
+        assert_eq!(jobs, 1);
+    }
+}
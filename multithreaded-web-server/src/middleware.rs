@@ -0,0 +1,143 @@
+// ===== Middleware
+// Some things apply to every request whatever the route: logging, authentication, CORS headers, timing.
+// A middleware wraps the handler: it gets the request first, decides whether to call the rest of the
+// chain with `next.run(request)` (possibly with a rewritten request), and can change the response on
+// its way back. Middlewares are stacked like layers, the first one added is the outermost:
+//
+//   request ──▶ timing ──▶ auth ──▶ handler
+//   response ◀── timing ◀── auth ◀──┘
+//
+// so `timing` measures everything, including `auth` answering 401 without calling the handler.
+
+use std::sync::Arc;
+
+use crate::{Handler, Request, Response};
+
+/// Wraps a handler, see `Chain` and `Server::with_middleware`.
+///
+/// Closures taking the request and `Next` are middlewares too:
+/// ```
+/// # use multithreaded_web_server::{middleware::Next, Request, Response};
+/// let powered_by = |request: &Request, next: Next<'_>| next.run(request).with_header("X-Powered-By", "Rust");
+/// ```
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&Request, Next<'_>) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of the chain after a middleware: the middlewares inside it, then the handler.
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(
+                request,
+                Next {
+                    middleware: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.handle(request),
+        }
+    }
+}
+
+/// A handler wrapped in middlewares, itself a handler.
+#[derive(Clone)]
+pub struct Chain {
+    middleware: Vec<Arc<dyn Middleware>>,
+    handler: Arc<dyn Handler>,
+}
+
+impl Chain {
+    pub fn new(handler: impl Handler) -> Self {
+        Self::from_arc(Arc::new(handler))
+    }
+
+    pub(crate) fn from_arc(handler: Arc<dyn Handler>) -> Self {
+        Self {
+            middleware: Vec::new(),
+            handler,
+        }
+    }
+
+    /// Adds `middleware` inside the ones added before, right around the handler.
+    pub fn with(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    pub(crate) fn with_arc(mut self, middleware: Arc<dyn Middleware>) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+}
+
+impl Handler for Chain {
+    fn handle(&self, request: &Request) -> Response {
+        Next {
+            middleware: &self.middleware,
+            handler: self.handler.as_ref(),
+        }
+        .run(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(handler: &impl Handler, raw: &str) -> Response {
+        handler.handle(&Request::parse(&mut raw.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn runs_in_the_order_they_were_added() {
+        // each one adds its name to the way in (a request header) and to the way out (a response header)
+        let layer = |name: &'static str| {
+            move |request: &Request, next: Next<'_>| {
+                let mut request = request.clone();
+                request.headers.append("X-In", name);
+                let mut response = next.run(&request);
+                response.headers.append("X-Out", name);
+                response
+            }
+        };
+        let handler = |request: &Request| {
+            let seen: Vec<&str> = request.headers.get_all("X-In").collect();
+            Response::text(200, seen.join(","))
+        };
+        let chain = Chain::new(handler).with(layer("outer")).with(layer("inner"));
+
+        let response = get(&chain, "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(response.body, b"outer,inner");
+        let out: Vec<&str> = response.headers.get_all("X-Out").collect();
+        assert_eq!(out, ["inner", "outer"]);
+    }
+
+    #[test]
+    fn can_answer_without_the_handler() {
+        let auth = |request: &Request, next: Next<'_>| match request.headers.get("Authorization") {
+            Some("Bearer secret") => next.run(request),
+            _ => Response::error(401).with_header("WWW-Authenticate", "Bearer"),
+        };
+        let chain = Chain::new(|_: &Request| Response::text(200, "private")).with(auth);
+
+        assert_eq!(get(&chain, "GET / HTTP/1.1\r\n\r\n").status, 401);
+        let response = get(&chain, "GET / HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n");
+        assert_eq!(response.body, b"private");
+    }
+}
@@ -0,0 +1,612 @@
+// ===== Reverse proxy
+// A `Proxy` is a handler that forwards the requests it gets to other HTTP servers (the "upstreams") and
+// answers with their responses, so the server can sit in front of a few local services:
+//
+//   client ──▶ this server ──▶ 127.0.0.1:9001
+//                          └─▶ 127.0.0.1:9002
+//
+// Requests are spread over the upstreams either in turn ("round robin"), or to the one with the fewest
+// requests in flight ("least connections"), which does better when some requests are much slower than others.
+//
+// The upstream doesn't see the client anymore, every request comes from us. The `X-Forwarded-*` headers tell
+// it who asked: the client's IP address (added to the ones earlier proxies put there), the Host it asked for
+// and whether it came over HTTPS. Headers that only concern one hop (`Connection`, `Keep-Alive`, ...) are
+// not forwarded, in either direction.
+//
+// An upstream that fails 3 times in a row, to accept a connection or to answer, is taken out of the rotation
+// ("ejected"). With a health check, a background thread asks every upstream for a path every now and then: a
+// 2xx or 3xx puts an ejected upstream back, 3 failed checks eject a healthy one before a client runs into it.
+// Without health checks, or between two of them, an upstream that was ejected for a while (the "cooldown")
+// gets the next request as a trial: an answer puts it back, a failure ejects it for another cooldown.
+// When every upstream is ejected the requests still go to all of them, a guess beats a sure 502.
+//
+// We open a new connection for every request and read the whole response before answering, like every
+// other handler. That's fine for APIs and pages, not for huge downloads or for WebSockets.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    headers::Headers,
+    request::{content_length, origin_form, read_chunked_body, read_headers},
+    Handler, Limits, Method, ParseError, Request, Response,
+};
+
+// failures in a row, of requests or of health checks, that take an upstream out
+const MAX_FAILURES: u32 = 3;
+
+// we trust the upstreams more than the clients, but a broken one still shouldn't make us buffer anything
+const UPSTREAM_LIMITS: Limits = Limits {
+    max_header_bytes: 64 * 1024,
+    max_headers: 200,
+    max_body_size: 64 * 1024 * 1024,
+};
+
+// https://www.rfc-editor.org/rfc/rfc9110#name-connection, plus the ones older clients and proxies use
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// How a `Proxy` picks the upstream of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Balance {
+    /// Each upstream in turn.
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest requests in flight, in turn among the ones with as few.
+    LeastConnections,
+}
+
+/// A handler forwarding requests to a pool of upstream servers.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use multithreaded_web_server::proxy::{Balance, Proxy};
+/// let proxy = Proxy::new(["127.0.0.1:9001".parse().unwrap(), "127.0.0.1:9002".parse().unwrap()])
+///     .balance(Balance::LeastConnections)
+///     .health_check("/health", Duration::from_secs(5));
+/// ```
+pub struct Proxy {
+    // shared with the health check thread, which stops once the proxy is dropped
+    upstreams: Arc<[Upstream]>,
+    balance: Balance,
+    // where the next round starts
+    next: AtomicUsize,
+    timeout: Duration,
+    cooldown: Duration,
+}
+
+#[derive(Debug)]
+struct Upstream {
+    address: SocketAddr,
+    healthy: AtomicBool,
+    failures: AtomicU32,
+    // when it was ejected, or last given a trial request
+    ejected: Mutex<Option<Instant>>,
+    // requests in flight, for `Balance::LeastConnections`
+    active: AtomicUsize,
+}
+
+// Counts a request as in flight on an upstream while it lives.
+struct InFlight<'a>(&'a Upstream);
+
+impl Proxy {
+    /// Forwards requests to `upstreams` in turn, with a timeout of 30 seconds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no upstream.
+    pub fn new(upstreams: impl IntoIterator<Item = SocketAddr>) -> Self {
+        let upstreams: Arc<[Upstream]> = upstreams
+            .into_iter()
+            .map(|address| Upstream {
+                address,
+                healthy: AtomicBool::new(true),
+                failures: AtomicU32::new(0),
+                ejected: Mutex::new(None),
+                active: AtomicUsize::new(0),
+            })
+            .collect();
+        assert!(!upstreams.is_empty(), "a proxy needs at least one upstream");
+        Self {
+            upstreams,
+            balance: Balance::default(),
+            next: AtomicUsize::new(0),
+            timeout: Duration::from_secs(30),
+            cooldown: Duration::from_secs(10),
+        }
+    }
+
+    pub fn balance(mut self, balance: Balance) -> Self {
+        self.balance = balance;
+        self
+    }
+
+    /// How long an upstream gets to accept the connection, and then to send each part of its response.
+    /// One that takes longer gets the client a 504.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long an ejected upstream stays out before it gets a trial request, 10 seconds by default.
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Sends a `GET path` to every upstream every `interval`, from a background thread, to eject the ones
+    /// that fail and put back the ones that recovered. A check that takes longer than `interval` fails.
+    pub fn health_check(self, path: impl Into<String>, interval: Duration) -> Self {
+        let upstreams = Arc::downgrade(&self.upstreams);
+        let path = path.into();
+        let spawned = thread::Builder::new()
+            .name(String::from("health-check"))
+            .spawn(move || check_health(upstreams, &path, interval));
+        // the cooldowns still bring the ejected upstreams back, only slower
+        if let Err(err) = spawned {
+            eprintln!("Failed to start the health checks, going on without them: {err}");
+        }
+        self
+    }
+
+    // The upstreams to try for a request, the best one first.
+    fn candidates(&self) -> Vec<&Upstream> {
+        let mut candidates: Vec<&Upstream> = self.upstreams.iter().collect();
+        if candidates.iter().any(|upstream| upstream.is_healthy()) {
+            candidates.retain(|upstream| upstream.is_healthy());
+        }
+        // in turn among the ones that are left, an ejected upstream doesn't send its turn to its neighbor
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+        candidates.rotate_left(start);
+        if self.balance == Balance::LeastConnections {
+            // the sort is stable, the ones with as few requests stay in turn
+            candidates.sort_by_key(|upstream| upstream.active.load(Ordering::Relaxed));
+        }
+        // an ejected upstream whose cooldown is over goes first, its trial is wasted if another one answers
+        if let Some(trial) = self.upstreams.iter().find(|upstream| upstream.trial(self.cooldown)) {
+            candidates.retain(|upstream| !std::ptr::eq(*upstream, trial));
+            candidates.insert(0, trial);
+        }
+        candidates
+    }
+}
+
+impl Handler for Proxy {
+    fn handle(&self, request: &Request) -> Response {
+        let message = forwarded(request);
+        // the request didn't reach an upstream that refuses the connection, the next one can have it.
+        // Once it's sent we can't know whether the upstream acted on it, no second try then.
+        for upstream in self.candidates() {
+            let _in_flight = InFlight::new(upstream);
+            let stream = match connect(upstream.address, self.timeout) {
+                Ok(stream) => stream,
+                Err(_) => {
+                    upstream.failed();
+                    continue;
+                }
+            };
+            return match exchange(stream, &message, request.method) {
+                Ok(response) => {
+                    upstream.succeeded();
+                    response
+                }
+                Err(err) => {
+                    upstream.failed();
+                    match err {
+                        ParseError::Io(err) if is_timeout(&err) => Response::error(504),
+                        _ => Response::error(502),
+                    }
+                }
+            };
+        }
+        Response::error(502)
+    }
+}
+
+impl Upstream {
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn succeeded(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        self.healthy.store(true, Ordering::Relaxed);
+    }
+
+    fn failed(&self) {
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 >= MAX_FAILURES {
+            // a failed trial starts another cooldown
+            *self.lock_ejected() = Some(Instant::now());
+            self.healthy.store(false, Ordering::Relaxed);
+        }
+    }
+
+    // Whether this ejected upstream is due for a trial request. Only one request gets it per cooldown.
+    fn trial(&self, cooldown: Duration) -> bool {
+        if self.is_healthy() {
+            return false;
+        }
+        let mut ejected = self.lock_ejected();
+        match *ejected {
+            Some(since) if since.elapsed() < cooldown => false,
+            _ => {
+                *ejected = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    fn lock_ejected(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        // an `Option<Instant>` can't be left half written
+        self.ejected.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<'a> InFlight<'a> {
+    fn new(upstream: &'a Upstream) -> Self {
+        upstream.active.fetch_add(1, Ordering::Relaxed);
+        Self(upstream)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// The health check thread, until the proxy is gone.
+fn check_health(upstreams: Weak<[Upstream]>, path: &str, interval: Duration) {
+    let request = format!("GET {path} HTTP/1.1\r\nUser-Agent: health-check\r\nConnection: close\r\n");
+    loop {
+        let upstreams = match upstreams.upgrade() {
+            Some(upstreams) => upstreams,
+            None => return,
+        };
+        for upstream in upstreams.iter() {
+            let message = format!("{request}Host: {}\r\n\r\n", upstream.address).into_bytes();
+            let status = connect(upstream.address, interval)
+                .map_err(ParseError::Io)
+                .and_then(|stream| exchange(stream, &message, Method::Get))
+                .map(|response| response.status);
+            match status {
+                Ok(200..=399) => upstream.succeeded(),
+                _ => upstream.failed(),
+            }
+        }
+        // not holding on to the upstreams while sleeping, the proxy may be dropped in the meantime
+        drop(upstreams);
+        thread::sleep(interval);
+    }
+}
+
+// The request as it goes to the upstream, head and body.
+fn forwarded(request: &Request) -> Vec<u8> {
+    let mut headers = end_to_end(&request.headers);
+    // the body is already read (and decoded, if it was chunked), we send it with its length.
+    // The client's `Expect: 100-continue` was for us, the upstream would answer it with a 100 we don't need.
+    headers.remove("Content-Length");
+    headers.remove("Expect");
+    if !request.body.is_empty() || matches!(request.method, Method::Post | Method::Put | Method::Patch) {
+        headers.set("Content-Length", request.body.len().to_string());
+    }
+    headers.set("Connection", "close");
+
+    if let Some(client) = request.client {
+        let mut chain: Vec<&str> = request.headers.get_all("X-Forwarded-For").collect();
+        let ip = client.ip().to_string();
+        chain.push(&ip);
+        headers.set("X-Forwarded-For", chain.join(", "));
+    }
+    if let Some(host) = request.headers.get("Host") {
+        headers.set("X-Forwarded-Host", host);
+    }
+    headers.set("X-Forwarded-Proto", if request.secure { "https" } else { "http" });
+
+    // the upstream gets the path and query only, even when the client sent us a full URL
+    let target = origin_form(&request.target).unwrap_or(&request.target);
+    let mut message = format!("{} {target} HTTP/1.1\r\n", request.method);
+    for (name, value) in headers.iter() {
+        message.push_str(&format!("{name}: {value}\r\n"));
+    }
+    message.push_str("\r\n");
+    let mut message = message.into_bytes();
+    message.extend_from_slice(&request.body);
+    message
+}
+
+// `headers` without the hop-by-hop ones, and without the ones `Connection` names as such.
+fn end_to_end(headers: &Headers) -> Headers {
+    let named: Vec<&str> = headers
+        .get_all("Connection")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    let mut copy = Headers::new();
+    for (name, value) in headers.iter() {
+        let hop_by_hop = HOP_BY_HOP.iter().chain(&named).any(|hop| hop.eq_ignore_ascii_case(name));
+        if !hop_by_hop {
+            copy.append(name, value);
+        }
+    }
+    copy
+}
+
+fn connect(address: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+// Sends `message` on `stream` and reads the response to it.
+fn exchange(stream: TcpStream, message: &[u8], method: Method) -> Result<Response, ParseError> {
+    (&stream).write_all(message)?;
+    let mut reader = BufReader::new(&stream);
+    loop {
+        let response = read_response(&mut reader, method)?;
+        // a 1xx is an interim response, the real one follows on the same connection
+        if !(100..=199).contains(&response.status) {
+            return Ok(response);
+        }
+    }
+}
+
+fn read_response<R: BufRead>(reader: &mut R, method: Method) -> Result<Response, ParseError> {
+    let mut budget = UPSTREAM_LIMITS.max_header_bytes;
+    let mut status_line = String::new();
+    reader.take(budget as u64).read_line(&mut status_line)?;
+    budget -= status_line.len();
+    // HTTP-Version SP Status-Code SP [Reason-Phrase]
+    let status = match status_line.trim_end().splitn(3, ' ').collect::<Vec<_>>()[..] {
+        [version, status, ..] if version.starts_with("HTTP/1.") && status.len() == 3 => status.parse().ok(),
+        _ => None,
+    };
+    let status: u16 = status.ok_or(ParseError::InvalidRequestLine)?;
+    let headers = read_headers(reader, &mut budget, UPSTREAM_LIMITS.max_headers)?;
+
+    let body = if method == Method::Head || matches!(status, 100..=199 | 204 | 304) {
+        Vec::new()
+    } else if headers.contains("Transfer-Encoding") {
+        read_chunked_body(reader, &UPSTREAM_LIMITS)?
+    } else if let Some(length) = content_length(&headers)? {
+        if length > UPSTREAM_LIMITS.max_body_size {
+            return Err(ParseError::BodyTooLarge);
+        }
+        read_exactly(reader, length)?
+    } else {
+        // no length, the body ends with the connection
+        let mut body = Vec::new();
+        reader.take(UPSTREAM_LIMITS.max_body_size + 1).read_to_end(&mut body)?;
+        if body.len() as u64 > UPSTREAM_LIMITS.max_body_size {
+            return Err(ParseError::BodyTooLarge);
+        }
+        body
+    };
+
+    let mut response = Response::new(status).with_body(body);
+    response.headers = end_to_end(&headers);
+    Ok(response)
+}
+
+fn read_exactly<R: BufRead>(reader: &mut R, length: u64) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    reader.take(length).read_to_end(&mut body)?;
+    if body.len() as u64 != length {
+        return Err(ParseError::UnexpectedEof);
+    }
+    Ok(body)
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::mpsc,
+        time::Instant,
+    };
+
+    use super::*;
+
+    // An upstream answering every request with `handler`, on a thread of its own.
+    fn stub(handler: impl Fn(&Request) -> Response + Send + Sync + 'static) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handler = Arc::new(handler);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let handler = Arc::clone(&handler);
+                thread::spawn(move || {
+                    if let Ok(request) = Request::parse(&mut BufReader::new(&stream)) {
+                        let _ = handler(&request).write_to(&mut &stream);
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    // An address nobody listens on.
+    fn closed() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    fn request(raw: &str) -> Request {
+        let request = Request::parse(&mut raw.as_bytes()).unwrap();
+        Request {
+            client: Some("203.0.113.7:51000".parse().unwrap()),
+            ..request
+        }
+    }
+
+    fn get(proxy: &Proxy, path: &str) -> Response {
+        proxy.handle(&request(&format!("GET {path} HTTP/1.1\r\nHost: example.com\r\n\r\n")))
+    }
+
+    fn until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn forwards_requests_with_the_forwarded_headers() {
+        let upstream = stub(|request: &Request| {
+            let header = |name| request.headers.get(name).unwrap_or("-");
+            let seen = format!(
+                "{} {} {} for={} host={} proto={} secret={}",
+                request.method,
+                request.target,
+                String::from_utf8_lossy(&request.body),
+                header("X-Forwarded-For"),
+                header("X-Forwarded-Host"),
+                header("X-Forwarded-Proto"),
+                header("X-Secret"),
+            );
+            Response::text(201, seen)
+                .with_header("X-Upstream", "yes")
+                .with_header("Keep-Alive", "timeout=5")
+        });
+        let proxy = Proxy::new([upstream]);
+
+        let response = proxy.handle(&request(
+            "POST http://example.com/items?page=2 HTTP/1.1\r\nHost: example.com\r\n\
+             X-Forwarded-For: 198.51.100.1\r\nConnection: X-Secret\r\nX-Secret: hop\r\n\
+             Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        ));
+        assert_eq!(response.status, 201);
+        assert_eq!(
+            String::from_utf8_lossy(&response.body),
+            "POST /items?page=2 hello for=198.51.100.1, 203.0.113.7 host=example.com proto=http secret=-"
+        );
+        assert_eq!(response.headers.get("X-Upstream"), Some("yes"));
+        assert_eq!(response.headers.get("Keep-Alive"), None);
+    }
+
+    #[test]
+    fn balances_the_requests() {
+        let (release, released) = mpsc::channel::<()>();
+        let released = std::sync::Mutex::new(released);
+        let first = stub(move |request: &Request| {
+            if request.path == "/slow" {
+                let _ = released.lock().unwrap().recv();
+            }
+            Response::text(200, "first")
+        });
+        let second = stub(|_: &Request| Response::text(200, "second"));
+        let body = |response: Response| String::from_utf8(response.body).unwrap();
+
+        let proxy = Proxy::new([first, second]);
+        let served: Vec<String> = (0..4).map(|_| body(get(&proxy, "/"))).collect();
+        assert_eq!(served, ["first", "second", "first", "second"]);
+
+        // the first upstream is busy with a slow request, the next ones go to the other one
+        let proxy = Arc::new(Proxy::new([first, second]).balance(Balance::LeastConnections));
+        let slow = thread::spawn({
+            let proxy = Arc::clone(&proxy);
+            move || body(get(&proxy, "/slow"))
+        });
+        until(|| proxy.upstreams[0].active.load(Ordering::Relaxed) == 1);
+        assert_eq!(body(get(&proxy, "/")), "second");
+        assert_eq!(body(get(&proxy, "/")), "second");
+        release.send(()).unwrap();
+        assert_eq!(slow.join().unwrap(), "first");
+    }
+
+    #[test]
+    fn ejects_the_upstreams_that_fail_their_health_checks() {
+        let sick = Arc::new(AtomicBool::new(true));
+        let healthy = stub(|_: &Request| Response::text(200, "healthy"));
+        let recovering = stub({
+            let sick = Arc::clone(&sick);
+            move |request: &Request| match request.path.as_str() {
+                "/health" if sick.load(Ordering::Relaxed) => Response::error(503),
+                _ => Response::text(200, "recovering"),
+            }
+        });
+        let proxy = Proxy::new([healthy, recovering]).health_check("/health", Duration::from_millis(20));
+
+        until(|| !proxy.upstreams[1].is_healthy());
+        for _ in 0..4 {
+            assert_eq!(get(&proxy, "/").body, b"healthy");
+        }
+
+        sick.store(false, Ordering::Relaxed);
+        until(|| proxy.upstreams[1].is_healthy());
+        let served: Vec<Vec<u8>> = (0..2).map(|_| get(&proxy, "/").body).collect();
+        assert!(served.contains(&b"recovering".to_vec()));
+    }
+
+    #[test]
+    fn skips_the_upstreams_that_are_down() {
+        let up = stub(|_: &Request| Response::text(200, "up"));
+        let proxy = Proxy::new([closed(), up]);
+        for _ in 0..5 {
+            assert_eq!(get(&proxy, "/").status, 200);
+        }
+        // after a few refused connections, the one that is down isn't even tried
+        assert!(!proxy.upstreams[0].is_healthy());
+
+        assert_eq!(get(&Proxy::new([closed()]), "/").status, 502);
+        let silent = stub(|_: &Request| {
+            thread::sleep(Duration::from_millis(500));
+            Response::new(200)
+        });
+        let proxy = Proxy::new([silent]).timeout(Duration::from_millis(50));
+        assert_eq!(get(&proxy, "/").status, 504);
+    }
+
+    #[test]
+    fn gives_the_ejected_upstreams_a_trial_after_the_cooldown() {
+        // a port that refuses connections for now, an upstream listens on it later
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let other = stub(|_: &Request| Response::text(200, "other"));
+        let proxy = Proxy::new([address, other]).cooldown(Duration::from_millis(100));
+
+        until(|| {
+            get(&proxy, "/");
+            !proxy.upstreams[0].is_healthy()
+        });
+        // still out within the cooldown
+        for _ in 0..4 {
+            assert_eq!(get(&proxy, "/").body, b"other");
+        }
+
+        let listener = TcpListener::bind(address).unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if Request::parse(&mut BufReader::new(&stream)).is_ok() {
+                    let _ = Response::text(200, "back").write_to(&mut &stream);
+                }
+            }
+        });
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(get(&proxy, "/").body, b"back");
+        assert!(proxy.upstreams[0].is_healthy());
+    }
+}
@@ -0,0 +1,201 @@
+// ===== Range requests
+// A client can ask for parts of a file with `Range: bytes=<ranges>`, which lets it resume an
+// interrupted download instead of starting over. Each range is one of:
+// - `0-499`  the bytes from 0 to 499, both included
+// - `500-`   everything from byte 500 to the end
+// - `-200`   the last 200 bytes
+// https://www.rfc-editor.org/rfc/rfc9110#name-range-requests
+
+/// More ranges than this in a single request is not a download client, it's someone trying
+/// to make us do a lot of small reads for nothing, we just send the whole file instead.
+const MAX_RANGES: usize = 16;
+
+/// A range of bytes, `end` is included like in the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// The value of the `Content-Range` header for this range of a file of `total` bytes.
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{total}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No usable `Range` header, send the whole file with a 200.
+    Full,
+    /// Send these ranges with a 206. They are sorted and don't overlap.
+    Partial(Vec<ByteRange>),
+    /// None of the ranges fall inside the file, answer 416.
+    Unsatisfiable,
+}
+
+/// Interprets the value of a `Range` header for a file of `len` bytes.
+///
+/// A header we don't understand (another unit, bad syntax) is ignored as RFC 9110 allows,
+/// so the client gets the whole file rather than an error.
+pub fn parse(header: &str, len: u64) -> RangeRequest {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        match parse_spec(spec, len) {
+            Ok(Some(range)) => ranges.push(range),
+            // valid but outside the file, other ranges might still be satisfiable
+            Ok(None) => {}
+            Err(()) => return RangeRequest::Full,
+        }
+    }
+
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    let ranges = coalesce(ranges);
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    RangeRequest::Partial(ranges)
+}
+
+/// Returns `Err` for bad syntax and `Ok(None)` for a range that doesn't overlap the file.
+fn parse_spec(spec: &str, len: u64) -> Result<Option<ByteRange>, ()> {
+    let (first, last) = spec.split_once('-').ok_or(())?;
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // suffix range: the last N bytes
+        let suffix: u64 = parse_number(last)?;
+        if suffix == 0 || len == 0 {
+            return Ok(None);
+        }
+        return Ok(Some(ByteRange {
+            start: len.saturating_sub(suffix),
+            end: len - 1,
+        }));
+    }
+
+    let start = parse_number(first)?;
+    let end = if last.is_empty() { u64::MAX } else { parse_number(last)? };
+    if end < start {
+        return Err(());
+    }
+    if start >= len {
+        return Ok(None);
+    }
+
+    Ok(Some(ByteRange {
+        start,
+        end: end.min(len - 1),
+    }))
+}
+
+fn parse_number(value: &str) -> Result<u64, ()> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(());
+    }
+    value.parse().map_err(|_| ())
+}
+
+/// Sorts the ranges and merges the ones that overlap or touch, so we never send the same byte twice.
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Builds a `multipart/byteranges` body, each part has its own `Content-Type` and `Content-Range` headers.
+/// `parts` are the ranges with the bytes read from the file.
+pub fn multipart_body(parts: &[(ByteRange, Vec<u8>)], content_type: &str, total: u64, boundary: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (range, bytes) in parts {
+        body.extend_from_slice(part_head(range, content_type, total, boundary).as_bytes());
+        body.extend_from_slice(bytes);
+    }
+    body.extend_from_slice(closing_boundary(boundary).as_bytes());
+    body
+}
+
+/// What comes before the bytes of `range` in a `multipart/byteranges` body, for a body that is put together
+/// as it is sent instead of with `multipart_body`.
+pub fn part_head(range: &ByteRange, content_type: &str, total: u64, boundary: &str) -> String {
+    format!(
+        "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+        range.content_range(total)
+    )
+}
+
+/// What ends a `multipart/byteranges` body.
+pub fn closing_boundary(boundary: &str) -> String {
+    format!("\r\n--{boundary}--\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn parses_the_three_kinds_of_ranges() {
+        assert_eq!(parse("bytes=0-499", 1000), RangeRequest::Partial(vec![range(0, 499)]));
+        assert_eq!(parse("bytes=500-", 1000), RangeRequest::Partial(vec![range(500, 999)]));
+        assert_eq!(parse("bytes=-200", 1000), RangeRequest::Partial(vec![range(800, 999)]));
+        assert_eq!(parse("bytes=-2000", 1000), RangeRequest::Partial(vec![range(0, 999)]));
+        assert_eq!(parse("bytes=900-5000", 1000), RangeRequest::Partial(vec![range(900, 999)]));
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        assert_eq!(
+            parse("bytes=500-600, 0-99, 100-199, 550-700", 1000),
+            RangeRequest::Partial(vec![range(0, 199), range(500, 700)])
+        );
+    }
+
+    #[test]
+    fn ranges_outside_the_file_are_unsatisfiable() {
+        assert_eq!(parse("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=2000-3000, 10-19", 1000), RangeRequest::Partial(vec![range(10, 19)]));
+    }
+
+    #[test]
+    fn ignores_what_it_does_not_understand() {
+        assert_eq!(parse("items=0-5", 1000), RangeRequest::Full);
+        assert_eq!(parse("bytes=5-1", 1000), RangeRequest::Full);
+        assert_eq!(parse("bytes=a-b", 1000), RangeRequest::Full);
+        let many = (0..20).map(|i| format!("{}-{}", i * 10, i * 10 + 1)).collect::<Vec<_>>().join(",");
+        assert_eq!(parse(&format!("bytes={many}"), 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn builds_multipart_bodies() {
+        let parts = vec![(range(0, 1), b"ab".to_vec()), (range(4, 5), b"ef".to_vec())];
+        let body = multipart_body(&parts, "text/plain", 6, "XYZ");
+
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "\r\n--XYZ\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/6\r\n\r\nab\
+             \r\n--XYZ\r\nContent-Type: text/plain\r\nContent-Range: bytes 4-5/6\r\n\r\nef\
+             \r\n--XYZ--\r\n"
+        );
+    }
+}
@@ -0,0 +1,374 @@
+// ===== Rate limiting
+// One client sending requests as fast as it can shouldn't get all the workers. Every client gets a bucket
+// of tokens ("token bucket"): a request takes a token, and the bucket fills back up at a steady rate. A
+// full bucket lets a client send a burst of requests at once, after that it gets the average rate, and a
+// request that finds the bucket empty gets `429 Too Many Requests`.
+//
+// The client is its IP address, or behind a reverse proxy a header the proxy sets, since every request
+// then comes from the proxy's address. Only trust that header when a proxy sets it: a client talking to
+// us directly could send any value and get a fresh bucket with each one.
+//
+// Every response says where the client stands, the way the IETF draft does it
+// (https://datatracker.ietf.org/doc/draft-ietf-httpapi-ratelimit-headers/):
+//   RateLimit-Policy: 100;w=60   100 requests per 60 seconds
+//   RateLimit-Limit: 100
+//   RateLimit-Remaining: 42      requests left right now
+//   RateLimit-Reset: 35          seconds until the bucket is full again
+//
+// A bucket that was left alone long enough to fill up is the same as no bucket, so those are dropped
+// from time to time and the memory only grows with the clients that are active. A client that makes up a
+// new address or header value for every request is "active" many times over though, so there is also a
+// maximum number of buckets: once it's reached, new clients get a 429 until old buckets are dropped. The
+// clients we already know keep their buckets.
+//
+// The buckets are split over a few maps ("shards"), each behind its own lock, by a hash of the client.
+// Requests from different clients rarely wait for each other, and a sweep only goes through one shard.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{middleware::Next, Middleware, Request, Response};
+
+// how often the buckets that filled up again are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+// a power of two, so that picking the shard is a mask
+const SHARDS: usize = 16;
+
+/// `requests` per `period`. A client can send all of them at once, the bucket refills continuously.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Rate {
+    pub fn new(requests: u32, period: Duration) -> Self {
+        Self { requests, period }
+    }
+
+    // how long it takes to get a token back
+    fn per_token(&self) -> f64 {
+        self.period.as_secs_f64() / self.requests.max(1) as f64
+    }
+}
+
+/// A `Middleware` that answers 429 to the clients that send requests faster than their rate.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use multithreaded_web_server::rate_limit::{Rate, RateLimiter};
+/// let limiter = RateLimiter::new(Rate::new(300, Duration::from_secs(60)))
+///     .route("/api/login", Rate::new(5, Duration::from_secs(60)))
+///     .key_header("X-Real-IP");
+/// ```
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: Rate,
+    // path prefixes with their own rate, the longest matching one applies
+    routes: Vec<(String, Rate)>,
+    key_header: Option<String>,
+    // per shard
+    max_buckets: usize,
+    // random keys, a client can't pick which shard it lands in
+    hasher: RandomState,
+    shards: Box<[Mutex<Buckets>]>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    // by route (0 for the default rate, then the index in `routes` + 1) and client
+    buckets: HashMap<(usize, String), Bucket>,
+    last_sweep: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// What a request found in its bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Decision {
+    allowed: bool,
+    remaining: u32,
+    // until the bucket is full
+    reset: Duration,
+    // until the next token, when there is none left
+    retry_after: Duration,
+}
+
+impl RateLimiter {
+    /// Limits every client to `rate`, on each route that doesn't have its own.
+    pub fn new(rate: Rate) -> Self {
+        Self {
+            rate,
+            routes: Vec::new(),
+            key_header: None,
+            max_buckets: 100_000 / SHARDS,
+            hasher: RandomState::new(),
+            shards: (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(Buckets {
+                        buckets: HashMap::new(),
+                        last_sweep: Instant::now(),
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Gives the paths under `prefix` their own `rate`, and their own buckets. `/login` takes `/login` and
+    /// `/login/reset`, not `/loginfoo`.
+    pub fn route(mut self, prefix: impl Into<String>, rate: Rate) -> Self {
+        let prefix = prefix.into();
+        self.routes.push((prefix.trim_end_matches('/').to_string(), rate));
+        self
+    }
+
+    /// How many buckets are kept at most, over all the routes, 100 000 by default. Roughly: the buckets go
+    /// in shards, and a new client whose shard is full gets a 429.
+    pub fn max_buckets(mut self, max_buckets: usize) -> Self {
+        self.max_buckets = max_buckets.div_ceil(SHARDS);
+        self
+    }
+
+    /// Tells the clients apart by the header `name` instead of their IP address, for a server behind a
+    /// reverse proxy. With several values, e.g. `X-Forwarded-For: client, proxy`, the last one is used:
+    /// it's the one our proxy added. Requests without the header fall back to the IP address.
+    pub fn key_header(mut self, name: impl Into<String>) -> Self {
+        self.key_header = Some(name.into());
+        self
+    }
+
+    fn rule(&self, path: &str) -> (usize, Rate) {
+        self.routes
+            .iter()
+            .enumerate()
+            .filter(|(_, (prefix, _))| match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            })
+            .max_by_key(|(_, (prefix, _))| prefix.len())
+            .map(|(index, (_, rate))| (index + 1, *rate))
+            .unwrap_or((0, self.rate))
+    }
+
+    fn key(&self, request: &Request) -> Option<String> {
+        let header = self.key_header.as_ref().and_then(|name| {
+            let values = request.headers.get_all(name).flat_map(|value| value.split(','));
+            values.map(str::trim).filter(|value| !value.is_empty()).last()
+        });
+        match header {
+            Some(value) => Some(value.to_string()),
+            None => request.client.map(|client| client.ip()).as_ref().map(IpAddr::to_string),
+        }
+    }
+
+    // Takes a token from the bucket of `key` on route `route`, if there is one.
+    fn take(&self, route: usize, rate: Rate, key: String, now: Instant) -> Decision {
+        let key = (route, key);
+        let mut buckets = self.shard(&key);
+        if now.saturating_duration_since(buckets.last_sweep) >= SWEEP_INTERVAL {
+            self.sweep(&mut buckets, now);
+        }
+
+        let capacity = rate.requests as f64;
+        if buckets.buckets.len() >= self.max_buckets && !buckets.buckets.contains_key(&key) {
+            // no room for a new client until the next sweep
+            return Decision {
+                allowed: false,
+                remaining: 0,
+                reset: rate.period,
+                retry_after: SWEEP_INTERVAL,
+            };
+        }
+        let bucket = buckets.buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let refilled = now.saturating_duration_since(bucket.updated).as_secs_f64() / rate.per_token();
+        bucket.tokens = (bucket.tokens + refilled).min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Decision {
+            allowed,
+            remaining: bucket.tokens.floor() as u32,
+            reset: millis((capacity - bucket.tokens) * rate.per_token()),
+            retry_after: millis((1.0 - bucket.tokens).max(0.0) * rate.per_token()),
+        }
+    }
+
+    fn shard(&self, key: &(usize, String)) -> std::sync::MutexGuard<'_, Buckets> {
+        let shard = &self.shards[self.hasher.hash_one(key) as usize & (SHARDS - 1)];
+        // a bucket is consistent between statements, a panic can't leave one half updated
+        shard.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Drops the buckets of a shard that had the time to fill up since they were last used.
+    fn sweep(&self, buckets: &mut Buckets, now: Instant) {
+        buckets.buckets.retain(|(route, _), bucket| {
+            let rate = match route {
+                0 => self.rate,
+                route => self.routes[route - 1].1,
+            };
+            now.saturating_duration_since(bucket.updated) < rate.period
+        });
+        buckets.last_sweep = now;
+    }
+}
+
+impl Middleware for RateLimiter {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Response {
+        // nothing to tell the client apart by, e.g. a request that didn't come from a connection
+        let key = match self.key(request) {
+            Some(key) => key,
+            None => return next.run(request),
+        };
+        let (route, rate) = self.rule(&request.path);
+        let decision = self.take(route, rate, key, Instant::now());
+
+        let mut response = if decision.allowed {
+            next.run(request)
+        } else {
+            Response::error(429).with_header("Retry-After", seconds(decision.retry_after).max(1).to_string())
+        };
+        response.headers.set("RateLimit-Policy", format!("{};w={}", rate.requests, seconds(rate.period)));
+        response.headers.set("RateLimit-Limit", rate.requests.to_string());
+        response.headers.set("RateLimit-Remaining", decision.remaining.to_string());
+        response.headers.set("RateLimit-Reset", seconds(decision.reset).to_string());
+        response
+    }
+}
+
+// the fractions of tokens would otherwise turn 4s into 4.000000001s, and then into 5 seconds in the headers
+fn millis(seconds: f64) -> Duration {
+    Duration::from_millis((seconds * 1000.0).round() as u64)
+}
+
+// rounded up, a client that waits that long is sure to find a token
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Chain, Handler};
+
+    fn request(raw: &str, client: &str) -> Request {
+        let request = Request::parse(&mut raw.as_bytes()).unwrap();
+        Request {
+            client: Some(client.parse().unwrap()),
+            ..request
+        }
+    }
+
+    #[test]
+    fn refills_the_bucket_over_time() {
+        let limiter = RateLimiter::new(Rate::new(2, Duration::from_secs(10)));
+        let start = Instant::now();
+        let take = |after: u64| limiter.take(0, limiter.rate, String::from("client"), start + Duration::from_secs(after));
+
+        assert!(take(0).allowed);
+        let second = take(0);
+        assert!(second.allowed);
+        assert_eq!(second.remaining, 0);
+        assert_eq!(second.reset, Duration::from_secs(10));
+
+        let refused = take(1);
+        assert!(!refused.allowed);
+        assert_eq!(refused.retry_after, Duration::from_secs(4));
+        // one token every 5 seconds
+        assert!(take(5).allowed);
+        assert!(!take(5).allowed);
+    }
+
+    #[test]
+    fn answers_429_with_the_rate_limit_headers() {
+        let limiter = RateLimiter::new(Rate::new(1, Duration::from_secs(60)))
+            .route("/api/", Rate::new(2, Duration::from_secs(1)))
+            .key_header("X-Forwarded-For");
+        let chain = Chain::new(|_: &Request| Response::new(200)).with(limiter);
+
+        let page = request("GET /page HTTP/1.1\r\n\r\n", "10.0.0.1:5000");
+        let response = chain.handle(&page);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("RateLimit-Remaining"), Some("0"));
+        assert_eq!(response.headers.get("RateLimit-Policy"), Some("1;w=60"));
+
+        let response = chain.handle(&page);
+        assert_eq!(response.status, 429);
+        assert_eq!(response.headers.get("Retry-After"), Some("60"));
+        assert_eq!(response.headers.get("RateLimit-Limit"), Some("1"));
+
+        // the API has its own bucket, another client has its own too
+        assert_eq!(chain.handle(&request("GET /api/items HTTP/1.1\r\n\r\n", "10.0.0.1:5000")).status, 200);
+        assert_eq!(chain.handle(&request("GET /page HTTP/1.1\r\n\r\n", "10.0.0.2:5000")).status, 200);
+
+        // behind the proxy, the header tells the clients apart
+        let proxied = "GET /page HTTP/1.1\r\nX-Forwarded-For: 203.0.113.7, 198.51.100.1\r\n\r\n";
+        assert_eq!(chain.handle(&request(proxied, "10.0.0.1:5000")).status, 200);
+        assert_eq!(chain.handle(&request(proxied, "10.0.0.1:5000")).status, 429);
+    }
+
+    #[test]
+    fn drops_the_buckets_that_filled_up() {
+        let limiter = RateLimiter::new(Rate::new(10, Duration::from_secs(1)));
+        let start = Instant::now();
+        for client in 0..100 {
+            limiter.take(0, limiter.rate, client.to_string(), start);
+        }
+        assert_eq!(limiter.len(), 100);
+
+        // each shard is swept when a request comes by it
+        let later = start + SWEEP_INTERVAL + Duration::from_secs(1);
+        let mut late = 0;
+        while limiter.shards.iter().any(|shard| shard.lock().unwrap().last_sweep != later) {
+            limiter.take(0, limiter.rate, format!("late {late}"), later);
+            late += 1;
+        }
+        assert_eq!(limiter.len(), late);
+    }
+
+    #[test]
+    fn refuses_new_clients_once_full() {
+        let limiter = RateLimiter::new(Rate::new(10, Duration::from_secs(1))).max_buckets(SHARDS);
+        let start = Instant::now();
+        let refused = (0..1000).filter(|client| !limiter.take(0, limiter.rate, client.to_string(), start).allowed);
+        assert!(refused.count() >= 1000 - SHARDS);
+        assert!(limiter.len() <= SHARDS);
+
+        // the ones that got a bucket keep it
+        let known = limiter.shards.iter().find_map(|shard| shard.lock().unwrap().buckets.keys().next().cloned());
+        assert!(limiter.take(0, limiter.rate, known.unwrap().1, start).allowed);
+    }
+
+    #[test]
+    fn matches_the_routes_on_path_segments() {
+        let limiter = RateLimiter::new(Rate::new(1, Duration::from_secs(1)))
+            .route("/login", Rate::new(5, Duration::from_secs(1)))
+            .route("/api/", Rate::new(7, Duration::from_secs(1)));
+        assert_eq!(limiter.rule("/login").0, 1);
+        assert_eq!(limiter.rule("/login/reset").0, 1);
+        assert_eq!(limiter.rule("/loginfoo").0, 0);
+        assert_eq!(limiter.rule("/api").0, 2);
+        assert_eq!(limiter.rule("/apiary").0, 0);
+    }
+
+    impl RateLimiter {
+        fn len(&self) -> usize {
+            self.shards.iter().map(|shard| shard.lock().unwrap().buckets.len()).sum()
+        }
+    }
+}
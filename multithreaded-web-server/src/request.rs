@@ -0,0 +1,634 @@
+// ===== Parsing HTTP/1.1 requests
+// HTTP is text-based, a request has the following format:
+// Method Request-URI HTTP-Version CRLF
+// headers CRLF
+// CRLF
+// message-body
+// https://www.rfc-editor.org/rfc/rfc9112
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, BufRead, Read},
+    net::SocketAddr,
+    str::FromStr,
+};
+
+use crate::headers::Headers;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = ParseError;
+
+    // methods are case-sensitive, "get" is not the same as "GET"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "CONNECT" => Ok(Method::Connect),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "PATCH" => Ok(Method::Patch),
+            _ => Err(ParseError::InvalidMethod),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// The client closed the connection before sending anything.
+    ConnectionClosed,
+    /// The client closed the connection in the middle of a request.
+    UnexpectedEof,
+    InvalidRequestLine,
+    InvalidMethod,
+    InvalidTarget,
+    UnsupportedVersion,
+    InvalidHeader,
+    InvalidContentLength,
+    InvalidChunk,
+    UnsupportedTransferEncoding,
+    /// The request line and headers are longer than `Limits::max_header_bytes`.
+    HeadersTooLarge,
+    /// More header lines than `Limits::max_headers`.
+    TooManyHeaders,
+    /// The body is longer than `Limits::max_body_size`.
+    BodyTooLarge,
+    Io(io::Error),
+}
+
+impl ParseError {
+    /// The status code that should be sent back to the client for this error.
+    pub fn status(&self) -> u16 {
+        match self {
+            ParseError::UnsupportedVersion => 505,
+            ParseError::UnsupportedTransferEncoding => 501,
+            ParseError::HeadersTooLarge | ParseError::TooManyHeaders => 431,
+            ParseError::BodyTooLarge => 413,
+            // the client was too slow, see `ConnectionConfig::read_timeout`. A read timeout is reported as
+            // `WouldBlock` on unix and `TimedOut` on windows.
+            ParseError::Io(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => 408,
+            _ => 400,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::ConnectionClosed => write!(f, "connection closed before a request was sent"),
+            ParseError::UnexpectedEof => write!(f, "connection closed in the middle of a request"),
+            ParseError::InvalidRequestLine => write!(f, "malformed request line"),
+            ParseError::InvalidMethod => write!(f, "unknown request method"),
+            ParseError::InvalidTarget => write!(f, "malformed request target"),
+            ParseError::UnsupportedVersion => write!(f, "unsupported HTTP version"),
+            ParseError::InvalidHeader => write!(f, "malformed header line"),
+            ParseError::InvalidContentLength => write!(f, "invalid Content-Length header"),
+            ParseError::InvalidChunk => write!(f, "malformed chunked body"),
+            ParseError::UnsupportedTransferEncoding => write!(f, "unsupported Transfer-Encoding"),
+            ParseError::HeadersTooLarge => write!(f, "request headers too large"),
+            ParseError::TooManyHeaders => write!(f, "too many request headers"),
+            ParseError::BodyTooLarge => write!(f, "request body too large"),
+            ParseError::Io(err) => write!(f, "i/o error while reading request: {err}"),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => ParseError::UnexpectedEof,
+            _ => ParseError::Io(err),
+        }
+    }
+}
+
+// The longest size line of a chunk: a size in hex and maybe a few extensions, nothing legitimate comes close.
+pub(crate) const MAX_CHUNK_LINE: usize = 1024;
+
+/// How big a request may be. A client that sends more gets a 431 or a 413 instead of making us buffer it all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The request line and the headers together, line endings included. Also applies to the trailers
+    /// of a chunked body.
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+    pub max_body_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_header_bytes: 16 * 1024,
+            max_headers: 100,
+            max_body_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    /// The request target exactly as the client sent it, e.g. `/search?q=rust%20book`.
+    pub target: String,
+    /// The percent-decoded path, e.g. `/search`.
+    pub path: String,
+    /// The decoded query parameters, in the order they appear in the target.
+    pub query: Vec<(String, String)>,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// Who sent the request. Filled in by the connection, `None` for a request parsed from anywhere else.
+    pub client: Option<SocketAddr>,
+    /// Whether the request came over TLS, filled in by the connection like `client`.
+    pub secure: bool,
+}
+
+impl Request {
+    /// Reads one request from `reader`.
+    ///
+    /// Only as many bytes as the request needs are consumed, so the same reader can be used
+    /// to read the next request on the connection.
+    pub fn parse<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+        Self::parse_with_limits(reader, &Limits::default())
+    }
+
+    /// Like `parse`, with the size limits of `limits`.
+    pub fn parse_with_limits<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError> {
+        let mut request = Self::parse_head(reader, limits)?;
+        request.body = read_body(reader, &request.headers, limits)?;
+        Ok(request)
+    }
+
+    // The request line and the headers, with an empty body. The reader is left at the start of the body.
+    pub(crate) fn parse_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError> {
+        let mut budget = limits.max_header_bytes;
+        let request_line = match read_line(reader, &mut budget)? {
+            Some(line) => line,
+            None => return Err(ParseError::ConnectionClosed),
+        };
+
+        // Method SP Request-URI SP HTTP-Version
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) => (method, target, version),
+            _ => return Err(ParseError::InvalidRequestLine),
+        };
+
+        let method: Method = method.parse()?;
+        let version = match version {
+            "HTTP/1.1" => Version::Http11,
+            "HTTP/1.0" => Version::Http10,
+            _ if version.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion),
+            _ => return Err(ParseError::InvalidRequestLine),
+        };
+        let (path, query) = parse_target(target)?;
+
+        let headers = read_headers(reader, &mut budget, limits.max_headers)?;
+
+        Ok(Request {
+            method,
+            target: target.to_string(),
+            path,
+            query,
+            version,
+            headers,
+            body: Vec::new(),
+            client: None,
+            secure: false,
+        })
+    }
+
+    /// Returns the first value of the query parameter `name`.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The path as the client sent it, before percent-decoding: `/a%2Fb` stays one segment here, while
+    /// `path` can't tell it apart from `/a/b`.
+    pub fn raw_path(&self) -> &str {
+        let origin_form = origin_form(&self.target).unwrap_or(&self.target);
+        origin_form.split(['?', '#']).next().unwrap_or_default()
+    }
+}
+
+/// Reads a line terminated by CRLF (or a bare LF, which RFC 9112 allows us to accept)
+/// and returns it without the line ending.
+/// Returns `None` if the reader is at EOF before reading any byte.
+///
+/// `budget` is how many bytes may still be read, the line is taken out of it. We stop reading once it's
+/// spent, an endless line doesn't get buffered.
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Option<String>, ParseError> {
+    let mut line = Vec::new();
+    if reader.take(*budget as u64).read_until(b'\n', &mut line)? == 0 {
+        return match *budget {
+            0 => Err(ParseError::HeadersTooLarge),
+            _ => Ok(None),
+        };
+    }
+    *budget -= line.len();
+    if line.last() != Some(&b'\n') && *budget == 0 {
+        return Err(ParseError::HeadersTooLarge);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(ParseError::UnexpectedEof);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| ParseError::InvalidHeader)
+}
+
+pub(crate) fn read_headers<R: BufRead>(reader: &mut R, budget: &mut usize, max_headers: usize) -> Result<Headers, ParseError> {
+    let mut headers = Headers::new();
+
+    // the end of the headers is signaled by an empty line
+    while let Some(line) = read_line(reader, budget)? {
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if headers.len() == max_headers {
+            return Err(ParseError::TooManyHeaders);
+        }
+        let (name, value) = parse_header_line(&line)?;
+        headers.append(name, value);
+    }
+
+    Err(ParseError::UnexpectedEof)
+}
+
+fn parse_header_line(line: &str) -> Result<(&str, &str), ParseError> {
+    // a line starting with whitespace is an obsolete "folded" continuation line, which we reject
+    if line.starts_with([' ', '\t']) {
+        return Err(ParseError::InvalidHeader);
+    }
+
+    let (name, value) = line.split_once(':').ok_or(ParseError::InvalidHeader)?;
+    // no whitespace is allowed between the header name and the colon
+    if name.is_empty() || !name.bytes().all(is_token_byte) {
+        return Err(ParseError::InvalidHeader);
+    }
+
+    Ok((name, value.trim_matches([' ', '\t'])))
+}
+
+// https://www.rfc-editor.org/rfc/rfc9110#name-tokens
+fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+fn read_body<R: BufRead>(reader: &mut R, headers: &Headers, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    if headers.contains("Transfer-Encoding") {
+        // a request with both headers is a classic request smuggling vector, refuse it
+        if headers.contains("Content-Length") {
+            return Err(ParseError::InvalidContentLength);
+        }
+        // chunked has to be the last (and for us the only) encoding applied
+        let encodings: Vec<_> = headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        if encodings.len() != 1 || !encodings[0].eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::UnsupportedTransferEncoding);
+        }
+        return read_chunked_body(reader, limits);
+    }
+
+    match content_length(headers)? {
+        // refused before reading any of it
+        Some(length) if length > limits.max_body_size => Err(ParseError::BodyTooLarge),
+        Some(length) => {
+            let mut body = Vec::new();
+            reader.take(length).read_to_end(&mut body)?;
+            if body.len() as u64 != length {
+                return Err(ParseError::UnexpectedEof);
+            }
+            Ok(body)
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+pub(crate) fn content_length(headers: &Headers) -> Result<Option<u64>, ParseError> {
+    let mut length = None;
+
+    // several Content-Length headers are only fine if they all agree
+    for value in headers.get_all("Content-Length").flat_map(|value| value.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(ParseError::InvalidContentLength);
+        }
+        let value: u64 = value.parse().map_err(|_| ParseError::InvalidContentLength)?;
+        match length {
+            Some(previous) if previous != value => return Err(ParseError::InvalidContentLength),
+            _ => length = Some(value),
+        }
+    }
+
+    Ok(length)
+}
+
+// A chunked body is a sequence of chunks, each prefixed by its size in hex:
+// 5\r\nhello\r\n
+// 0\r\n
+// \r\n
+// The last chunk has a size of 0 and may be followed by trailer fields, which we read and discard.
+//
+// The size lines and the CRLFs are part of the body as it travels, they count against `max_body_size`
+// like the data. Only the trailers count like headers.
+pub(crate) fn read_chunked_body<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    // the bytes of the size lines and of the CRLFs so far
+    let mut framing = 0;
+
+    loop {
+        let line = read_chunk_line(reader, &mut framing, limits, body.len())?;
+        // chunk extensions (`;name=value`) are allowed after the size, we ignore them
+        let size = line.split(';').next().unwrap_or_default().trim();
+        if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(ParseError::InvalidChunk);
+        }
+        let size = u64::from_str_radix(size, 16).map_err(|_| ParseError::InvalidChunk)?;
+
+        if size == 0 {
+            let mut budget = limits.max_header_bytes;
+            read_headers(reader, &mut budget, limits.max_headers)?;
+            return Ok(body);
+        }
+        // the body and its framing never grow past the limit, the subtraction can't overflow
+        if size > limits.max_body_size - (body.len() + framing) as u64 {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        let start = body.len();
+        reader.take(size).read_to_end(&mut body)?;
+        if (body.len() - start) as u64 != size {
+            return Err(ParseError::UnexpectedEof);
+        }
+
+        // every chunk's data is followed by a CRLF
+        if !read_chunk_line(reader, &mut framing, limits, body.len())?.is_empty() {
+            return Err(ParseError::InvalidChunk);
+        }
+    }
+}
+
+// A line of the framing of a chunked body, at most `MAX_CHUNK_LINE` bytes long.
+fn read_chunk_line<R: BufRead>(
+    reader: &mut R,
+    framing: &mut usize,
+    limits: &Limits,
+    body: usize,
+) -> Result<String, ParseError> {
+    let mut budget = MAX_CHUNK_LINE;
+    let line = match read_line(reader, &mut budget) {
+        Ok(Some(line)) => line,
+        Ok(None) => return Err(ParseError::UnexpectedEof),
+        Err(ParseError::HeadersTooLarge) => return Err(ParseError::InvalidChunk),
+        Err(err) => return Err(err),
+    };
+    *framing += MAX_CHUNK_LINE - budget;
+    if (body + *framing) as u64 > limits.max_body_size {
+        return Err(ParseError::BodyTooLarge);
+    }
+    Ok(line)
+}
+
+// The path and query of a request target. Browsers send the "origin form" (`/path?query`), proxies may send
+// the "absolute form" (`http://host/path?query`), in which case we only keep the path and query.
+// `None` for the other forms, like `*`.
+pub(crate) fn origin_form(target: &str) -> Option<&str> {
+    if target.starts_with('/') {
+        return Some(target);
+    }
+    let rest = target.strip_prefix("http://").or_else(|| target.strip_prefix("https://"))?;
+    Some(rest.find('/').map(|index| &rest[index..]).unwrap_or("/"))
+}
+
+fn parse_target(target: &str) -> Result<(String, Vec<(String, String)>), ParseError> {
+    let origin_form = match origin_form(target) {
+        Some(origin_form) => origin_form,
+        // `OPTIONS * HTTP/1.1` asks about the server as a whole
+        None if target == "*" => return Ok((target.to_string(), Vec::new())),
+        None => return Err(ParseError::InvalidTarget),
+    };
+
+    // the fragment (`#...`) should never be sent, but if it is it's not part of the path
+    let origin_form = origin_form.split('#').next().unwrap_or_default();
+    let (path, query) = origin_form.split_once('?').unwrap_or((origin_form, ""));
+
+    let path = percent_decode(path, false).ok_or(ParseError::InvalidTarget)?;
+    let query = parse_query(query)?;
+
+    Ok((path, query))
+}
+
+/// Parses an `application/x-www-form-urlencoded` string like `q=rust+book&page=2`.
+pub fn parse_query(query: &str) -> Result<Vec<(String, String)>, ParseError> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match (percent_decode(key, true), percent_decode(value, true)) {
+                (Some(key), Some(value)) => Ok((key, value)),
+                _ => Err(ParseError::InvalidTarget),
+            }
+        })
+        .collect()
+}
+
+/// Decodes `%XX` escapes. In query strings `+` also stands for a space.
+/// Returns `None` if an escape is malformed or the result is not valid UTF-8.
+pub fn percent_decode(input: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' => {
+                let hex = bytes.get(index + 1..index + 3)?;
+                let hex = std::str::from_utf8(hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                index += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                index += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request, ParseError> {
+        Request::parse(&mut raw.as_bytes())
+    }
+
+    #[test]
+    fn parses_a_simple_get() {
+        let request = parse("GET /hello%20world?name=J%C3%BCrgen&lang=en+us HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.path, "/hello world");
+        assert_eq!(request.query_param("name"), Some("Jürgen"));
+        assert_eq!(request.query_param("lang"), Some("en us"));
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.headers.get("host"), Some("localhost"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn reads_body_by_content_length() {
+        let request = parse("POST /submit HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello, trailing bytes").unwrap();
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn reads_chunked_body() {
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nX-Trailer: yes\r\n\r\n";
+        let request = parse(raw).unwrap();
+        assert_eq!(request.body, b"hello, world");
+    }
+
+    #[test]
+    fn leaves_the_next_request_in_the_reader() {
+        let mut reader = "GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n".as_bytes();
+
+        assert_eq!(Request::parse(&mut reader).unwrap().path, "/a");
+        assert_eq!(Request::parse(&mut reader).unwrap().path, "/b");
+        assert!(matches!(Request::parse(&mut reader), Err(ParseError::ConnectionClosed)));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(matches!(parse("GET /\r\n\r\n"), Err(ParseError::InvalidRequestLine)));
+        assert!(matches!(parse("get / HTTP/1.1\r\n\r\n"), Err(ParseError::InvalidMethod)));
+        assert!(matches!(parse("GET /%zz HTTP/1.1\r\n\r\n"), Err(ParseError::InvalidTarget)));
+        assert!(matches!(parse("GET / HTTP/2.0\r\n\r\n"), Err(ParseError::UnsupportedVersion)));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nBad Header: x\r\n\r\n"), Err(ParseError::InvalidHeader)));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost: x\r\n"), Err(ParseError::UnexpectedEof)));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"),
+            Err(ParseError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab"),
+            Err(ParseError::InvalidContentLength)
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nz\r\n"),
+            Err(ParseError::InvalidChunk)
+        ));
+    }
+
+    #[test]
+    fn enforces_the_limits() {
+        let limits = Limits {
+            max_header_bytes: 64,
+            max_headers: 2,
+            max_body_size: 8,
+        };
+        let parse = |raw: &str| Request::parse_with_limits(&mut raw.as_bytes(), &limits);
+
+        assert!(parse("POST / HTTP/1.1\r\nA: 1\r\nB: 2\r\nContent-Length: 8\r\n\r\n12345678").is_err());
+        assert!(parse("POST / HTTP/1.1\r\nA: 1\r\nContent-Length: 8\r\n\r\n12345678").is_ok());
+        assert!(matches!(parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"), Err(ParseError::TooManyHeaders)));
+        // an endless header line stops at the limit, it doesn't need to end
+        let long = format!("GET / HTTP/1.1\r\nCookie: {}", "x".repeat(1000));
+        assert!(matches!(parse(&long), Err(ParseError::HeadersTooLarge)));
+        assert!(matches!(parse("POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n"), Err(ParseError::BodyTooLarge)));
+        let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n";
+        assert!(matches!(parse(chunked), Err(ParseError::BodyTooLarge)));
+        assert_eq!(ParseError::BodyTooLarge.status(), 413);
+
+        // the framing of many small chunks counts against the body, not against the headers
+        let limits = Limits::default();
+        let chunks = format!("400\r\n{}\r\n", "x".repeat(1024)).repeat(3000);
+        let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{chunks}0\r\nX-Sum: 1\r\n\r\n");
+        let request = Request::parse_with_limits(&mut raw.as_bytes(), &limits).unwrap();
+        assert_eq!(request.body.len(), 3000 * 1024);
+        let endless = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5{}\r\n", " ".repeat(2000));
+        let parsed = Request::parse_with_limits(&mut endless.as_bytes(), &limits);
+        assert!(matches!(parsed, Err(ParseError::InvalidChunk)));
+        assert_eq!(ParseError::TooManyHeaders.status(), 431);
+    }
+}
@@ -0,0 +1,203 @@
+// ===== Building HTTP/1.1 responses
+// A response has the following format:
+// HTTP-Version Status-Code Reason-Phrase CRLF
+// headers CRLF
+// CRLF
+// message-body
+
+use std::io::{self, Write};
+
+use crate::{headers::Headers, request::Method, static_files::FileBody, websocket::Upgrade};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    // a body too big to keep in memory, read from its file while it's sent. `body` is empty then.
+    pub(crate) file: Option<FileBody>,
+    // what runs on the connection after a `101 Switching Protocols`, see `websocket::accept`
+    pub(crate) upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Headers::new(),
+            body: Vec::new(),
+            file: None,
+            upgrade: None,
+        }
+    }
+
+    pub fn html(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body)
+    }
+
+    pub fn text(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body)
+    }
+
+    /// A plain text response whose body is the reason phrase, e.g. "400 Bad Request".
+    pub fn error(status: u16) -> Self {
+        Self::text(status, format!("{status} {}\n", reason_phrase(status)))
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.set(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self.file = None;
+        self
+    }
+
+    pub(crate) fn with_file(mut self, file: FileBody) -> Self {
+        self.body.clear();
+        self.file = Some(file);
+        self
+    }
+
+    /// Serializes the response. `Content-Length` is always derived from the body.
+    ///
+    /// 1xx, 204 and 304 responses never have a body, so they are sent without `Content-Length`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.write(writer, true)
+    }
+
+    /// Like `write_to`, for a request with `method`. The response to a HEAD request is the one a GET
+    /// would get, `Content-Length` included, without the body.
+    pub(crate) fn write_for<W: Write>(&self, method: Method, writer: &mut W) -> io::Result<()> {
+        self.write(writer, method != Method::Head)
+    }
+
+    /// Like `write_for`, but a body read from a file isn't written. It is returned instead, for the caller
+    /// to send a piece at a time.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn write_buffered_for<W: Write>(&self, method: Method, writer: &mut W) -> io::Result<Option<&FileBody>> {
+        let with_body = method != Method::Head;
+        self.write_buffered(writer, with_body)?;
+        Ok(self.file.as_ref().filter(|_| with_body && self.has_body()))
+    }
+
+    fn write<W: Write>(&self, writer: &mut W, with_body: bool) -> io::Result<()> {
+        self.write_buffered(writer, with_body)?;
+        if let Some(file) = self.file.as_ref().filter(|_| with_body && self.has_body()) {
+            file.write_to(writer)?;
+        }
+        writer.flush()
+    }
+
+    fn write_buffered<W: Write>(&self, writer: &mut W, with_body: bool) -> io::Result<()> {
+        // we build the head in memory so it goes out in a single write instead of one per header
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        for (name, value) in self.headers.iter() {
+            if !name.eq_ignore_ascii_case("Content-Length") {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+        }
+        if self.has_body() {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body_len()));
+        }
+        head.push_str("\r\n");
+
+        writer.write_all(head.as_bytes())?;
+        if self.has_body() && with_body {
+            writer.write_all(&self.body)?;
+        }
+        Ok(())
+    }
+
+    /// How many bytes of body `write_to` sends.
+    pub(crate) fn sent_body_len(&self) -> usize {
+        if self.has_body() {
+            usize::try_from(self.body_len()).unwrap_or(usize::MAX)
+        } else {
+            0
+        }
+    }
+
+    fn body_len(&self) -> u64 {
+        match &self.file {
+            Some(file) => file.len(),
+            None => self.body.len() as u64,
+        }
+    }
+
+    fn has_body(&self) -> bool {
+        !matches!(self.status, 100..=199 | 204 | 304)
+    }
+}
+
+// https://www.rfc-editor.org/rfc/rfc9110#name-status-codes
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_status_line_headers_and_body() {
+        let response = Response::html(200, "<h1>Hi</h1>").with_header("X-Test", "yes");
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nX-Test: yes\r\nContent-Length: 11\r\n\r\n<h1>Hi</h1>"
+        );
+    }
+
+    #[test]
+    fn not_modified_has_no_body() {
+        let mut output = Vec::new();
+        Response::new(304).with_header("ETag", "\"1\"").write_to(&mut output).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "HTTP/1.1 304 Not Modified\r\nETag: \"1\"\r\n\r\n");
+    }
+}
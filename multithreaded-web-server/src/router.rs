@@ -0,0 +1,328 @@
+// ===== Routing requests to handlers
+// Handlers are registered for a method and a path pattern. A pattern is made of segments separated by `/`:
+// - `/posts`       a literal segment only matches itself
+// - `/posts/:id`   a `:name` segment matches any single non-empty segment and captures it as `name`
+// - `/static/*path` a `*name` segment matches the rest of the path (possibly empty) and must come last
+//
+// Routes are tried in the order they were registered, the first one whose pattern and method match wins.
+// A HEAD request without a HEAD route of its own goes to the GET route, every resource that supports GET
+// has to support HEAD. The connection sends the headers of the response and leaves out its body.
+//
+// Patterns are matched against the path as it was sent, and each segment is percent-decoded on its own:
+// `/files/a%2Fb` is the single segment `a/b`, it doesn't match `/files/:dir/:name`.
+
+use std::collections::BTreeSet;
+
+use crate::{
+    request::{percent_decode, Method},
+    Request, Response,
+};
+
+/// Anything that can turn a request into a response.
+///
+/// Handlers are shared between the pool's worker threads, which is why they need to be `Send + Sync`.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+/// The values captured by `:name` and `*name` segments of a route pattern.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    entries: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+type RouteHandler = Box<dyn Fn(&Request, &Params) -> Response + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: RouteHandler,
+}
+
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Box<dyn Handler>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            not_found: Box::new(|_: &Request| Response::error(404)),
+        }
+    }
+
+    /// Registers `handler` for requests with the given method whose path matches `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pattern doesn't start with `/`, has an unnamed `:` or `*` segment,
+    /// or has a `*` segment that isn't the last one.
+    /// Patterns are written by the programmer, so a bad one is a bug rather than a runtime error.
+    pub fn route<F>(mut self, method: Method, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            segments: parse_pattern(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Sets the handler used when no route matches the path. Defaults to a plain `404 Not Found`.
+    pub fn not_found(mut self, handler: impl Handler) -> Self {
+        self.not_found = Box::new(handler);
+        self
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, request: &Request) -> Response {
+        // the methods of every route whose pattern matched, for the `Allow` header of a 405
+        let mut allowed = BTreeSet::new();
+        let mut get = None;
+
+        for route in &self.routes {
+            if let Some(params) = match_segments(&route.segments, request.raw_path()) {
+                if route.method == request.method {
+                    return (route.handler)(request, &params);
+                }
+                if route.method == Method::Get {
+                    allowed.insert(Method::Head.as_str());
+                    get = get.or(Some((route, params)));
+                }
+                allowed.insert(route.method.as_str());
+            }
+        }
+
+        if let (Method::Head, Some((route, params))) = (request.method, get) {
+            return (route.handler)(request, &params);
+        }
+        if allowed.is_empty() {
+            return self.not_found.handle(request);
+        }
+
+        let allow = allowed.into_iter().collect::<Vec<_>>().join(", ");
+        Response::error(405).with_header("Allow", allow)
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let rest = pattern
+        .strip_prefix('/')
+        .unwrap_or_else(|| panic!("route pattern {pattern:?} must start with '/'"));
+    if rest.is_empty() {
+        return Vec::new();
+    }
+
+    let segments: Vec<Segment> = rest
+        .split('/')
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix(':') {
+                assert!(!name.is_empty(), "unnamed parameter in route pattern {pattern:?}");
+                Segment::Param(name.to_string())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                assert!(!name.is_empty(), "unnamed wildcard in route pattern {pattern:?}");
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Literal(segment.to_string())
+            }
+        })
+        .collect();
+
+    let wildcard_in_the_middle = segments[..segments.len() - 1]
+        .iter()
+        .any(|segment| matches!(segment, Segment::Wildcard(_)));
+    assert!(!wildcard_in_the_middle, "wildcard must be the last segment of route pattern {pattern:?}");
+
+    segments
+}
+
+// Matches the raw (not yet percent-decoded) `path`.
+fn match_segments(segments: &[Segment], path: &str) -> Option<Params> {
+    let rest = path.strip_prefix('/')?;
+    // a `%` sequence never spans a `/`, each segment decodes on its own
+    let parts: Vec<String> = match rest {
+        "" => Vec::new(),
+        rest => rest.split('/').map(|part| percent_decode(part, false)).collect::<Option<_>>()?,
+    };
+    let mut params = Params::default();
+
+    for (index, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Wildcard(name) => {
+                let captured = parts.get(index..).unwrap_or_default().join("/");
+                params.entries.push((name.clone(), captured));
+                return Some(params);
+            }
+            Segment::Literal(literal) => {
+                if parts.get(index) != Some(literal) {
+                    return None;
+                }
+            }
+            Segment::Param(name) => match parts.get(index) {
+                Some(value) if !value.is_empty() => params.entries.push((name.clone(), value.clone())),
+                _ => return None,
+            },
+        }
+    }
+
+    // every segment of the path has to be consumed by the pattern
+    (parts.len() == segments.len()).then_some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{method} {target} HTTP/1.1\r\n\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/", |_, _| Response::text(200, "home"))
+            .get("/posts/:id", |_, params| Response::text(200, format!("get {}", params.get("id").unwrap())))
+            .delete("/posts/:id", |_, params| Response::text(200, format!("delete {}", params.get("id").unwrap())))
+            .get("/static/*path", |_, params| Response::text(200, format!("file {}", params.get("path").unwrap())))
+    }
+
+    #[test]
+    fn dispatches_on_method_and_path() {
+        let router = router();
+
+        assert_eq!(router.handle(&request("GET", "/")).body, b"home");
+        assert_eq!(router.handle(&request("GET", "/posts/42")).body, b"get 42");
+        assert_eq!(router.handle(&request("DELETE", "/posts/42")).body, b"delete 42");
+    }
+
+    #[test]
+    fn captures_the_rest_of_the_path() {
+        let router = router();
+
+        assert_eq!(router.handle(&request("GET", "/static/css/site.css")).body, b"file css/site.css");
+        assert_eq!(router.handle(&request("GET", "/static")).body, b"file ");
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let router = router();
+
+        assert_eq!(router.handle(&request("GET", "/posts")).status, 404);
+        assert_eq!(router.handle(&request("GET", "/posts/42/comments")).status, 404);
+        assert_eq!(router.handle(&request("GET", "/posts/")).status, 404);
+    }
+
+    #[test]
+    fn wrong_method_is_not_allowed() {
+        let response = router().handle(&request("POST", "/posts/42"));
+
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers.get("Allow"), Some("DELETE, GET, HEAD"));
+    }
+
+    #[test]
+    fn head_goes_to_the_get_route() {
+        let router = router().route(Method::Head, "/", |_, _| Response::text(200, "head"));
+
+        let response = router.handle(&request("HEAD", "/posts/42"));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"get 42");
+        // a HEAD route of its own comes first
+        assert_eq!(router.handle(&request("HEAD", "/")).body, b"head");
+
+        // the connection leaves the body out, the length stays
+        let mut written = Vec::new();
+        response.write_for(Method::Head, &mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains("Content-Length: 6\r\n"));
+        assert!(written.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn matches_the_path_before_decoding_it() {
+        let router = router().get("/files/:dir/:name", |_, params| {
+            Response::text(200, format!("{} {}", params.get("dir").unwrap(), params.get("name").unwrap()))
+        });
+
+        assert_eq!(router.handle(&request("GET", "/files/a/b")).body, b"a b");
+        assert_eq!(router.handle(&request("GET", "/files/a%2Fb")).status, 404);
+        assert_eq!(router.handle(&request("GET", "/posts/a%2Fb")).body, b"get a/b");
+        assert_eq!(router.handle(&request("GET", "/files/caf%C3%A9/x%20y")).body, "café x y".as_bytes());
+        assert_eq!(router.handle(&request("GET", "http://example.com/posts/7?x=1")).body, b"get 7");
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard must be the last segment")]
+    fn wildcard_must_be_last() {
+        Router::new().get("/*path/edit", |_, _| Response::new(200));
+    }
+}
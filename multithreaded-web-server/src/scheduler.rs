@@ -0,0 +1,234 @@
+// ===== Job queue
+// Every worker takes its jobs from the same queue, behind one mutex: a worker with nothing to do waits
+// on `job_submitted` until a job is submitted, the pool closes or its idle timeout runs out.
+//
+// Per-worker deques with work stealing were tried in place of this, to take the lock out of the way of
+// many short jobs on several cores. They were never shown to be faster than this queue (the only
+// measurement, on a single core, had them at 0.7x to 1.0x of it), so the simpler design stays.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::Job;
+
+pub(crate) struct Scheduler {
+    queue: Mutex<Queue>,
+    job_submitted: Condvar,
+    // `None` for an unbounded queue
+    capacity: Option<usize>,
+    // jobs submitted that no worker took yet, including the ones reserved but not submitted yet
+    queued: AtomicUsize,
+    // workers that aren't running a job
+    idle: AtomicUsize,
+    // where submitters wait for room in a full queue
+    blocked: AtomicUsize,
+    room: Mutex<()>,
+    room_made: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: VecDeque<Job>,
+    closed: bool,
+}
+
+/// What a worker gets when it asks for its next job.
+pub(crate) enum Next {
+    Job(Job),
+    /// Nothing to do for a whole idle timeout.
+    TimedOut,
+    /// The pool shut down and every job has been taken.
+    Closed,
+}
+
+impl Scheduler {
+    pub(crate) fn new(capacity: Option<usize>) -> Self {
+        Self {
+            queue: Mutex::new(Queue::default()),
+            job_submitted: Condvar::new(),
+            capacity,
+            queued: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            blocked: AtomicUsize::new(0),
+            room: Mutex::new(()),
+            room_made: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn idle(&self) -> usize {
+        self.idle.load(Ordering::SeqCst)
+    }
+
+    /// Makes room for a job in the queue, false if it's full.
+    ///
+    /// A full queue holds `capacity` jobs more than there are idle workers to take them,
+    /// so with a capacity of 0 a job is only accepted if a worker is free to run it.
+    pub(crate) fn try_reserve(&self) -> bool {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => {
+                self.queued.fetch_add(1, Ordering::SeqCst);
+                return true;
+            }
+        };
+        self.queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < capacity + self.idle()).then_some(queued + 1)
+            })
+            .is_ok()
+    }
+
+    /// Like `try_reserve`, but waits for room instead of giving up.
+    pub(crate) fn reserve(&self) {
+        if self.try_reserve() {
+            return;
+        }
+        let mut room = self.room.lock().unwrap();
+        self.blocked.fetch_add(1, Ordering::SeqCst);
+        // checked under the lock: a worker making room either sees us blocked and waits for the lock
+        // to wake us up, or made room before we checked
+        while !self.try_reserve() {
+            room = self.room_made.wait(room).unwrap();
+        }
+        self.blocked.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Queues a job that `try_reserve` or `reserve` made room for.
+    pub(crate) fn submit(&self, job: Job) {
+        self.queue.lock().unwrap().jobs.push_back(job);
+        self.job_submitted.notify_one();
+    }
+
+    /// Counts a new worker as idle, before its thread starts so the pool can hand it jobs right away.
+    pub(crate) fn add_worker(&self) {
+        self.idle.fetch_add(1, Ordering::SeqCst);
+        self.made_room();
+    }
+
+    /// Undoes `add_worker`, when the worker's thread exits or couldn't be spawned.
+    pub(crate) fn remove_worker(&self) {
+        self.idle.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Waits up to `idle_timeout` for a job.
+    pub(crate) fn next(&self, idle_timeout: Duration) -> Next {
+        let deadline = Instant::now() + idle_timeout;
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some(job) = queue.jobs.pop_front() {
+                drop(queue);
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                self.made_room();
+                return Next::Job(job);
+            }
+            // nothing can be submitted once we are closed (the pool submits under the lock it closes
+            // with), a job that was reserved but never submitted can't keep the worker around
+            if queue.closed {
+                return Next::Closed;
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            // a job reserved but not submitted yet doesn't count as nothing to do, it will wake us up
+            if timeout.is_zero() && self.queued() == 0 {
+                return Next::TimedOut;
+            }
+            let wait = if timeout.is_zero() { idle_timeout } else { timeout };
+            queue = self.job_submitted.wait_timeout(queue, wait).unwrap().0;
+        }
+    }
+
+    /// The worker finished its job and is idle again.
+    pub(crate) fn job_done(&self) {
+        self.idle.fetch_add(1, Ordering::SeqCst);
+        self.made_room();
+    }
+
+    /// Stops the workers once every job is taken. Submitting after closing is the pool's job to prevent.
+    pub(crate) fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.job_submitted.notify_all();
+    }
+
+    // Wakes up the submitters waiting in `reserve`, the number of queued or idle workers changed.
+    fn made_room(&self) {
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            let _room = self.room.lock().unwrap();
+            self.room_made.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn runs_jobs_in_the_order_they_were_submitted() {
+        let scheduler = Scheduler::new(None);
+        scheduler.add_worker();
+        let (sender, receiver) = mpsc::channel();
+        for n in 0..3 {
+            let sender = sender.clone();
+            assert!(scheduler.try_reserve());
+            scheduler.submit(Box::new(move || sender.send(n).unwrap()));
+        }
+
+        for _ in 0..3 {
+            match scheduler.next(Duration::from_secs(1)) {
+                Next::Job(job) => job(),
+                _ => panic!("expected a job"),
+            }
+            scheduler.job_done();
+        }
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [0, 1, 2]);
+        assert!(matches!(scheduler.next(Duration::from_millis(10)), Next::TimedOut));
+    }
+
+    #[test]
+    fn wakes_up_a_waiting_worker() {
+        let scheduler = Arc::new(Scheduler::new(None));
+        scheduler.add_worker();
+        let worker = Arc::clone(&scheduler);
+        let waiting = thread::spawn(move || matches!(worker.next(Duration::from_secs(10)), Next::Job(_)));
+
+        assert!(scheduler.try_reserve());
+        scheduler.submit(Box::new(|| {}));
+        assert!(waiting.join().unwrap());
+    }
+
+    #[test]
+    fn stops_once_closed_and_empty() {
+        let scheduler = Scheduler::new(None);
+        scheduler.add_worker();
+        // a job counted but never pushed, the counter can't keep the worker around
+        assert!(scheduler.try_reserve());
+        scheduler.close();
+        assert!(matches!(scheduler.next(Duration::from_secs(1)), Next::Closed));
+    }
+
+    #[test]
+    fn a_full_queue_counts_idle_workers() {
+        let scheduler = Scheduler::new(Some(1));
+        scheduler.add_worker();
+
+        // one job for the idle worker, one waiting in the queue
+        assert!(scheduler.try_reserve());
+        assert!(scheduler.try_reserve());
+        assert!(!scheduler.try_reserve());
+    }
+}
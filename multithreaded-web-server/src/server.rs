@@ -0,0 +1,785 @@
+// ===== The server
+// Ties everything together: accepts connections on one or more listeners and hands each one to the
+// thread pool, until it's told to shut down. A listener speaks plain HTTP or HTTPS.
+//
+// How connections are served depends on the I/O mode:
+// - blocking: every connection is a job that keeps a worker until the connection closes
+// - epoll (Linux only): event loops own the plain HTTP connections and only send complete requests to
+//   the pool, see `event_loop`. HTTPS connections are still served in the blocking way.
+
+use std::{
+    io::{self, Read},
+    net::{self, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(target_os = "linux")]
+use crate::event_loop::{Context, EventLoop, LoopHandle, Message};
+use crate::{
+    connection::{self, handle_connection, handle_tls_connection, ConnectionConfig},
+    middleware::{Chain, Middleware},
+    ExecuteError, Handler, HttpsRedirect, Metrics, Response, Shutdown, ThreadPool, TlsConfig,
+};
+
+/// How the server waits for its connections, see the top of this file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IoMode {
+    #[default]
+    Blocking,
+    /// `threads` event loops share the connections. Only available on Linux.
+    Epoll { threads: usize },
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub connection: ConnectionConfig,
+    /// How long the requests that are being handled get to finish once a shutdown starts.
+    pub shutdown_timeout: Duration,
+    /// What the `Retry-After` header of a 503 tells a client we turned away because the pool's queue was full.
+    pub retry_after: Duration,
+    pub io: IoMode,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            connection: ConnectionConfig::default(),
+            shutdown_timeout: Duration::from_secs(30),
+            retry_after: Duration::from_secs(1),
+            io: IoMode::Blocking,
+        }
+    }
+}
+
+/// A socket to accept connections on, and whether they are HTTPS. A `TcpListener` is a plain HTTP listener.
+#[derive(Debug)]
+pub struct Listener {
+    socket: TcpListener,
+    tls: Option<TlsConfig>,
+}
+
+impl Listener {
+    pub fn plain(socket: TcpListener) -> Self {
+        Self { socket, tls: None }
+    }
+
+    pub fn tls(socket: TcpListener, tls: TlsConfig) -> Self {
+        Self { socket, tls: Some(tls) }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(socket: TcpListener) -> Self {
+        Self::plain(socket)
+    }
+}
+
+// Takes a connection away from the accept loop, or gives it back.
+type HandOff<'a> = dyn Fn(TcpStream, &Arc<dyn Handler>) -> Option<TcpStream> + Sync + 'a;
+
+pub struct Server {
+    listeners: Vec<Listener>,
+    pool: ThreadPool,
+    handler: Arc<dyn Handler>,
+    // wrapped around `handler` when the server starts, outermost first
+    middleware: Vec<Arc<dyn Middleware>>,
+    // what the plain listeners answer instead of `handler`, in redirect mode
+    redirect: Option<Arc<dyn Handler>>,
+    config: Arc<ServerConfig>,
+}
+
+impl Server {
+    pub fn new(listener: impl Into<Listener>, pool: ThreadPool, handler: impl Handler, config: ServerConfig) -> Self {
+        Self {
+            listeners: vec![listener.into()],
+            pool,
+            // the handler is shared by every worker, Arc lets each job hold a reference to it
+            handler: Arc::new(handler),
+            middleware: Vec::new(),
+            redirect: None,
+            config: Arc::new(config),
+        }
+    }
+
+    /// Records every request in `metrics`, along with the gauges of the thread pool.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        metrics.watch_pool(self.pool.monitor());
+        Arc::make_mut(&mut self.config).connection.metrics = Some(metrics);
+        self
+    }
+
+    /// Also accepts connections on `listener`, to listen on several addresses or ports.
+    pub fn with_listener(mut self, listener: impl Into<Listener>) -> Self {
+        self.listeners.push(listener.into());
+        self
+    }
+
+    /// Runs every request through `middleware` before the handler. Middlewares added first run first,
+    /// and see the response last. The HTTPS redirect of `redirect_to_https` doesn't go through them.
+    pub fn with_middleware(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Answers every request on the plain HTTP listeners with a redirect to the HTTPS server on `https_port`,
+    /// only the HTTPS listeners serve the handler.
+    pub fn redirect_to_https(mut self, https_port: u16) -> Self {
+        self.redirect = Some(Arc::new(HttpsRedirect::new(https_port)));
+        self
+    }
+
+    /// Serves connections until `shutdown` is triggered, then waits for the connections that are
+    /// still being handled, up to `shutdown_timeout`.
+    ///
+    /// Returns true if every connection finished before the deadline.
+    pub fn run(mut self, shutdown: &Shutdown) -> io::Result<bool> {
+        if !self.middleware.is_empty() {
+            let chain = self.middleware.drain(..).fold(Chain::from_arc(self.handler), Chain::with_arc);
+            self.handler = Arc::new(chain);
+        }
+        for listener in &self.listeners {
+            shutdown.register_listener(listener.socket.local_addr()?);
+        }
+
+        match self.config.io {
+            IoMode::Blocking => {
+                self.accept_all(shutdown, &|stream, _| Some(stream))?;
+                Ok(self.pool.shutdown_timeout(self.config.shutdown_timeout))
+            }
+            IoMode::Epoll { threads } => self.run_event_loops(shutdown, threads),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn run_event_loops(&self, shutdown: &Shutdown, threads: usize) -> io::Result<bool> {
+        let loops = (0..threads.max(1)).map(|_| EventLoop::new()).collect::<io::Result<Vec<_>>>()?;
+        let handles: Vec<LoopHandle> = loops.iter().map(EventLoop::handle).collect();
+        let context = Context {
+            pool: &self.pool,
+            config: &self.config,
+            shutdown,
+        };
+        // the loops take turns, so the connections are spread evenly between them
+        let next = AtomicUsize::new(0);
+        let hand_off = |stream, handler: &Arc<dyn Handler>| {
+            let message = Message::Connection(stream, Arc::clone(handler));
+            handles[next.fetch_add(1, Ordering::Relaxed) % handles.len()].send(message);
+            None
+        };
+
+        let (accepted, deadline, drained) = thread::scope(|scope| {
+            let context = &context;
+            let mut threads = Vec::new();
+            let mut spawn_error = None;
+            for (index, event_loop) in loops.into_iter().enumerate() {
+                let spawned = thread::Builder::new()
+                    .name(format!("event-loop-{index}"))
+                    .spawn_scoped(scope, move || event_loop.run(context));
+                match spawned {
+                    Ok(thread) => threads.push(thread),
+                    Err(err) => {
+                        spawn_error = Some(err);
+                        break;
+                    }
+                }
+            }
+            let accepted = match spawn_error {
+                None => self.accept_all(shutdown, &hand_off),
+                Some(err) => {
+                    shutdown.trigger();
+                    Err(err)
+                }
+            };
+
+            // the listeners are closed, the loops finish the requests they have
+            let deadline = Instant::now() + self.config.shutdown_timeout;
+            for handle in &handles {
+                handle.send(Message::Drain { deadline });
+            }
+            let drained: Vec<bool> = threads.into_iter().map(|thread| thread.join().unwrap_or(false)).collect();
+            (accepted, deadline, drained.into_iter().all(|drained| drained))
+        });
+
+        accepted?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        Ok(self.pool.shutdown_timeout(remaining) && drained)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn run_event_loops(&self, shutdown: &Shutdown, _: usize) -> io::Result<bool> {
+        shutdown.trigger();
+        Err(io::Error::new(io::ErrorKind::Unsupported, "the epoll I/O mode is only available on Linux"))
+    }
+
+    // Accepts connections on every listener until the shutdown. Plain HTTP connections go to `hand_off`
+    // first, with the handler of their listener, which gives them back if they should be served by the pool.
+    fn accept_all(&self, shutdown: &Shutdown, hand_off: &HandOff<'_>) -> io::Result<()> {
+        // every listener gets its own thread blocked in `accept`, scoped threads can borrow `self`
+        thread::scope(|scope| {
+            for (index, listener) in self.listeners.iter().enumerate().skip(1) {
+                let spawned = thread::Builder::new()
+                    .name(format!("accept-{index}"))
+                    .spawn_scoped(scope, || self.accept(listener, shutdown, hand_off));
+                // the scope waits for the threads that did start, they only return once we shut down
+                if let Err(err) = spawned {
+                    shutdown.trigger();
+                    return Err(err);
+                }
+            }
+            self.accept(&self.listeners[0], shutdown, hand_off);
+            Ok(())
+        })
+    }
+
+    fn accept(&self, listener: &Listener, shutdown: &Shutdown, hand_off: &HandOff<'_>) {
+        let handler = match (&listener.tls, &self.redirect) {
+            (None, Some(redirect)) => redirect,
+            _ => &self.handler,
+        };
+
+        // incoming gives an iterator over a sequence of streams.
+        // a single stream represents an open connection between the client and the server.
+        // we are actually iterating over connection
+        for stream in listener.socket.incoming() {
+            // checked after `accept` returns, `Shutdown::trigger` wakes us up by connecting to the listener
+            if shutdown.is_triggered() {
+                break;
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                // accepting can fail because of the client (it reset the connection before we got to it)
+                // or because we are out of file descriptors, neither is a reason to stop serving
+                Err(err) => {
+                    eprintln!("Failed to accept a connection: {err}");
+                    continue;
+                }
+            };
+
+            let stream = match listener.tls {
+                None => match hand_off(stream, handler) {
+                    Some(stream) => stream,
+                    None => continue,
+                },
+                Some(_) => stream,
+            };
+
+            // the job takes the stream with it, even when it's rejected, so keep a handle to answer with a 503
+            let rejected = stream.try_clone();
+            let tls = listener.tls.clone();
+            let handler = Arc::clone(handler);
+            let config = Arc::clone(&self.config);
+            let shutdown = shutdown.clone();
+
+            // the connection keeps this worker busy until the client is done with it or goes idle
+            let submitted = self.pool.execute(move || match tls {
+                Some(tls) => handle_tls_connection(stream, &tls, handler.as_ref(), &config.connection, &shutdown),
+                None => handle_connection(stream, handler.as_ref(), &config.connection, &shutdown),
+            });
+            match (submitted, rejected) {
+                (Ok(()), _) => {}
+                // answering needs a TLS handshake, which is too slow for the accept loop. The client sees
+                // the connection closed and can try again.
+                (Err(ExecuteError::QueueFull), _) if listener.tls.is_some() => {}
+                // with a fail-fast pool a full queue means we are overloaded, better to tell the client
+                // right away than to let it wait behind everybody else
+                (Err(ExecuteError::QueueFull), Ok(stream)) => {
+                    let started = Instant::now();
+                    let client = stream.peer_addr().ok();
+                    let response = Response::error(503)
+                        .with_header("Retry-After", self.config.retry_after.as_secs().max(1).to_string())
+                        .with_header("Connection", "close");
+                    turn_away(stream, &response);
+                    connection::record(&self.config.connection, client, None, &response, started.elapsed());
+                }
+                // otherwise the job was dropped with the stream, which closes the connection
+                (Err(err), _) => eprintln!("Dropping a connection: {err}"),
+            }
+            // connection is closed as part of the drop implementation
+        }
+    }
+}
+
+// What `turn_away` reads at most of a request before closing the connection, its head and a small body.
+const TURN_AWAY_DRAIN: usize = 64 * 1024;
+
+/// Answers a connection we don't have a worker for with a `503 Service Unavailable` response and closes it.
+///
+/// This runs on the accept loop, so it must not wait on a slow client for long.
+fn turn_away(stream: TcpStream, response: &Response) {
+    let _ = stream.set_write_timeout(Some(Duration::from_millis(100)));
+    let written = response.write_to(&mut &stream);
+    if written.is_err() {
+        return;
+    }
+
+    // closing a socket with unread data makes the kernel reset the connection, and a reset can make the
+    // client throw away our response before reading it. Discard what the request sent so far first, up to
+    // `TURN_AWAY_DRAIN` bytes: a client that keeps sending would keep us from accepting anyone else.
+    let _ = stream.shutdown(net::Shutdown::Write);
+    if stream.set_nonblocking(true).is_ok() {
+        let mut buffer = [0; 1024];
+        let mut drained = 0;
+        while drained < TURN_AWAY_DRAIN {
+            match (&stream).read(&mut buffer) {
+                Ok(read) if read > 0 => drained += read,
+                _ => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::TcpStream,
+        thread,
+    };
+
+    use super::*;
+    use crate::{Limits, Request, Response};
+
+    fn start(handler: impl Handler, shutdown_timeout: Duration) -> (std::net::SocketAddr, Shutdown, thread::JoinHandle<bool>) {
+        start_with_pool(handler, shutdown_timeout, ThreadPool::new(2))
+    }
+
+    fn start_with_pool(
+        handler: impl Handler,
+        shutdown_timeout: Duration,
+        pool: ThreadPool,
+    ) -> (std::net::SocketAddr, Shutdown, thread::JoinHandle<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            shutdown_timeout,
+            ..ServerConfig::default()
+        };
+        let server = Server::new(listener, pool, handler, config);
+        let shutdown = Shutdown::new();
+        let running = shutdown.clone();
+
+        (address, shutdown, thread::spawn(move || server.run(&running).unwrap()))
+    }
+
+    #[test]
+    fn closes_idle_connections_and_drains_in_flight_requests() {
+        let (address, shutdown, server) = start(
+            |request: &Request| {
+                if request.path == "/slow" {
+                    thread::sleep(Duration::from_millis(300));
+                }
+                Response::text(200, request.path.clone())
+            },
+            Duration::from_secs(5),
+        );
+
+        // a keep-alive connection that has been answered and is now waiting for its next request
+        let mut idle = TcpStream::connect(address).unwrap();
+        idle.write_all(b"GET /idle HTTP/1.1\r\n\r\n").unwrap();
+        let mut buffer = [0; 512];
+        assert!(idle.read(&mut buffer).unwrap() > 0);
+
+        let mut slow = TcpStream::connect(address).unwrap();
+        slow.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        shutdown.trigger();
+
+        let mut response = String::new();
+        slow.read_to_string(&mut response).unwrap();
+        assert!(response.contains("Connection: close"));
+        assert!(response.ends_with("/slow"));
+
+        // the idle connection was closed without waiting for the idle timeout
+        assert_eq!(idle.read(&mut buffer).unwrap(), 0);
+        assert!(server.join().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn serves_every_listener() {
+        let first = TcpListener::bind("127.0.0.1:0").unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addresses = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+        let handler = |request: &Request| Response::text(200, request.path.clone());
+        let server = Server::new(first, ThreadPool::new(2), handler, ServerConfig::default()).with_listener(second);
+        let shutdown = Shutdown::new();
+        let running = shutdown.clone();
+        let server = thread::spawn(move || server.run(&running).unwrap());
+
+        for address in addresses {
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(b"GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.ends_with("/hello"));
+        }
+
+        shutdown.trigger();
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn runs_the_middleware_around_the_handler() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handler = |request: &Request| Response::text(200, request.path.clone());
+        let server = Server::new(listener, ThreadPool::new(2), handler, ServerConfig::default())
+            .with_middleware(|request: &Request, next: crate::middleware::Next<'_>| {
+                next.run(request).with_header("X-Layer", "outer")
+            })
+            .with_middleware(|request: &Request, next: crate::middleware::Next<'_>| {
+                let mut rewritten = request.clone();
+                rewritten.path = format!("/v1{}", request.path);
+                next.run(&rewritten).with_header("X-Layer", "inner")
+            });
+        let shutdown = Shutdown::new();
+        let running = shutdown.clone();
+        let server = thread::spawn(move || server.run(&running).unwrap());
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        // the outer one has the last word
+        assert!(response.contains("X-Layer: outer\r\n"));
+        assert!(response.ends_with("/v1/hello"));
+
+        shutdown.trigger();
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn serves_https_and_redirects_plain_http() {
+        use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+        let generated = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let tls = TlsConfig::from_pem(generated.cert.pem().as_bytes(), generated.key_pair.serialize_pem().as_bytes());
+        let plain = TcpListener::bind("127.0.0.1:0").unwrap();
+        let secure = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (plain_address, secure_address) = (plain.local_addr().unwrap(), secure.local_addr().unwrap());
+        let handler = |request: &Request| Response::text(200, request.path.clone());
+        let server = Server::new(plain, ThreadPool::new(2), handler, ServerConfig::default())
+            .with_listener(Listener::tls(secure, tls.unwrap()))
+            .redirect_to_https(secure_address.port());
+        let shutdown = Shutdown::new();
+        let running = shutdown.clone();
+        let server = thread::spawn(move || server.run(&running).unwrap());
+
+        let mut client = TcpStream::connect(plain_address).unwrap();
+        client.write_all(b"GET /hello?x=1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 308 Permanent Redirect"));
+        let location = format!("Location: https://localhost:{}/hello?x=1\r\n", secure_address.port());
+        assert!(response.contains(&location));
+
+        // a client that trusts our self-signed certificate, and only that one
+        let mut roots = RootCertStore::empty();
+        roots.add(generated.cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let session = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut client = StreamOwned::new(session, TcpStream::connect(secure_address).unwrap());
+        client.write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("/hello"));
+
+        shutdown.trigger();
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn reports_requests_that_miss_the_deadline() {
+        let (address, shutdown, server) = start(
+            |_: &Request| {
+                thread::sleep(Duration::from_secs(2));
+                Response::new(200)
+            },
+            Duration::from_millis(100),
+        );
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        shutdown.trigger();
+        assert!(!server.join().unwrap());
+    }
+
+    #[test]
+    fn answers_503_when_the_queue_is_full() {
+        let pool = ThreadPool::builder(1)
+            .queue_capacity(0)
+            .queue_policy(crate::QueuePolicy::FailFast)
+            .build()
+            .unwrap();
+        let (address, shutdown, server) = start_with_pool(
+            |_: &Request| {
+                thread::sleep(Duration::from_millis(300));
+                Response::new(200)
+            },
+            Duration::from_secs(5),
+            pool,
+        );
+
+        // keeps the only worker busy, once it's waiting for a job
+        thread::sleep(Duration::from_millis(50));
+        let mut busy = TcpStream::connect(address).unwrap();
+        busy.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+        assert!(response.contains("Retry-After: 1\r\n"));
+
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        shutdown.trigger();
+        assert!(server.join().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    fn start_epoll(
+        handler: impl Handler,
+        connection: ConnectionConfig,
+    ) -> (std::net::SocketAddr, Shutdown, thread::JoinHandle<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            connection,
+            io: IoMode::Epoll { threads: 2 },
+            ..ServerConfig::default()
+        };
+        // a single worker, the connections must not need one each
+        let server = Server::new(listener, ThreadPool::new(1), handler, config);
+        let shutdown = Shutdown::new();
+        let running = shutdown.clone();
+
+        (address, shutdown, thread::spawn(move || server.run(&running).unwrap()))
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn epoll_redirects_plain_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            io: IoMode::Epoll { threads: 1 },
+            ..ServerConfig::default()
+        };
+        let handler = |request: &Request| Response::text(200, request.path.clone());
+        let server = Server::new(listener, ThreadPool::new(1), handler, config).redirect_to_https(8443);
+        let shutdown = Shutdown::new();
+        let running = shutdown.clone();
+        let server = thread::spawn(move || server.run(&running).unwrap());
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /hello?x=1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 308 Permanent Redirect"));
+        assert!(response.contains("Location: https://localhost:8443/hello?x=1\r\n"));
+
+        shutdown.trigger();
+        assert!(server.join().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn epoll_streams_big_files() {
+        let root = std::env::temp_dir().join(format!("epoll-stream-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let contents: Vec<u8> = (0..300_000).map(|n| (n % 251) as u8).collect();
+        fs::write(root.join("big.bin"), &contents).unwrap();
+        let files = crate::StaticFiles::new(&root).max_buffered(1024);
+        let (address, shutdown, server) = start_epoll(files, ConnectionConfig::default());
+
+        // the next response on the connection starts right after the streamed body
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /big.bin HTTP/1.1\r\n\r\n").unwrap();
+        client.write_all(b"GET /big.bin HTTP/1.1\r\nRange: bytes=0-9\r\nConnection: close\r\n\r\n").unwrap();
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).unwrap();
+        let head = b"Content-Length: 300000\r\n\r\n";
+        let start = output.windows(head.len()).position(|window| window == head).unwrap() + head.len();
+        assert_eq!(&output[start..start + contents.len()], contents);
+        let next = &output[start + contents.len()..];
+        assert!(next.starts_with(b"HTTP/1.1 206 Partial Content\r\n"));
+        assert!(next.ends_with(&contents[..10]));
+
+        shutdown.trigger();
+        assert!(server.join().unwrap());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn epoll_serves_more_connections_than_workers() {
+        let handler = |request: &Request| Response::text(200, request.path.clone());
+        let (address, shutdown, server) = start_epoll(handler, ConnectionConfig::default());
+
+        // keep-alive connections that stay open without a request, each would hold the only worker
+        // in the blocking mode
+        let mut idle: Vec<_> = (0..4).map(|_| TcpStream::connect(address).unwrap()).collect();
+        for client in &mut idle {
+            client.write_all(b"GET /idle HTTP/1.1\r\n\r\n").unwrap();
+            let mut buffer = [0; 512];
+            assert!(client.read(&mut buffer).unwrap() > 0);
+        }
+
+        // two pipelined requests, answered in order
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /one HTTP/1.1\r\n\r\nGET /two HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        let one = response.find("/one").unwrap();
+        assert!(response[one..].starts_with("/oneHTTP/1.1 200 OK"));
+        assert!(response.ends_with("/two"));
+
+        shutdown.trigger();
+        assert!(server.join().unwrap());
+        // the idle connections were closed by the shutdown
+        let mut buffer = [0; 512];
+        assert_eq!(idle[0].read(&mut buffer).unwrap(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn epoll_drains_in_flight_requests() {
+        let slow = |request: &Request| {
+            thread::sleep(Duration::from_millis(300));
+            Response::text(200, request.path.clone())
+        };
+        let (address, shutdown, server) = start_epoll(slow, ConnectionConfig::default());
+
+        let mut slow = TcpStream::connect(address).unwrap();
+        slow.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        shutdown.trigger();
+
+        let mut response = String::new();
+        slow.read_to_string(&mut response).unwrap();
+        assert!(response.contains("Connection: close"));
+        assert!(response.ends_with("/slow"));
+        assert!(server.join().unwrap());
+    }
+
+    // opens a WebSocket on `/echo`, sends a masked text frame and returns the frame that comes back
+    fn websocket_echo(address: std::net::SocketAddr) -> Vec<u8> {
+        let mut client = TcpStream::connect(address).unwrap();
+        let handshake = "GET /echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        // the frame goes right after the handshake, before the 101 came back
+        let mut frame = vec![0x81, 0x80 | 5, 1, 2, 3, 4];
+        frame.extend(b"hello".iter().enumerate().map(|(i, byte)| byte ^ [1, 2, 3, 4][i % 4]));
+        client.write_all(&[handshake.as_bytes(), &frame].concat()).unwrap();
+
+        let mut response = Vec::new();
+        let mut buffer = [0; 512];
+        while !response.ends_with(b"hello") {
+            let read = client.read(&mut buffer).unwrap();
+            assert!(read > 0);
+            response.extend_from_slice(&buffer[..read]);
+        }
+        let head_end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&response[..head_end]);
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        response[head_end..].to_vec()
+    }
+
+    fn echo(request: &Request) -> Response {
+        crate::websocket::accept(request, |mut socket| {
+            while let Ok(Some(message)) = socket.receive() {
+                if socket.send(message).is_err() {
+                    break;
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn serves_websockets() {
+        let (address, shutdown, server) = start(echo, Duration::from_secs(5));
+        assert_eq!(websocket_echo(address), b"\x81\x05hello");
+
+        // the WebSocket waits for a message, the shutdown doesn't wait for it
+        shutdown.trigger();
+        assert!(server.join().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn epoll_hands_websockets_to_the_pool() {
+        let (address, shutdown, server) = start_epoll(echo, ConnectionConfig::default());
+        assert_eq!(websocket_echo(address), b"\x81\x05hello");
+
+        shutdown.trigger();
+        assert!(server.join().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn epoll_answers_408_to_slow_clients() {
+        let config = ConnectionConfig {
+            read_timeout: Duration::from_millis(200),
+            ..ConnectionConfig::default()
+        };
+        let (address, shutdown, server) = start_epoll(|_: &Request| Response::new(200), config);
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+
+        shutdown.trigger();
+        assert!(server.join().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn epoll_stops_reading_requests_over_the_limits() {
+        let config = ConnectionConfig {
+            limits: Limits {
+                max_header_bytes: 1024,
+                max_headers: 100,
+                max_body_size: 4096,
+            },
+            ..ConnectionConfig::default()
+        };
+        let (address, shutdown, server) = start_epoll(|_: &Request| Response::new(200), config);
+
+        // every chunk is fine, all of them together are more than the limits allow
+        let mut client = TcpStream::connect(address).unwrap();
+        let chunk = format!("10\r\n{}\r\n", "x".repeat(16));
+        let request = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}", chunk.repeat(250));
+        client.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large"), "{response}");
+
+        shutdown.trigger();
+        assert!(server.join().unwrap());
+    }
+}
@@ -0,0 +1,167 @@
+// ===== Graceful shutdown
+// Stopping the server with Ctrl-C used to kill it in the middle of whatever the workers were doing.
+// A graceful shutdown happens in steps:
+// 1. stop accepting new connections
+// 2. close the keep-alive connections that are waiting for their next request
+// 3. let the requests that are being handled finish, up to a deadline
+// 4. join the workers
+//
+// `Shutdown` is the switch shared by the accept loop and every connection to coordinate steps 1 and 2.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    triggered: AtomicBool,
+    // the addresses of the listeners blocked in `accept`, see `trigger`
+    listeners: Mutex<Vec<SocketAddr>>,
+    // a clone of every open connection's stream, and whether it's waiting for a request
+    connections: Mutex<HashMap<u64, (TcpStream, Arc<AtomicBool>)>>,
+    next_connection_id: AtomicU64,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.inner.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Starts the shutdown: listeners stop accepting and idle connections are closed.
+    /// Calling it more than once has no effect.
+    pub fn trigger(&self) {
+        if self.inner.triggered.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // `accept` blocks until a client connects and there is no way to interrupt it from another thread,
+        // so we connect to the listener ourselves. The accept loop then sees the flag and stops.
+        for address in self.inner.listeners.lock().unwrap().iter() {
+            let _ = TcpStream::connect(reachable(*address));
+        }
+
+        // shutting down the read half makes a connection blocked waiting for its next request read EOF,
+        // responses can still be written so a request that is being handled isn't affected
+        for (stream, idle) in self.inner.connections.lock().unwrap().values() {
+            if idle.load(Ordering::SeqCst) {
+                let _ = stream.shutdown(net::Shutdown::Read);
+            }
+        }
+    }
+
+    /// Triggers the shutdown when the process receives SIGINT (Ctrl-C) or SIGTERM (`kill`, systemd, docker stop).
+    /// A second signal exits right away, for when the drain takes longer than the person waiting for it.
+    #[cfg(unix)]
+    pub fn trigger_on_signals(&self) -> io::Result<()> {
+        use signal_hook::{
+            consts::{SIGINT, SIGTERM},
+            iterator::Signals,
+        };
+
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let shutdown = self.clone();
+
+        std::thread::Builder::new()
+            .name(String::from("signals"))
+            .spawn(move || {
+                let mut signals = signals.forever();
+                if signals.next().is_some() {
+                    println!("Shutting down, press Ctrl-C again to exit immediately.");
+                    shutdown.trigger();
+                }
+                if let Some(signal) = signals.next() {
+                    // same exit status a shell reports for a process killed by this signal
+                    std::process::exit(128 + signal);
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Without unix signals, Ctrl-C (or Ctrl-Break, or closing the console) is what triggers the shutdown.
+    /// A second one exits right away, like above.
+    #[cfg(not(unix))]
+    pub fn trigger_on_signals(&self) -> io::Result<()> {
+        let shutdown = self.clone();
+        ctrlc::set_handler(move || {
+            if shutdown.is_triggered() {
+                std::process::exit(130);
+            }
+            println!("Shutting down, press Ctrl-C again to exit immediately.");
+            shutdown.trigger();
+        })
+        .map_err(io::Error::other)
+    }
+
+    pub(crate) fn register_listener(&self, address: SocketAddr) {
+        self.inner.listeners.lock().unwrap().push(address);
+    }
+
+    /// Keeps track of a connection until the returned guard is dropped.
+    pub(crate) fn track(&self, stream: &TcpStream) -> io::Result<TrackedConnection> {
+        let id = self.inner.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let idle = Arc::new(AtomicBool::new(false));
+        self.inner
+            .connections
+            .lock()
+            .unwrap()
+            .insert(id, (stream.try_clone()?, Arc::clone(&idle)));
+
+        Ok(TrackedConnection {
+            id,
+            idle,
+            shutdown: self.clone(),
+        })
+    }
+}
+
+pub(crate) struct TrackedConnection {
+    id: u64,
+    idle: Arc<AtomicBool>,
+    shutdown: Shutdown,
+}
+
+impl TrackedConnection {
+    /// Marks the connection as waiting for a request (`true`) or handling one (`false`).
+    /// Returns false if the server is shutting down and the connection should be closed.
+    pub(crate) fn set_idle(&self, idle: bool) -> bool {
+        self.idle.store(idle, Ordering::SeqCst);
+        // checked after setting the flag: either `trigger` sees us idle and closes the stream,
+        // or we see that it was triggered, we can't both miss each other
+        !self.shutdown.is_triggered()
+    }
+
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_triggered()
+    }
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        self.shutdown.inner.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+// a listener bound to 0.0.0.0 accepts connections on every interface, but 0.0.0.0 isn't an address
+// we can connect to everywhere, the loopback interface is
+fn reachable(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), address.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), address.port()),
+        _ => address,
+    }
+}
@@ -0,0 +1,628 @@
+// ===== Serving files from a document root
+// Every request path is mapped to a file under `root`, `/css/site.css` becomes `<root>/css/site.css`.
+// The path comes from the client, so we have to make sure it can't be used to read files outside of the root
+// (`GET /../../etc/passwd`), this is known as a "path traversal" attack.
+//
+// Small files are read in memory, which is what the cache and the compression middleware need. A file bigger
+// than `max_buffered` (a video, a release archive) is sent from the disk a chunk at a time instead, so a few
+// downloads can't take all the memory of the server.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, Metadata},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    compression::{self, Encoding},
+    conditional::Validators,
+    file_cache::FileCache,
+    range::{self, ByteRange, RangeRequest},
+    request::Method,
+    Handler, Request, Response,
+};
+
+// How long the result of looking for a `.gz` copy is trusted while the file itself doesn't change. A copy
+// that appears or changes shows up that much later.
+const SIBLING_RECHECK: Duration = Duration::from_secs(2);
+
+// The biggest body read in memory by default, bigger ones are streamed.
+const MAX_BUFFERED: u64 = 1024 * 1024;
+
+/// How much of a streamed body is read from the file at a time.
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct StaticFiles {
+    root: PathBuf,
+    index: String,
+    not_found_page: Option<PathBuf>,
+    precompressed: bool,
+    cache: Option<FileCache>,
+    max_buffered: u64,
+    // the `.gz` copies found (or not) by `precompressed_sibling`, by file
+    siblings: Mutex<HashMap<PathBuf, Sibling>>,
+}
+
+struct Sibling {
+    checked: Instant,
+    // the file's, when we looked
+    modified: Option<SystemTime>,
+    found: Option<(PathBuf, Metadata)>,
+}
+
+impl StaticFiles {
+    /// Serves the files under `root`. A request for a directory serves its `index.html`.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            index: String::from("index.html"),
+            not_found_page: None,
+            precompressed: false,
+            cache: None,
+            max_buffered: MAX_BUFFERED,
+            siblings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the file served for a request for a directory, `index.html` by default.
+    pub fn index(mut self, name: impl Into<String>) -> Self {
+        self.index = name.into();
+        self
+    }
+
+    /// Sets the page (relative to the root) returned with a 404 when a file doesn't exist.
+    pub fn not_found_page(mut self, page: impl Into<PathBuf>) -> Self {
+        self.not_found_page = Some(page.into());
+        self
+    }
+
+    /// Serves `site.css.gz` instead of `site.css` to clients that accept gzip, when it exists and isn't
+    /// older than `site.css`. Compressing a file ahead of time with `gzip -k9` beats compressing it again
+    /// on every request.
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// Keeps the whole responses of the files in `cache`, so the popular ones aren't read from the disk on
+    /// every request. Ranges and files bigger than `max_buffered` are still read from the file.
+    pub fn cache(mut self, cache: FileCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Sets the biggest body (a file, or the ranges asked for) read in memory before it's sent, 1 MiB by
+    /// default. Bigger ones are read from the file a chunk at a time while they are sent.
+    pub fn max_buffered(mut self, bytes: u64) -> Self {
+        self.max_buffered = bytes;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Serves the file at `path`, which is relative to the root and already percent-decoded
+    /// (e.g. the `*path` parameter of a route).
+    pub fn serve(&self, request: &Request, path: &str) -> Response {
+        let file = match self.resolve(path) {
+            Some(file) => file,
+            None => return self.not_found(),
+        };
+
+        let metadata = match fs::metadata(&file) {
+            Ok(metadata) => metadata,
+            Err(err) => return self.error_response(&err),
+        };
+        let validators = Validators::from_metadata(&metadata);
+        let sibling = self.precompressed_sibling(&file, &metadata);
+        // with a `.gz` copy the response depends on the client's Accept-Encoding, caches have to know
+        let vary = |mut response: Response| {
+            if sibling.is_some() {
+                compression::add_vary(&mut response);
+            }
+            response
+        };
+        if validators.is_not_modified(request) {
+            return vary(validators.apply(Response::new(304)));
+        }
+
+        let content_type = content_type(&file);
+        let len = metadata.len();
+        let ranges = match request.headers.get("Range") {
+            Some(header) if request.method == Method::Get && validators.matches_if_range(request) => {
+                range::parse(header, len)
+            }
+            _ => RangeRequest::Full,
+        };
+
+        // ranges are ranges of the uncompressed file
+        let gzip = match &sibling {
+            Some(sibling) if matches!(ranges, RangeRequest::Full) => {
+                compression::accepts(request.headers.get("Accept-Encoding"), Encoding::Gzip).then_some(sibling)
+            }
+            _ => None,
+        };
+
+        let response = match ranges {
+            RangeRequest::Full => {
+                let (file, metadata) = match gzip {
+                    Some((sibling, metadata)) => (sibling, metadata),
+                    None => (&file, &metadata),
+                };
+                let response = Response::new(200).with_header("Content-Type", content_type);
+                match &self.cache {
+                    Some(cache) if metadata.len() <= self.max_buffered => {
+                        cache.response(file, metadata, |contents| response.with_body(contents))
+                    }
+                    _ => self.with_file(response, file, vec![Part::File { start: 0, len: metadata.len() }]),
+                }
+            }
+            RangeRequest::Partial(ranges) => {
+                let (response, parts) = partial_response(&ranges, content_type, len);
+                self.with_file(response, &file, parts)
+            }
+            RangeRequest::Unsatisfiable => {
+                Ok(Response::error(416).with_header("Content-Range", format!("bytes */{len}")))
+            }
+        };
+
+        match response {
+            Ok(response) => {
+                let mut response = vary(validators.apply(response).with_header("Accept-Ranges", "bytes"));
+                if gzip.is_some() {
+                    compression::mark_encoded(&mut response, Encoding::Gzip);
+                }
+                response
+            }
+            Err(err) => self.error_response(&err),
+        }
+    }
+
+    /// Gives `response` the body made of `parts` of `file`: read now if it's small enough, streamed otherwise.
+    fn with_file(&self, response: Response, file: &Path, parts: Vec<Part>) -> io::Result<Response> {
+        let body = FileBody::new(File::open(file)?, parts);
+        if body.len() <= self.max_buffered {
+            body.read_all().map(|contents| response.with_body(contents))
+        } else {
+            Ok(response.with_file(body))
+        }
+    }
+
+    /// Maps a request path to a file under the root.
+    /// Returns `None` if the path tries to escape the root or the file doesn't exist.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut file = self.root.clone();
+        for segment in path.split('/').filter(|segment| !segment.is_empty() && *segment != ".") {
+            // `..` is the obvious way out of the root, backslashes and NUL bytes are ways to sneak it past us
+            // on platforms that treat `\` as a separator or truncate paths at NUL
+            if segment == ".." || segment.contains(['\\', '\0']) {
+                return None;
+            }
+            file.push(segment);
+        }
+
+        if file.is_dir() {
+            file.push(&self.index);
+        }
+
+        // a symlink inside the root could still point outside of it, canonicalize resolves
+        // every link so we can check where the file really is
+        let root = self.root.canonicalize().ok()?;
+        let file = file.canonicalize().ok()?;
+        (file.starts_with(&root) && file.is_file()).then_some(file)
+    }
+
+    /// The precompressed copy of `file` to serve instead of it and its metadata, if there is one.
+    /// Looked up again when `file` changed or after `SIBLING_RECHECK`, not on every request.
+    fn precompressed_sibling(&self, file: &Path, metadata: &Metadata) -> Option<(PathBuf, Metadata)> {
+        if !self.precompressed || !compression::is_compressible(content_type(file)) {
+            return None;
+        }
+        let modified = metadata.modified().ok();
+        // a panic can't leave an entry half written, the poisoned map is still fine
+        let siblings = self.siblings.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(sibling) = siblings.get(file) {
+            if sibling.modified == modified && sibling.checked.elapsed() < SIBLING_RECHECK {
+                return sibling.found.clone();
+            }
+        }
+        // not holding the lock while we look at the disk
+        drop(siblings);
+        let found = self.find_sibling(file, metadata);
+        let sibling = Sibling {
+            checked: Instant::now(),
+            modified,
+            found: found.clone(),
+        };
+        self.siblings.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(file.to_path_buf(), sibling);
+        found
+    }
+
+    fn find_sibling(&self, file: &Path, metadata: &Metadata) -> Option<(PathBuf, Metadata)> {
+        let mut name = file.file_name()?.to_os_string();
+        name.push(".gz");
+
+        // the same link check as `resolve`, and a copy older than the file is stale
+        let root = self.root.canonicalize().ok()?;
+        let sibling = file.with_file_name(name).canonicalize().ok()?;
+        let sibling_metadata = fs::metadata(&sibling).ok()?;
+        let fresh = sibling_metadata.modified().ok()? >= metadata.modified().ok()?;
+        (sibling.starts_with(&root) && sibling_metadata.is_file() && fresh).then_some((sibling, sibling_metadata))
+    }
+
+    fn not_found(&self) -> Response {
+        let page = self
+            .not_found_page
+            .as_ref()
+            .and_then(|page| fs::read(self.root.join(page)).ok());
+
+        match page {
+            Some(page) => Response::html(404, page),
+            None => Response::error(404),
+        }
+    }
+
+    fn error_response(&self, err: &io::Error) -> Response {
+        match err.kind() {
+            io::ErrorKind::NotFound => self.not_found(),
+            io::ErrorKind::PermissionDenied => Response::error(403),
+            _ => Response::error(500),
+        }
+    }
+}
+
+/// A single range is sent as is with a `Content-Range` header,
+/// several ranges are sent as the parts of a `multipart/byteranges` body.
+/// Returns the response without its body, and what the body is made of.
+fn partial_response(ranges: &[ByteRange], content_type: &str, len: u64) -> (Response, Vec<Part>) {
+    let part = |range: &ByteRange| Part::File {
+        start: range.start,
+        len: range.end - range.start + 1,
+    };
+    if let [range] = ranges {
+        let response = Response::new(206)
+            .with_header("Content-Type", content_type)
+            .with_header("Content-Range", range.content_range(len));
+        return (response, vec![part(range)]);
+    }
+
+    // the boundary must not appear in the parts, a value that changes on every response
+    // makes it very unlikely that a file contains it
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let boundary = format!("byteranges-{nanos:x}");
+
+    let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+    for range in ranges {
+        parts.push(Part::Text(range::part_head(range, content_type, len, &boundary).into_bytes()));
+        parts.push(part(range));
+    }
+    parts.push(Part::Text(range::closing_boundary(&boundary).into_bytes()));
+    let response = Response::new(206).with_header("Content-Type", format!("multipart/byteranges; boundary={boundary}"));
+    (response, parts)
+}
+
+/// A response body made of pieces of a file, read when they are needed instead of all at once.
+#[derive(Debug, Clone)]
+pub(crate) struct FileBody {
+    // shared by the clones of the response, the lock keeps a seek and the read after it together
+    file: Arc<Mutex<File>>,
+    parts: Vec<Part>,
+    len: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    // e.g. the headers of a `multipart/byteranges` part
+    Text(Vec<u8>),
+    File { start: u64, len: u64 },
+}
+
+impl Part {
+    fn len(&self) -> u64 {
+        match self {
+            Part::Text(text) => text.len() as u64,
+            Part::File { len, .. } => *len,
+        }
+    }
+}
+
+impl FileBody {
+    fn new(file: File, parts: Vec<Part>) -> Self {
+        Self {
+            file: Arc::new(Mutex::new(file)),
+            len: parts.iter().map(Part::len).sum(),
+            parts,
+        }
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Reads the body from `offset` into `buffer`, returns how many bytes were read, 0 past the end.
+    pub(crate) fn read_at(&self, mut offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        for part in &self.parts {
+            if offset >= part.len() {
+                offset -= part.len();
+                continue;
+            }
+            let wanted = buffer.len().min(usize::try_from(part.len() - offset).unwrap_or(usize::MAX));
+            return match part {
+                Part::Text(text) => {
+                    let offset = offset as usize;
+                    buffer[..wanted].copy_from_slice(&text[offset..offset + wanted]);
+                    Ok(wanted)
+                }
+                Part::File { start, .. } => {
+                    // a panic can't happen between the seek and the read, the file is fine
+                    let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    file.seek(SeekFrom::Start(start + offset))?;
+                    match file.read(&mut buffer[..wanted])? {
+                        // the file got shorter since we took its length, we can't send what we announced
+                        0 => Err(io::ErrorKind::UnexpectedEof.into()),
+                        read => Ok(read),
+                    }
+                }
+            };
+        }
+        Ok(0)
+    }
+
+    /// Sends the whole body, `CHUNK_SIZE` bytes at a time.
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            match self.read_at(offset, &mut buffer)? {
+                0 => return Ok(()),
+                read => {
+                    writer.write_all(&buffer[..read])?;
+                    offset += read as u64;
+                }
+            }
+        }
+    }
+
+    fn read_all(&self) -> io::Result<Vec<u8>> {
+        let mut contents = vec![0; usize::try_from(self.len).unwrap_or(usize::MAX)];
+        let mut filled = 0;
+        while filled < contents.len() {
+            filled += self.read_at(filled as u64, &mut contents[filled..])?;
+        }
+        Ok(contents)
+    }
+}
+
+// the same pieces of the same open file
+impl PartialEq for FileBody {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.file, &other.file) && self.parts == other.parts
+    }
+}
+
+impl Eq for FileBody {}
+
+impl Handler for StaticFiles {
+    fn handle(&self, request: &Request) -> Response {
+        self.serve(request, &request.path)
+    }
+}
+
+/// Guesses the `Content-Type` of a file from its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every test gets its own directory so they can run in parallel
+    fn document_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("static-files-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("css")).unwrap();
+        fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+        fs::write(root.join("404.html"), "<h1>oops</h1>").unwrap();
+        fs::write(root.join("css/site.css"), "body {}").unwrap();
+        root
+    }
+
+    fn get(files: &StaticFiles, path: &str) -> Response {
+        let raw = format!("GET {path} HTTP/1.1\r\n\r\n");
+        files.handle(&Request::parse(&mut raw.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn serves_files_with_their_content_type() {
+        let files = StaticFiles::new(document_root("serve"));
+
+        let response = get(&files, "/css/site.css");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("Content-Type"), Some("text/css; charset=utf-8"));
+        assert_eq!(response.body, b"body {}");
+
+        let response = get(&files, "/");
+        assert_eq!(response.body, b"<h1>home</h1>");
+    }
+
+    #[test]
+    fn unchanged_files_are_not_modified() {
+        let files = StaticFiles::new(document_root("conditional"));
+
+        let response = get(&files, "/index.html");
+        let etag = response.headers.get("ETag").unwrap();
+        let last_modified = response.headers.get("Last-Modified").unwrap();
+
+        let raw = format!("GET /index.html HTTP/1.1\r\nIf-None-Match: {etag}\r\n\r\n");
+        let response = files.handle(&Request::parse(&mut raw.as_bytes()).unwrap());
+        assert_eq!(response.status, 304);
+        assert!(response.body.is_empty());
+
+        let raw = format!("GET /index.html HTTP/1.1\r\nIf-Modified-Since: {last_modified}\r\n\r\n");
+        let response = files.handle(&Request::parse(&mut raw.as_bytes()).unwrap());
+        assert_eq!(response.status, 304);
+    }
+
+    #[test]
+    fn serves_byte_ranges() {
+        let files = StaticFiles::new(document_root("range"));
+        let get_range = |range: &str| {
+            let raw = format!("GET /index.html HTTP/1.1\r\nRange: {range}\r\n\r\n");
+            files.handle(&Request::parse(&mut raw.as_bytes()).unwrap())
+        };
+
+        let response = get_range("bytes=4-7");
+        assert_eq!(response.status, 206);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 4-7/13"));
+        assert_eq!(response.body, b"home");
+
+        let response = get_range("bytes=0-3,-5");
+        assert_eq!(response.status, 206);
+        let content_type = response.headers.get("Content-Type").unwrap();
+        assert!(content_type.starts_with("multipart/byteranges; boundary="));
+        let body = String::from_utf8(response.body.clone()).unwrap();
+        assert!(body.contains("Content-Range: bytes 0-3/13\r\n\r\n<h1>"));
+        assert!(body.contains("Content-Range: bytes 8-12/13\r\n\r\n</h1>"));
+
+        let response = get_range("bytes=100-");
+        assert_eq!(response.status, 416);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes */13"));
+    }
+
+    #[test]
+    fn streams_files_bigger_than_max_buffered() {
+        let root = document_root("stream");
+        // a few chunks, the last one short
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 3 + 100).map(|n| (n % 251) as u8).collect();
+        fs::write(root.join("big.bin"), &contents).unwrap();
+        let files = StaticFiles::new(&root).max_buffered(1024).cache(FileCache::new(1024 * 1024));
+        let send = |raw: &str| {
+            let request = Request::parse(&mut raw.as_bytes()).unwrap();
+            let response = files.handle(&request);
+            let mut output = Vec::new();
+            response.write_for(request.method, &mut output).unwrap();
+            (response, output)
+        };
+
+        let (response, output) = send("GET /big.bin HTTP/1.1\r\n\r\n");
+        assert!(response.body.is_empty());
+        assert_eq!(response.headers.get("Content-Type"), Some("application/octet-stream"));
+        let head = format!("Content-Length: {}\r\n\r\n", contents.len());
+        let start = output.windows(head.len()).position(|window| window == head.as_bytes()).unwrap() + head.len();
+        assert_eq!(&output[start..], contents);
+
+        // the same for a HEAD, without the body
+        let (_, output) = send("HEAD /big.bin HTTP/1.1\r\n\r\n");
+        assert!(output.ends_with(head.as_bytes()));
+
+        let (response, output) = send("GET /big.bin HTTP/1.1\r\nRange: bytes=10-2009,-1000\r\n\r\n");
+        assert_eq!(response.status, 206);
+        let body = &output[output.len() - response.sent_body_len()..];
+        assert!(body.windows(2000).any(|window| window == &contents[10..2010]));
+        assert!(body.windows(1000).any(|window| window == &contents[contents.len() - 1000..]));
+
+        // small files are still read in memory, and cached
+        assert_eq!(send("GET /css/site.css HTTP/1.1\r\n\r\n").0.body, b"body {}");
+    }
+
+    #[test]
+    fn serves_precompressed_files_to_clients_that_accept_gzip() {
+        let root = document_root("precompressed");
+        fs::write(root.join("css/site.css.gz"), "gzipped").unwrap();
+        let files = StaticFiles::new(&root).precompressed(true);
+        let get_encoded = |accept_encoding: &str| {
+            let raw = format!("GET /css/site.css HTTP/1.1\r\nAccept-Encoding: {accept_encoding}\r\n\r\n");
+            files.handle(&Request::parse(&mut raw.as_bytes()).unwrap())
+        };
+
+        let response = get_encoded("gzip, deflate");
+        assert_eq!(response.body, b"gzipped");
+        assert_eq!(response.headers.get("Content-Type"), Some("text/css; charset=utf-8"));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert!(response.headers.get("ETag").unwrap().starts_with("W/"));
+
+        let response = get_encoded("deflate");
+        assert_eq!(response.body, b"body {}");
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+
+        // a file without a copy doesn't vary
+        let response = get(&files, "/index.html");
+        assert_eq!(response.headers.get("Vary"), None);
+    }
+
+    #[test]
+    fn serves_the_cached_files_from_memory() {
+        let root = document_root("cache");
+        let cache = FileCache::new(1024 * 1024);
+        let files = StaticFiles::new(&root).cache(cache.clone());
+
+        let first = get(&files, "/css/site.css");
+        let second = get(&files, "/css/site.css");
+        assert_eq!(second, first);
+        assert_eq!(second.headers.get("Content-Type"), Some("text/css; charset=utf-8"));
+        assert_eq!((cache.stats().hits, cache.stats().misses), (1, 1));
+
+        // ranges don't go through the cache, a new file does
+        let raw = "GET /index.html HTTP/1.1\r\nRange: bytes=4-7\r\n\r\n";
+        assert_eq!(files.handle(&Request::parse(&mut raw.as_bytes()).unwrap()).body, b"home");
+        fs::write(root.join("css/site.css"), "body { margin: 0 }").unwrap();
+        assert_eq!(get(&files, "/css/site.css").body, b"body { margin: 0 }");
+        assert_eq!((cache.stats().hits, cache.stats().misses), (1, 2));
+    }
+
+    #[test]
+    fn missing_files_get_the_not_found_page() {
+        let files = StaticFiles::new(document_root("missing")).not_found_page("404.html");
+
+        let response = get(&files, "/nope.html");
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"<h1>oops</h1>");
+    }
+
+    #[test]
+    fn blocks_path_traversal() {
+        let root = document_root("traversal");
+        fs::write(root.parent().unwrap().join("secret.txt"), "secret").unwrap();
+        let files = StaticFiles::new(root.join("css"));
+
+        assert_eq!(get(&files, "/../index.html").status, 404);
+        assert_eq!(get(&files, "/%2e%2e/index.html").status, 404);
+        assert_eq!(get(&files, "/..%2f..%2fsecret.txt").status, 404);
+        assert_eq!(get(&files, "/site.css").status, 200);
+    }
+}
@@ -0,0 +1,160 @@
+// ===== HTTPS
+// HTTPS is HTTP sent through a TLS connection: the client and the server first agree on keys (the handshake,
+// where the server also proves who it is with its certificate), then everything is encrypted. Once the
+// handshake is done the HTTP requests and responses are exactly the same as in plain text, so the same
+// connection code serves both, it only reads and writes through rustls instead of the socket directly.
+//
+// The certificate and its private key are read from PEM files, the format Let's Encrypt and openssl write:
+//   -----BEGIN CERTIFICATE-----
+//   MIIBkTCB+wIJAK...
+//   -----END CERTIFICATE-----
+// The certificate file can hold the whole chain, the server's certificate first.
+//
+// A site that moved to HTTPS usually keeps listening on port 80 to send old links and typed-in addresses
+// to the HTTPS version, that's what `HttpsRedirect` answers.
+
+use std::{
+    fmt, fs,
+    io::{self, BufReader},
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{crypto::ring, ServerConfig};
+
+use crate::{request, Handler, Request, Response};
+
+/// A certificate and its key, ready to accept TLS connections. Cloning it is cheap.
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsConfig {
+    /// Reads the certificate chain and the private key from PEM files.
+    pub fn from_pem_files(certificate: impl AsRef<Path>, private_key: impl AsRef<Path>) -> io::Result<Self> {
+        let read = |path: &Path| {
+            fs::read(path).map_err(|err| io::Error::new(err.kind(), format!("failed to read {}: {err}", path.display())))
+        };
+        Self::from_pem(&read(certificate.as_ref())?, &read(private_key.as_ref())?)
+    }
+
+    /// Parses a PEM certificate chain and a PEM private key (PKCS#8, PKCS#1 or SEC1).
+    pub fn from_pem(certificate: &[u8], private_key: &[u8]) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let chain = rustls_pemfile::certs(&mut BufReader::new(certificate)).collect::<Result<Vec<_>, _>>()?;
+        if chain.is_empty() {
+            return Err(invalid(String::from("no certificate found in the PEM file")));
+        }
+        let key = rustls_pemfile::private_key(&mut BufReader::new(private_key))?
+            .ok_or_else(|| invalid(String::from("no private key found in the PEM file")))?;
+
+        // rustls checks that the key goes with the certificate
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| invalid(err.to_string()))?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(|err| invalid(format!("invalid certificate or key: {err}")))?;
+
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    pub(crate) fn server_config(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.config)
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig").finish_non_exhaustive()
+    }
+}
+
+/// Answers every request with a redirect to the same URL over HTTPS.
+///
+/// It's a `308 Permanent Redirect`, so browsers remember it and a `POST` stays a `POST`.
+#[derive(Debug, Clone)]
+pub struct HttpsRedirect {
+    port: u16,
+}
+
+impl HttpsRedirect {
+    /// Redirects to the HTTPS server on `port`, usually 443.
+    pub fn new(port: u16) -> Self {
+        Self { port }
+    }
+}
+
+impl Handler for HttpsRedirect {
+    fn handle(&self, request: &Request) -> Response {
+        // the Host header is the only place that says which name the client used to reach us
+        let host = match request.headers.get("Host").map(host_without_port) {
+            Some(host) if !host.is_empty() => host,
+            _ => return Response::error(400),
+        };
+        // the same path and query, even if the client sent a full `http://` URL as the target
+        let target = request::origin_form(&request.target).unwrap_or("/");
+        let location = match self.port {
+            443 => format!("https://{host}{target}"),
+            port => format!("https://{host}:{port}{target}"),
+        };
+
+        Response::new(308)
+            .with_header("Location", location)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body("308 Permanent Redirect\n")
+    }
+}
+
+// `example.com:8080` -> `example.com`, `[::1]:8080` -> `[::1]`
+fn host_without_port(host: &str) -> &str {
+    let host = host.trim();
+    match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirect(port: u16, raw: &str) -> Response {
+        HttpsRedirect::new(port).handle(&Request::parse(&mut raw.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn redirects_to_the_same_url() {
+        let response = redirect(443, "GET /a/b?c=d HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert_eq!(response.status, 308);
+        assert_eq!(response.headers.get("Location"), Some("https://example.com/a/b?c=d"));
+
+        let response = redirect(8443, "POST /form HTTP/1.1\r\nHost: example.com:8080\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(response.headers.get("Location"), Some("https://example.com:8443/form"));
+
+        let response = redirect(8443, "GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n");
+        assert_eq!(response.headers.get("Location"), Some("https://[::1]:8443/"));
+
+        let response = redirect(443, "GET http://example.com/a?b=c HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert_eq!(response.headers.get("Location"), Some("https://example.com/a?b=c"));
+
+        assert_eq!(redirect(443, "GET / HTTP/1.0\r\n\r\n").status, 400);
+    }
+
+    #[test]
+    fn rejects_pem_files_without_a_certificate_or_key() {
+        let generated = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let certificate = generated.cert.pem();
+        let key = generated.key_pair.serialize_pem();
+
+        assert!(TlsConfig::from_pem(certificate.as_bytes(), key.as_bytes()).is_ok());
+        let err = TlsConfig::from_pem(key.as_bytes(), key.as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "no certificate found in the PEM file");
+        let err = TlsConfig::from_pem(certificate.as_bytes(), certificate.as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "no private key found in the PEM file");
+    }
+}
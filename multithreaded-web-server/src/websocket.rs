@@ -0,0 +1,564 @@
+// ===== WebSocket
+// A WebSocket starts as an HTTP request that asks to switch protocols:
+//   GET /live HTTP/1.1
+//   Upgrade: websocket
+//   Connection: Upgrade
+//   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
+//   Sec-WebSocket-Version: 13
+// We answer `101 Switching Protocols` with a hash of the key, to prove we understood the request (and aren't
+// a server that happens to echo headers), and from then on the connection carries frames both ways instead
+// of HTTP. https://www.rfc-editor.org/rfc/rfc6455
+//
+// A frame is a small header and a payload:
+//   FIN + opcode | MASK + length | extended length (0, 2 or 8 bytes) | mask key (0 or 4 bytes) | payload
+// - the opcode says what the frame is: text, binary, a continuation of the previous frame, or one of the
+//   control frames: ping, pong and close
+// - a message can be split in several frames (fragmentation), FIN marks its last one. Control frames can
+//   show up between the fragments, so a ping gets its pong even in the middle of a long message.
+// - every frame from the client is masked: the payload is XORed with the 4 byte key. It doesn't hide
+//   anything, it keeps a malicious page from making the browser send bytes that a proxy on the way would
+//   mistake for an HTTP request. Our frames are never masked.
+//
+// A route accepts a WebSocket by returning `websocket::accept(request, |socket| ...)`. The connection
+// writes the 101, then runs the closure with the socket on the same pool worker, for as long as it needs:
+//   .get("/echo", |request, _| websocket::accept(request, |mut socket| {
+//       while let Ok(Some(message)) = socket.receive() {
+//           if socket.send(message).is_err() {
+//               break;
+//           }
+//       }
+//   }))
+
+use std::{
+    fmt,
+    io::{self, BufRead, Read, Write},
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+use crate::{request::Version, shutdown::TrackedConnection, Method, Request, Response};
+
+// appended to the client's key before hashing, the same for every server
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// a message bigger than this is refused, so a client can't make us buffer without end
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Close codes, https://www.rfc-editor.org/rfc/rfc6455#section-7.4.1
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    /// The server is shutting down, or the browser leaves the page.
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    /// A text message that isn't UTF-8.
+    pub const INVALID_DATA: u16 = 1007;
+    pub const TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Self {
+        Message::Binary(data)
+    }
+}
+
+/// Answers a WebSocket handshake: a `101 Switching Protocols` that runs `handler` with the socket once
+/// it's sent, or an error if `request` isn't a valid handshake.
+///
+/// Headers added to the 101, e.g. the chosen `Sec-WebSocket-Protocol`, are sent with it.
+pub fn accept<F>(request: &Request, handler: F) -> Response
+where
+    F: FnOnce(WebSocket<'_>) + Send + 'static,
+{
+    let headers = &request.headers;
+    if request.method != Method::Get
+        || request.version != Version::Http11
+        || !headers.has_token("Upgrade", "websocket")
+        || !headers.has_token("Connection", "upgrade")
+    {
+        return Response::error(400);
+    }
+    // the only version there is, an older client is told which one we speak
+    if headers.get("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Response::error(426).with_header("Sec-WebSocket-Version", "13");
+    }
+    let key = match headers.get("Sec-WebSocket-Key").map(str::trim) {
+        Some(key) if BASE64.decode(key).is_ok_and(|nonce| nonce.len() == 16) => key,
+        _ => return Response::error(400),
+    };
+
+    let mut response = Response::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key));
+    response.upgrade = Some(Upgrade(Arc::new(Mutex::new(Some(Box::new(handler))))));
+    response
+}
+
+/// The `Sec-WebSocket-Accept` for a `Sec-WebSocket-Key`: the base64 SHA-1 of the key and the GUID.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.digest().bytes())
+}
+
+type Callback = Box<dyn FnOnce(WebSocket<'_>) + Send>;
+
+/// What a `101 Switching Protocols` response runs once it's sent. It runs once, even if the response is cloned.
+#[derive(Clone)]
+pub(crate) struct Upgrade(Arc<Mutex<Option<Callback>>>);
+
+impl Upgrade {
+    pub(crate) fn run(&self, socket: WebSocket<'_>) {
+        let callback = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        if let Some(callback) = callback {
+            // like a handler panic, it ends the connection and nothing else
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| callback(socket)));
+        }
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upgrade").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Upgrade {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Upgrade {}
+
+/// The server side of a WebSocket connection.
+///
+/// Pings are answered while `receive` waits for a message. Dropping the socket closes it with
+/// `close_code::NORMAL` if it wasn't closed yet.
+pub struct WebSocket<'a> {
+    reader: &'a mut dyn BufRead,
+    writer: &'a mut dyn Write,
+    tracked: Option<&'a TrackedConnection>,
+    // we sent our close frame, nothing else may follow it
+    closed: bool,
+}
+
+// A frame that breaks the protocol, we close the connection with `code`.
+struct Violation {
+    code: u16,
+    reason: &'static str,
+}
+
+enum Failure {
+    Io(io::Error),
+    Violation(Violation),
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Io(err)
+    }
+}
+
+fn violation(code: u16, reason: &'static str) -> Failure {
+    Failure::Violation(Violation { code, reason })
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl<'a> WebSocket<'a> {
+    pub(crate) fn new(
+        reader: &'a mut dyn BufRead,
+        writer: &'a mut dyn Write,
+        tracked: Option<&'a TrackedConnection>,
+    ) -> Self {
+        Self {
+            reader,
+            writer,
+            tracked,
+            closed: false,
+        }
+    }
+
+    /// Waits for the next message. `None` once the connection is closed, by the client or by a server
+    /// that is shutting down.
+    ///
+    /// If nothing arrives for the connection's idle timeout, it fails with `ErrorKind::TimedOut` and
+    /// the socket can still be used: a handler that pushes updates can send one and wait again.
+    pub fn receive(&mut self) -> io::Result<Option<Message>> {
+        if self.closed {
+            return Ok(None);
+        }
+        match self.read_message() {
+            Ok(message) => Ok(message),
+            Err(Failure::Io(err)) => {
+                // the shutdown closed the socket we were waiting on
+                if self.tracked.is_some_and(TrackedConnection::is_shutting_down) {
+                    self.closed = true;
+                    return Ok(None);
+                }
+                Err(err)
+            }
+            Err(Failure::Violation(Violation { code, reason })) => {
+                let _ = self.send_close(code, reason);
+                Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+            }
+        }
+    }
+
+    /// Sends a text or binary message. Fails once the server is shutting down, after telling the client.
+    pub fn send(&mut self, message: impl Into<Message>) -> io::Result<()> {
+        self.check_open()?;
+        match message.into() {
+            Message::Text(text) => self.write_frame(TEXT, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(BINARY, &data),
+        }
+    }
+
+    /// Sends a ping, the client answers with a pong carrying the same payload (125 bytes at most).
+    pub fn ping(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.check_open()?;
+        if payload.len() > 125 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "a ping payload is 125 bytes at most"));
+        }
+        self.write_frame(PING, payload)
+    }
+
+    /// Closes the connection with `code` and a short `reason`, and waits for the client to agree.
+    pub fn close(mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.send_close(code, reason)?;
+        // what the client sent in the meantime is dropped, until its own close frame
+        loop {
+            match self.read_frame(false) {
+                Ok(frame) if frame.opcode == CLOSE => return Ok(()),
+                Ok(_) => {}
+                Err(Failure::Io(err)) => return Err(err),
+                Err(Failure::Violation(_)) => return Ok(()),
+            }
+        }
+    }
+
+    fn check_open(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "the WebSocket is closed"));
+        }
+        if self.tracked.is_some_and(TrackedConnection::is_shutting_down) {
+            let _ = self.send_close(close_code::GOING_AWAY, "server shutting down");
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the server is shutting down"));
+        }
+        Ok(())
+    }
+
+    // Reads frames until a whole message, answering the control frames on the way.
+    fn read_message(&mut self) -> Result<Option<Message>, Failure> {
+        // the first frame of the message and what arrived so far, while the message is fragmented
+        let mut fragmented: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let frame = self.read_frame(fragmented.is_none())?;
+            match frame.opcode {
+                PING => self.write_frame(PONG, &frame.payload)?,
+                PONG => {}
+                CLOSE => {
+                    self.answer_close(&frame.payload)?;
+                    return Ok(None);
+                }
+                TEXT | BINARY if fragmented.is_some() => {
+                    return Err(violation(close_code::PROTOCOL_ERROR, "expected a continuation frame"))
+                }
+                TEXT | BINARY if frame.fin => return message(frame.opcode, frame.payload).map(Some),
+                TEXT | BINARY => fragmented = Some((frame.opcode, frame.payload)),
+                CONTINUATION => {
+                    let (opcode, mut payload) = fragmented
+                        .take()
+                        .ok_or_else(|| violation(close_code::PROTOCOL_ERROR, "unexpected continuation frame"))?;
+                    if payload.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                        return Err(violation(close_code::TOO_BIG, "message too big"));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return message(opcode, payload).map(Some);
+                    }
+                    fragmented = Some((opcode, payload));
+                }
+                _ => return Err(violation(close_code::PROTOCOL_ERROR, "unknown opcode")),
+            }
+        }
+    }
+
+    // `between_messages`: the connection is marked idle while we wait for the first byte, so a shutdown
+    // can close it. A timeout there leaves the socket usable, one in the middle of a frame doesn't.
+    fn read_frame(&mut self, between_messages: bool) -> Result<Frame, Failure> {
+        if between_messages {
+            if self.tracked.is_some_and(|tracked| !tracked.set_idle(true)) {
+                return Err(Failure::Io(io::ErrorKind::ConnectionAborted.into()));
+            }
+            let waited = self.reader.fill_buf().map(|buffer| buffer.is_empty());
+            if let Some(tracked) = self.tracked {
+                tracked.set_idle(false);
+            }
+            match waited {
+                Ok(false) => {}
+                Ok(true) => return Err(Failure::Io(io::ErrorKind::UnexpectedEof.into())),
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    return Err(Failure::Io(io::ErrorKind::TimedOut.into()))
+                }
+                Err(err) => return Err(Failure::Io(err)),
+            }
+        }
+
+        let mut head = [0; 2];
+        self.reader.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        // the reserved bits are for extensions, and we didn't agree on any
+        if head[0] & 0x70 != 0 {
+            return Err(violation(close_code::PROTOCOL_ERROR, "reserved bits set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(violation(close_code::PROTOCOL_ERROR, "client frames must be masked"));
+        }
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                self.reader.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+        if opcode >= CLOSE && (len > 125 || !fin) {
+            return Err(violation(close_code::PROTOCOL_ERROR, "invalid control frame"));
+        }
+        if len > MAX_MESSAGE_SIZE as u64 {
+            return Err(violation(close_code::TOO_BIG, "message too big"));
+        }
+
+        let mut mask = [0; 4];
+        self.reader.read_exact(&mut mask)?;
+        // grows as the payload arrives: a header announcing 16 MiB costs nothing until the bytes are sent
+        let mut payload = Vec::new();
+        if (&mut self.reader).take(len).read_to_end(&mut payload)? as u64 != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Frame { fin, opcode, payload })
+    }
+
+    // The client started the closing handshake, we send its close frame back and we're done.
+    fn answer_close(&mut self, payload: &[u8]) -> Result<(), Failure> {
+        let code = match payload {
+            [] => close_code::NORMAL,
+            [_] => return Err(violation(close_code::PROTOCOL_ERROR, "invalid close frame")),
+            [high, low, reason @ ..] => {
+                let code = u16::from_be_bytes([*high, *low]);
+                // 1004 to 1006 and 1015 are reserved to report errors locally, never sent
+                if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+                    return Err(violation(close_code::PROTOCOL_ERROR, "invalid close code"));
+                }
+                if std::str::from_utf8(reason).is_err() {
+                    return Err(violation(close_code::INVALID_DATA, "close reason isn't UTF-8"));
+                }
+                code
+            }
+        };
+        if !self.closed {
+            self.send_close(code, "")?;
+        }
+        Ok(())
+    }
+
+    fn send_close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        let mut payload = code.to_be_bytes().to_vec();
+        // the reason has to fit in a control frame, cut at a character boundary
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        self.closed = true;
+        self.write_frame(CLOSE, &payload)
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        // one write for the header and the payload, like `Response::write_to`
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.writer.write_all(&frame)?;
+        self.writer.flush()
+    }
+}
+
+impl Drop for WebSocket<'_> {
+    fn drop(&mut self) {
+        if !self.closed {
+            let code = if std::thread::panicking() { close_code::INTERNAL_ERROR } else { close_code::NORMAL };
+            let _ = self.send_close(code, "");
+        }
+    }
+}
+
+fn message(opcode: u8, payload: Vec<u8>) -> Result<Message, Failure> {
+    match opcode {
+        TEXT => String::from_utf8(payload)
+            .map(Message::Text)
+            .map_err(|_| violation(close_code::INVALID_DATA, "text message isn't UTF-8")),
+        _ => Ok(Message::Binary(payload)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a frame as a client sends it, masked
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    // runs `handler` on a socket that reads `input`, returns what it wrote
+    fn exchange(input: Vec<u8>, handler: impl FnOnce(WebSocket<'_>)) -> Vec<u8> {
+        let mut output = Vec::new();
+        handler(WebSocket::new(&mut input.as_slice(), &mut output, None));
+        output
+    }
+
+    #[test]
+    fn answers_the_handshake() {
+        let raw = "GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+                   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let request = Request::parse(&mut raw.as_bytes()).unwrap();
+        let response = accept(&request, |_| {});
+        assert_eq!(response.status, 101);
+        // the example of the RFC
+        assert_eq!(response.headers.get("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert!(response.upgrade.is_some());
+
+        let old = Request::parse(&mut raw.replace("Version: 13", "Version: 8").as_bytes()).unwrap();
+        let response = accept(&old, |_| {});
+        assert_eq!(response.status, 426);
+        assert_eq!(response.headers.get("Sec-WebSocket-Version"), Some("13"));
+
+        let plain = Request::parse(&mut "GET /chat HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+        assert_eq!(accept(&plain, |_| {}).status, 400);
+    }
+
+    #[test]
+    fn reassembles_fragments_and_answers_pings() {
+        let mut input = client_frame(false, TEXT, b"Hel");
+        input.extend(client_frame(true, PING, b"are you there?"));
+        input.extend(client_frame(true, CONTINUATION, b"lo"));
+        input.extend(client_frame(true, BINARY, &[7; 300]));
+        input.extend(client_frame(true, CLOSE, &1000u16.to_be_bytes()));
+
+        let output = exchange(input, |mut socket| {
+            assert_eq!(socket.receive().unwrap(), Some(Message::Text(String::from("Hello"))));
+            assert_eq!(socket.receive().unwrap(), Some(Message::Binary(vec![7; 300])));
+            assert_eq!(socket.receive().unwrap(), None);
+        });
+
+        let mut expected = vec![0x80 | PONG, 14];
+        expected.extend_from_slice(b"are you there?");
+        expected.extend_from_slice(&[0x80 | CLOSE, 2, 0x03, 0xE8]);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn closes_on_protocol_errors() {
+        let mut unmasked = client_frame(true, TEXT, b"hi");
+        unmasked[1] &= 0x7F;
+        let output = exchange(unmasked, |mut socket| {
+            assert_eq!(socket.receive().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        });
+        assert_eq!(&output[..4], &[0x80 | CLOSE, 2 + 28, 0x03, 0xEA]);
+
+        let output = exchange(client_frame(true, TEXT, &[0xFF, 0xFE]), |mut socket| {
+            assert!(socket.receive().is_err());
+        });
+        assert_eq!(&output[2..4], &close_code::INVALID_DATA.to_be_bytes());
+
+        // announces 16 MiB, sends 3 bytes and hangs up
+        let mut truncated = vec![0x80 | BINARY, 0x80 | 127];
+        truncated.extend_from_slice(&(MAX_MESSAGE_SIZE as u64).to_be_bytes());
+        truncated.extend_from_slice(&[0x12, 0x34, 0x56, 0x78, 1, 2, 3]);
+        exchange(truncated, |mut socket| {
+            assert_eq!(socket.receive().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        });
+    }
+
+    #[test]
+    fn sends_unmasked_frames() {
+        let output = exchange(Vec::new(), |mut socket| {
+            socket.send("hi").unwrap();
+            socket.send(vec![1; 200]).unwrap();
+        });
+
+        assert_eq!(&output[..4], &[0x80 | TEXT, 2, b'h', b'i']);
+        assert_eq!(&output[4..8], &[0x80 | BINARY, 126, 0, 200]);
+        // dropped without closing, it closes normally
+        assert_eq!(&output[208..], &[0x80 | CLOSE, 2, 0x03, 0xE8]);
+    }
+}